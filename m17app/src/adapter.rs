@@ -1,4 +1,6 @@
 use crate::{app::TxHandle, error::AdapterError, link_setup::LinkSetup};
+use m17core::kiss::{PacketDiscardReason, StreamLostReason};
+use m17core::modem::SignalQuality;
 use m17core::protocol::PacketType;
 use std::sync::Arc;
 
@@ -27,6 +29,14 @@ pub trait PacketAdapter: Send + Sync + 'static {
         let _ = packet_type;
         let _ = content;
     }
+
+    /// The TNC started receiving a packet but had to give up before it was complete.
+    ///
+    /// This is an opportunity to ask the sender to try again, if the application supports that.
+    fn packet_discarded(&self, link_setup: LinkSetup, reason: PacketDiscardReason) {
+        let _ = link_setup;
+        let _ = reason;
+    }
 }
 
 /// Can be connected to an `M17App` to receive incoming streams (voice or data).
@@ -62,18 +72,42 @@ pub trait StreamAdapter: Send + Sync + 'static {
     /// It is not guaranteed to receive every frame. Frame numbers may not start from 0, and they will
     /// wrap around to 0 after 0x7fff. If we receive an indication that the frame is the final one then
     /// `is_final` is set. If the transmitter never sends that frame or we fail to receive it then the
-    /// stream may trail off without that being set. Implementors should consider setting an appropriate
-    /// timeout to consider a stream "dead" and wait for the next `stream_began`.
+    /// stream may trail off without that being set. A TNC that supports it will call `stream_lost`
+    /// in that case; otherwise implementors should consider setting an appropriate timeout to
+    /// consider a stream "dead" and wait for the next `stream_began`.
     fn stream_data(&self, frame_number: u16, is_final: bool, data: Arc<[u8; 16]>) {
         let _ = frame_number;
         let _ = is_final;
         let _ = data;
     }
 
+    /// Signal quality of the stream frame that is about to be passed to `stream_data`.
+    ///
+    /// Only called if the TNC reports signal quality.
+    fn stream_frame_quality(&self, frame_number: u16, quality: SignalQuality) {
+        let _ = frame_number;
+        let _ = quality;
+    }
+
+    /// The incoming stream stopped without a final frame, e.g., because the signal faded.
+    ///
+    /// No more `stream_data` will arrive for it. Another stream may follow with `stream_began`.
+    fn stream_lost(&self, reason: StreamLostReason) {
+        let _ = reason;
+    }
+
+    /// The TNC ended our outgoing stream before we sent the final frame.
+    ///
+    /// This happens if we did not supply stream data fast enough, or if the TNC's transmit limits
+    /// cut the transmission short. Any further data for this stream will be ignored; start a new
+    /// transmission with a fresh `LinkSetup` if desired.
+    ///
+    /// Only the adapter that started the stream, using the `TxHandle` passed to its `start()`, is
+    /// notified.
+    fn stream_tx_ended_early(&self) {}
+
     // TODO: callbacks for LICH metadata received
     // fn stream_assembled_text_block()
     // fn stream_gnss_data()
     // fn stream_extended_callsign_data()
-
-    // fn stream_tx_ended_early(&self); // underrun/overrun
 }
@@ -3,7 +3,12 @@ use crate::error::{M17Error, M17Errors};
 use crate::link_setup::LinkSetup;
 use crate::tnc::Tnc;
 use crate::{LsfFrame, PacketType, StreamFrame};
-use m17core::kiss::{KissBuffer, KissCommand, KissFrame};
+use m17core::address::Address;
+use m17core::kiss::PORT_STREAM;
+use m17core::kiss::{
+    KissBuffer, KissCommand, KissFrame, PacketDiscardReason, StreamEndReason, StreamLostReason,
+};
+use m17core::modem::SignalQuality;
 use m17core::protocol::EncryptionType;
 
 use log::debug;
@@ -50,7 +55,7 @@ impl M17App {
         drop(adapters);
         if self.lifecycle() == Lifecycle::Started {
             adapter
-                .start(self.tx())
+                .start(self.adapter_tx(id))
                 .map_err(|e| M17Error::Adapter(id, e))?;
         }
         Ok(id)
@@ -68,26 +73,26 @@ impl M17App {
         drop(adapters);
         if self.lifecycle() == Lifecycle::Started {
             adapter
-                .start(self.tx())
+                .start(self.adapter_tx(id))
                 .map_err(|e| M17Error::Adapter(id, e))?;
         }
         Ok(id)
     }
 
     pub fn remove_packet_adapter(&self, id: usize) -> Result<(), M17Error> {
-        if let Some(a) = self.adapters.write().unwrap().packet.remove(&id) {
-            if self.lifecycle() == Lifecycle::Started {
-                a.close().map_err(|e| M17Error::Adapter(id, e))?;
-            }
+        if let Some(a) = self.adapters.write().unwrap().packet.remove(&id)
+            && self.lifecycle() == Lifecycle::Started
+        {
+            a.close().map_err(|e| M17Error::Adapter(id, e))?;
         }
         Ok(())
     }
 
     pub fn remove_stream_adapter(&self, id: usize) -> Result<(), M17Error> {
-        if let Some(a) = self.adapters.write().unwrap().stream.remove(&id) {
-            if self.lifecycle() == Lifecycle::Started {
-                a.close().map_err(|e| M17Error::Adapter(id, e))?;
-            }
+        if let Some(a) = self.adapters.write().unwrap().stream.remove(&id)
+            && self.lifecycle() == Lifecycle::Started
+        {
+            a.close().map_err(|e| M17Error::Adapter(id, e))?;
         }
         Ok(())
     }
@@ -96,6 +101,16 @@ impl M17App {
     pub fn tx(&self) -> TxHandle {
         TxHandle {
             event_tx: self.event_tx.clone(),
+            adapters: self.adapters.clone(),
+            adapter: None,
+        }
+    }
+
+    /// Create a handle for the adapter with this id, so it hears about its own transmissions.
+    fn adapter_tx(&self, id: usize) -> TxHandle {
+        TxHandle {
+            adapter: Some(id),
+            ..self.tx()
         }
     }
 
@@ -105,17 +120,19 @@ impl M17App {
         }
         self.set_lifecycle(Lifecycle::Started);
         let mut errs = vec![];
-        {
+        // Adapters may use their handle straight away, which needs the lock
+        let (packet, stream) = {
             let adapters = self.adapters.read().unwrap();
-            for (i, p) in &adapters.packet {
-                if let Err(e) = p.start(self.tx()) {
-                    errs.push(M17Error::Adapter(*i, e));
-                }
+            (adapters.packet.clone(), adapters.stream.clone())
+        };
+        for (i, p) in packet {
+            if let Err(e) = p.start(self.adapter_tx(i)) {
+                errs.push(M17Error::Adapter(i, e));
             }
-            for (i, s) in &adapters.stream {
-                if let Err(e) = s.start(self.tx()) {
-                    errs.push(M17Error::Adapter(*i, e));
-                }
+        }
+        for (i, s) in stream {
+            if let Err(e) = s.start(self.adapter_tx(i)) {
+                errs.push(M17Error::Adapter(i, e));
             }
         }
         let _ = self.event_tx.send(TncControlEvent::Start);
@@ -166,6 +183,9 @@ impl M17App {
 
 pub struct TxHandle {
     event_tx: mpsc::SyncSender<TncControlEvent>,
+    adapters: Arc<RwLock<Adapters>>,
+    /// The adapter this handle was given to, if any
+    adapter: Option<usize>,
 }
 
 impl TxHandle {
@@ -193,6 +213,7 @@ impl TxHandle {
     }
 
     pub fn transmit_stream_start(&self, link_setup: &LinkSetup) {
+        self.adapters.write().unwrap().stream_tx_owner = self.adapter;
         let kiss_frame = KissFrame::new_stream_setup(&link_setup.raw.0).unwrap();
         let _ = self.event_tx.send(TncControlEvent::Kiss(kiss_frame));
     }
@@ -213,6 +234,8 @@ struct Adapters {
     next: usize,
     packet: HashMap<usize, Arc<dyn PacketAdapter>>,
     stream: HashMap<usize, Arc<dyn StreamAdapter>>,
+    /// Stream adapter that started the most recent outgoing stream, if it was one of ours
+    stream_tx_owner: Option<usize>,
 }
 
 impl Adapters {
@@ -221,6 +244,7 @@ impl Adapters {
             next: 0,
             packet: HashMap::new(),
             stream: HashMap::new(),
+            stream_tx_owner: None,
         }
     }
 }
@@ -237,23 +261,69 @@ fn spawn_reader<T: Tnc>(mut tnc: T, adapters: Arc<RwLock<Adapters>>) {
     std::thread::spawn(move || {
         let mut kiss_buffer = KissBuffer::new();
         let mut stream_running = false;
+        // Port 0 is a fallback for TNCs that only speak basic mode. We ask the TNC to stop
+        // duplicating full packets there on start, but in case it ignores the request we also
+        // stop using port 0 as soon as anything arrives on port 1.
+        let mut full_packets_seen = false;
+        // Quality reports precede the data frame they describe, on the same port
+        let mut pending_quality: [Option<SignalQuality>; 3] = [None; 3];
         loop {
             let buf = kiss_buffer.buf_remaining();
             let n = match tnc.read(buf) {
+                // End of stream, e.g., the TNC hung up
+                Ok(0) => break,
                 Ok(n) => n,
                 Err(_) => break,
             };
             kiss_buffer.did_write(n);
             while let Some(frame) = kiss_buffer.next_frame() {
+                if frame.command() == Ok(KissCommand::SetHardware) {
+                    if let Some(quality) = signal_quality_report(frame)
+                        && let Some(pending) = frame
+                            .port()
+                            .ok()
+                            .and_then(|p| pending_quality.get_mut(p as usize))
+                    {
+                        *pending = Some(quality);
+                    } else {
+                        handle_hardware_report(frame, &adapters, &mut stream_running);
+                    }
+                    continue;
+                }
                 if frame.command() != Ok(KissCommand::DataFrame) {
                     continue;
                 }
+                let quality = frame
+                    .port()
+                    .ok()
+                    .and_then(|p| pending_quality.get_mut(p as usize))
+                    .and_then(|q| q.take());
                 match frame.port() {
                     Ok(m17core::kiss::PORT_PACKET_BASIC) => {
-                        // no action
-                        // we will handle the more full-featured version from from port 1
+                        if full_packets_seen {
+                            // we will handle the more full-featured version from from port 1
+                            continue;
+                        }
+                        let mut payload = [0u8; 855];
+                        let Ok(n) = frame.decode_payload(&mut payload) else {
+                            debug!("failed to decode payload from KISS frame");
+                            continue;
+                        };
+                        // Basic mode doesn't tell us who sent it or to whom
+                        let lsf = LsfFrame::new_packet(&Address::Invalid, &Address::Broadcast);
+                        let packet_payload: Arc<[u8]> = Arc::from(&payload[0..n]);
+                        let subs: Vec<_> =
+                            adapters.read().unwrap().packet.values().cloned().collect();
+                        for s in subs {
+                            s.packet_received(
+                                LinkSetup::new_raw(lsf.clone()),
+                                PacketType::Raw,
+                                packet_payload.clone(),
+                            );
+                        }
                     }
                     Ok(m17core::kiss::PORT_PACKET_FULL) => {
+                        full_packets_seen = true;
                         let mut payload = [0u8; 855]; // 30 byte LSF + 825 byte packet including CRC
                         let Ok(n) = frame.decode_payload(&mut payload) else {
                             debug!("failed to decode payload from KISS frame");
@@ -293,7 +363,7 @@ fn spawn_reader<T: Tnc>(mut tnc: T, adapters: Arc<RwLock<Adapters>>) {
                             adapters.read().unwrap().packet.values().cloned().collect();
                         for s in subs {
                             s.packet_received(
-                                LinkSetup::new_raw(lsf.clone()),
+                                LinkSetup::new_received(lsf.clone(), quality),
                                 packet_type,
                                 packet_payload.clone(),
                             );
@@ -315,7 +385,7 @@ fn spawn_reader<T: Tnc>(mut tnc: T, adapters: Arc<RwLock<Adapters>>) {
                             let subs: Vec<_> =
                                 adapters.read().unwrap().stream.values().cloned().collect();
                             for s in subs {
-                                s.stream_began(LinkSetup::new_raw(lsf.clone()));
+                                s.stream_began(LinkSetup::new_received(lsf.clone(), quality));
                             }
                         } else if n == 26 {
                             if !stream_running {
@@ -338,6 +408,9 @@ fn spawn_reader<T: Tnc>(mut tnc: T, adapters: Arc<RwLock<Adapters>>) {
                             let subs: Vec<_> =
                                 adapters.read().unwrap().stream.values().cloned().collect();
                             for s in subs {
+                                if let Some(quality) = quality {
+                                    s.stream_frame_quality(frame_number, quality);
+                                }
                                 s.stream_data(frame_number, is_final, data.clone());
                             }
                         }
@@ -349,6 +422,70 @@ fn spawn_reader<T: Tnc>(mut tnc: T, adapters: Arc<RwLock<Adapters>>) {
     });
 }
 
+/// Decode a signal quality report from the TNC, if that's what this is.
+fn signal_quality_report(frame: &KissFrame) -> Option<SignalQuality> {
+    let mut payload = [0u8; 64];
+    let n = frame.decode_payload(&mut payload).ok()?;
+    if n < 1 || payload[0] != m17core::kiss::HW_SIGNAL_QUALITY {
+        return None;
+    }
+    SignalQuality::from_bytes(&payload[1..n])
+}
+
+/// React to M17RT extension messages from the TNC.
+fn handle_hardware_report(
+    frame: &KissFrame,
+    adapters: &RwLock<Adapters>,
+    stream_running: &mut bool,
+) {
+    let mut payload = [0u8; 64];
+    let Ok(n) = frame.decode_payload(&mut payload) else {
+        return;
+    };
+    if n >= 2 && payload[0] == m17core::kiss::HW_STREAM_ENDED_EARLY {
+        debug!(
+            "TNC ended stream early: {:?}",
+            StreamEndReason::from_proto(payload[1])
+        );
+        let owner = {
+            let mut adapters = adapters.write().unwrap();
+            adapters
+                .stream_tx_owner
+                .take()
+                .and_then(|id| adapters.stream.get(&id).cloned())
+        };
+        if let Some(s) = owner {
+            s.stream_tx_ended_early();
+        }
+    } else if n >= 2 && payload[0] == m17core::kiss::HW_STREAM_LOST {
+        let Some(reason) = StreamLostReason::from_proto(payload[1]) else {
+            return;
+        };
+        debug!("TNC lost incoming stream: {reason:?}");
+        if !*stream_running {
+            return;
+        }
+        *stream_running = false;
+        let subs: Vec<_> = adapters.read().unwrap().stream.values().cloned().collect();
+        for s in subs {
+            s.stream_lost(reason);
+        }
+    } else if n >= 33 && payload[0] == m17core::kiss::HW_PACKET_DISCARDED {
+        let Some(reason) = PacketDiscardReason::from_proto(payload[1]) else {
+            return;
+        };
+        let lsf = LsfFrame(payload[3..33].try_into().unwrap());
+        debug!(
+            "TNC discarded partial packet after {} frames: {reason:?}",
+            payload[2]
+        );
+        let subs: Vec<_> = adapters.read().unwrap().packet.values().cloned().collect();
+        for s in subs {
+            s.packet_discarded(LinkSetup::new_raw(lsf.clone()), reason);
+        }
+    }
+}
+
 fn spawn_writer<T: Tnc>(mut tnc: T, event_rx: mpsc::Receiver<TncControlEvent>) {
     std::thread::spawn(move || {
         while let Ok(ev) = event_rx.recv() {
@@ -360,6 +497,17 @@ fn spawn_writer<T: Tnc>(mut tnc: T, event_rx: mpsc::Receiver<TncControlEvent>) {
                 }
                 TncControlEvent::Start => {
                     tnc.start();
+                    // TNCs that don't support this extension will ignore it
+                    let enable = KissFrame::new_set_signal_quality_reports(PORT_STREAM, true);
+                    if tnc.write_all(enable.as_bytes()).is_err() {
+                        return;
+                    }
+                    // We read full packets from port 1 so the port 0 copies are redundant
+                    let disable =
+                        KissFrame::new_set_basic_packets(m17core::kiss::PORT_PACKET_BASIC, false);
+                    if tnc.write_all(disable.as_bytes()).is_err() {
+                        return;
+                    }
                 }
                 TncControlEvent::Close => {
                     tnc.close();
@@ -371,11 +519,42 @@ fn spawn_writer<T: Tnc>(mut tnc: T, event_rx: mpsc::Receiver<TncControlEvent>) {
 
 #[cfg(test)]
 mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
     use crate::error::AdapterError;
     use crate::{link_setup::M17Address, test_util::NullTnc};
 
     use super::*;
 
+    /// An app whose TNC is a TCP connection, along with the other end of it for the test to drive.
+    fn app_with_tcp_tnc() -> (M17App, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let app = M17App::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (tnc_side, _) = listener.accept().unwrap();
+        tnc_side
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (app, tnc_side)
+    }
+
+    /// Read KISS frames sent by the app until one matches `expected`.
+    fn expect_from_app(tnc_side: &mut TcpStream, expected: &KissFrame) {
+        let mut kiss_buffer = KissBuffer::new();
+        loop {
+            let buf = kiss_buffer.buf_remaining();
+            let n = tnc_side.read(buf).unwrap();
+            assert_ne!(n, 0, "app never sent {:?}", expected.as_bytes());
+            kiss_buffer.did_write(n);
+            while let Some(frame) = kiss_buffer.next_frame() {
+                if frame.as_bytes() == expected.as_bytes() {
+                    return;
+                }
+            }
+        }
+    }
+
     #[test]
     fn packet_payload_len() {
         let app = M17App::new(NullTnc);
@@ -447,4 +626,120 @@ mod tests {
         assert_eq!(rx_s.try_recv(), Ok(Event::Closed));
         assert_eq!(rx_s.try_recv(), Err(mpsc::TryRecvError::Disconnected));
     }
+
+    #[test]
+    fn basic_packets_only_without_port_1() {
+        struct Received(mpsc::SyncSender<(PacketType, Vec<u8>)>);
+        impl PacketAdapter for Received {
+            fn packet_received(&self, _: LinkSetup, packet_type: PacketType, content: Arc<[u8]>) {
+                let _ = self.0.send((packet_type, content.to_vec()));
+            }
+        }
+
+        let (app, mut tnc_side) = app_with_tcp_tnc();
+        let (tx, rx) = mpsc::sync_channel(16);
+        app.add_packet_adapter(Received(tx)).unwrap();
+
+        // TNC that only speaks basic mode
+        let basic = KissFrame::new_basic_packet(b"hello").unwrap();
+        tnc_side.write_all(basic.as_bytes()).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (PacketType::Raw, b"hello".to_vec())
+        );
+
+        // A TNC that ignores the opt-out also sends full packets on port 0, which we skip once
+        // port 1 shows up
+        let lsf = LsfFrame::new_packet(&Address::Invalid, &Address::Broadcast);
+        let mut packet = vec![0x00];
+        packet.extend_from_slice(b"full");
+        packet.extend_from_slice(&m17core::crc::m17_crc(&packet).to_be_bytes());
+        let full = KissFrame::new_full_packet(&lsf.0, &packet).unwrap();
+        tnc_side.write_all(full.as_bytes()).unwrap();
+        let basic = KissFrame::new_basic_packet(b"full").unwrap();
+        tnc_side.write_all(basic.as_bytes()).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (PacketType::Raw, b"full".to_vec())
+        );
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn start_disables_basic_packets() {
+        let (app, mut tnc_side) = app_with_tcp_tnc();
+        app.start().unwrap();
+        let disable = KissFrame::new_set_basic_packets(m17core::kiss::PORT_PACKET_BASIC, false);
+        expect_from_app(&mut tnc_side, &disable);
+    }
+
+    #[test]
+    fn stream_ended_early_goes_to_transmitting_adapter() {
+        struct Streamer {
+            name: &'static str,
+            transmit: bool,
+            ended: mpsc::SyncSender<&'static str>,
+        }
+        impl StreamAdapter for Streamer {
+            fn start(&self, handle: TxHandle) -> Result<(), AdapterError> {
+                if self.transmit {
+                    let link_setup = LinkSetup::new_voice(
+                        &M17Address::from_callsign("VK7XT").unwrap(),
+                        &M17Address::new_broadcast(),
+                    );
+                    handle.transmit_stream_start(&link_setup);
+                }
+                Ok(())
+            }
+
+            fn stream_tx_ended_early(&self) {
+                let _ = self.ended.send(self.name);
+            }
+        }
+
+        let (app, mut tnc_side) = app_with_tcp_tnc();
+        let (tx, rx) = mpsc::sync_channel(16);
+        for (name, transmit) in [("listener", false), ("talker", true)] {
+            app.add_stream_adapter(Streamer {
+                name,
+                transmit,
+                ended: tx.clone(),
+            })
+            .unwrap();
+        }
+        app.start().unwrap();
+
+        let ended = KissFrame::new_stream_ended_early(PORT_STREAM, StreamEndReason::Underrun);
+        tnc_side.write_all(ended.as_bytes()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("talker"));
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn stream_lost_reaches_adapters() {
+        struct Lost(mpsc::SyncSender<StreamLostReason>);
+        impl StreamAdapter for Lost {
+            fn stream_lost(&self, reason: StreamLostReason) {
+                let _ = self.0.send(reason);
+            }
+        }
+
+        let (app, mut tnc_side) = app_with_tcp_tnc();
+        let (tx, rx) = mpsc::sync_channel(16);
+        app.add_stream_adapter(Lost(tx)).unwrap();
+
+        let lost = KissFrame::new_stream_lost(PORT_STREAM, StreamLostReason::Timeout);
+        // Nothing to report if we never told the adapter a stream had begun
+        tnc_side.write_all(lost.as_bytes()).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let lsf = LsfFrame::new_voice(&Address::Invalid, &Address::Broadcast);
+        let setup = KissFrame::new_stream_setup(&lsf.0).unwrap();
+        tnc_side.write_all(setup.as_bytes()).unwrap();
+        tnc_side.write_all(lost.as_bytes()).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Ok(StreamLostReason::Timeout)
+        );
+    }
 }
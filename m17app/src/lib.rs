@@ -5,15 +5,18 @@ pub mod app;
 pub mod error;
 pub mod link_setup;
 pub mod reflector;
+pub mod rigctld;
 pub mod rtlsdr;
 pub mod serial;
 pub mod soundcard;
 pub mod soundmodem;
 pub mod tnc;
 pub mod util;
+pub mod virtual_air;
 
 #[cfg(test)]
 mod test_util;
 
 // Protocol definitions needed to implement stream and packet adapters or create fully custom LSFs
+pub use m17core::modem::SignalQuality;
 pub use m17core::protocol::{LsfFrame, PacketType, StreamFrame};
@@ -2,6 +2,7 @@ use std::fmt::Display;
 
 use m17core::{
     address::{ALPHABET, Address, Callsign},
+    modem::SignalQuality,
     protocol::LsfFrame,
 };
 
@@ -9,12 +10,23 @@ use crate::error::M17Error;
 
 pub struct LinkSetup {
     pub(crate) raw: LsfFrame,
+    pub(crate) quality: Option<SignalQuality>,
 }
 
 impl LinkSetup {
     /// Provide a completed LsfFrame.
     pub fn new_raw(frame: LsfFrame) -> Self {
-        Self { raw: frame }
+        Self {
+            raw: frame,
+            quality: None,
+        }
+    }
+
+    pub(crate) fn new_received(frame: LsfFrame, quality: Option<SignalQuality>) -> Self {
+        Self {
+            raw: frame,
+            quality,
+        }
     }
 
     pub fn source(&self) -> M17Address {
@@ -25,10 +37,19 @@ impl LinkSetup {
         M17Address(self.raw.destination())
     }
 
+    /// How well this transmission was received, if the TNC reports signal quality.
+    ///
+    /// For a packet this covers every frame in the packet. For a stream it covers only the LSF;
+    /// see `StreamAdapter::stream_frame_quality` for the frames that follow.
+    pub fn signal_quality(&self) -> Option<SignalQuality> {
+        self.quality
+    }
+
     /// Set up an unencrypted voice stream with channel access number 0 and the given source and destination.
     pub fn new_voice(source: &M17Address, destination: &M17Address) -> Self {
         Self {
             raw: LsfFrame::new_voice(source.address(), destination.address()),
+            quality: None,
         }
     }
 
@@ -36,6 +57,7 @@ impl LinkSetup {
     pub fn new_packet(source: &M17Address, destination: &M17Address) -> Self {
         Self {
             raw: LsfFrame::new_packet(source.address(), destination.address()),
+            quality: None,
         }
     }
 
@@ -323,10 +323,10 @@ fn spawn_reader(socket: UdpSocket, event_tx: Sender<TncEvent>, cancel: Arc<Atomi
             if cancel.load(Ordering::Acquire) {
                 break;
             }
-            if let Some(msg) = ServerMessage::parse(&buf[..n]) {
-                if event_tx.send(TncEvent::Received(msg)).is_err() {
-                    break;
-                }
+            if let Some(msg) = ServerMessage::parse(&buf[..n])
+                && event_tx.send(TncEvent::Received(msg)).is_err()
+            {
+                break;
             }
         }
     });
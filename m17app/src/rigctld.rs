@@ -0,0 +1,254 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::{error::SoundmodemError, soundmodem::Ptt};
+
+/// Port on which `rigctld` listens unless configured otherwise.
+pub const DEFAULT_RIGCTLD_PORT: u16 = 4532;
+
+/// How long to wait for `rigctld` to accept a connection or answer a command.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Key the radio using CAT commands sent via hamlib's `rigctld` daemon.
+///
+/// This works with any rig that hamlib supports. `rigctld` must already be running and configured
+/// for the radio, e.g., `rigctld -m 1035 -r /dev/ttyUSB0`. If the connection drops, for example
+/// because `rigctld` was restarted, each command makes one attempt to reconnect before failing.
+pub struct RigctldPtt {
+    addr: SocketAddr,
+    conn: Option<Connection>,
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RigctldPtt {
+    /// Connect to `rigctld` at the given address, e.g., `("localhost", DEFAULT_RIGCTLD_PORT)`.
+    ///
+    /// PTT is switched off once connected.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self, SoundmodemError> {
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(RigctldError::Connect)?
+            .next()
+            .ok_or_else(|| {
+                RigctldError::Connect(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "address did not resolve",
+                ))
+            })?;
+        let mut s = Self { addr, conn: None };
+        s.ptt_off()?;
+        Ok(s)
+    }
+
+    /// Ask `rigctld` whether the radio is currently transmitting.
+    pub fn ptt_status(&mut self) -> Result<bool, SoundmodemError> {
+        let response = self.command("t")?;
+        match response.trim().parse::<u8>() {
+            // Some rigs report 2 or 3 to indicate which audio input is keyed
+            Ok(status) => Ok(status != 0),
+            Err(_) => Err(parse_error(&response).into()),
+        }
+    }
+
+    fn set_ptt(&mut self, on: bool) -> Result<(), RigctldError> {
+        let response = self.command(if on { "T 1" } else { "T 0" })?;
+        match parse_report(&response) {
+            Some(0) => Ok(()),
+            _ => Err(parse_error(&response)),
+        }
+    }
+
+    /// Send a command and return the first line of the response, reconnecting if required.
+    fn command(&mut self, command: &str) -> Result<String, RigctldError> {
+        match self.try_command(command) {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                log::debug!("rigctld command failed, reconnecting: {e}");
+                self.conn = None;
+                self.try_command(command)
+            }
+        }
+    }
+
+    fn try_command(&mut self, command: &str) -> Result<String, RigctldError> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(Connection::open(&self.addr)?),
+        };
+        let result = conn.send(command);
+        if result.is_err() {
+            self.conn = None;
+        }
+        result.map_err(RigctldError::Io)
+    }
+}
+
+impl Connection {
+    fn open(addr: &SocketAddr) -> Result<Self, RigctldError> {
+        let stream = TcpStream::connect_timeout(addr, TIMEOUT).map_err(RigctldError::Connect)?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(RigctldError::Connect)?;
+        stream
+            .set_write_timeout(Some(TIMEOUT))
+            .map_err(RigctldError::Connect)?;
+        let _ = stream.set_nodelay(true);
+        let writer = stream.try_clone().map_err(RigctldError::Connect)?;
+        Ok(Self {
+            reader: BufReader::new(stream),
+            writer,
+        })
+    }
+
+    fn send(&mut self, command: &str) -> io::Result<String> {
+        self.writer.write_all(format!("{command}\n").as_bytes())?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line)
+    }
+}
+
+/// Extract the code from a `RPRT n` response.
+fn parse_report(response: &str) -> Option<i32> {
+    response.trim().strip_prefix("RPRT ")?.parse().ok()
+}
+
+fn parse_error(response: &str) -> RigctldError {
+    match parse_report(response) {
+        Some(code) => RigctldError::Rejected(code),
+        None => RigctldError::UnexpectedResponse(response.trim().to_owned()),
+    }
+}
+
+impl Ptt for RigctldPtt {
+    fn ptt_on(&mut self) -> Result<(), SoundmodemError> {
+        Ok(self.set_ptt(true)?)
+    }
+
+    fn ptt_off(&mut self) -> Result<(), SoundmodemError> {
+        Ok(self.set_ptt(false)?)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RigctldError {
+    #[error("unable to connect to rigctld: {0}")]
+    Connect(#[source] io::Error),
+
+    #[error("lost connection to rigctld: {0}")]
+    Io(#[source] io::Error),
+
+    #[error("rigctld reported error code {0}")]
+    Rejected(i32),
+
+    #[error("unexpected response from rigctld: '{0}'")]
+    UnexpectedResponse(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Minimal stand-in for `rigctld` which tracks PTT state and records the commands it receives.
+    ///
+    /// Each connection is closed after `commands_per_connection` commands, if given.
+    fn fake_rigctld(
+        commands_per_connection: Option<usize>,
+        ptt_response: &'static str,
+    ) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+        let log = received.clone();
+        std::thread::spawn(move || {
+            let mut ptt = false;
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    break;
+                };
+                let mut writer = stream.try_clone().unwrap();
+                let reader = BufReader::new(stream);
+                for (count, line) in reader.lines().enumerate() {
+                    let Ok(line) = line else {
+                        break;
+                    };
+                    let response = match line.as_str() {
+                        "T 1" | "T 0" => {
+                            ptt = line == "T 1";
+                            ptt_response.to_owned()
+                        }
+                        "t" => format!("{}", ptt as u8),
+                        _ => "RPRT -1".to_owned(),
+                    };
+                    log.lock().unwrap().push(line);
+                    writer
+                        .write_all(format!("{response}\n").as_bytes())
+                        .unwrap();
+                    if commands_per_connection == Some(count + 1) {
+                        break;
+                    }
+                }
+            }
+        });
+        (addr, received)
+    }
+
+    #[test]
+    fn key_and_query() {
+        let (addr, received) = fake_rigctld(None, "RPRT 0");
+        let mut ptt = RigctldPtt::new(addr).unwrap();
+        assert!(!ptt.ptt_status().unwrap());
+        ptt.ptt_on().unwrap();
+        assert!(ptt.ptt_status().unwrap());
+        ptt.ptt_off().unwrap();
+        assert!(!ptt.ptt_status().unwrap());
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["T 0", "t", "T 1", "t", "T 0", "t"]
+        );
+    }
+
+    #[test]
+    fn reconnects_after_disconnection() {
+        let (addr, received) = fake_rigctld(Some(1), "RPRT 0");
+        let mut ptt = RigctldPtt::new(addr).unwrap();
+        ptt.ptt_on().unwrap();
+        assert!(ptt.ptt_status().unwrap());
+        ptt.ptt_off().unwrap();
+        assert_eq!(*received.lock().unwrap(), ["T 0", "T 1", "t", "T 0"]);
+    }
+
+    #[test]
+    fn reports_rig_errors() {
+        let (addr, _) = fake_rigctld(None, "RPRT -9");
+        let err = RigctldPtt::new(addr).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RigctldError>(),
+            Some(RigctldError::Rejected(-9))
+        ));
+    }
+
+    #[test]
+    fn reports_unavailable_server() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = RigctldPtt::new(addr).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RigctldError>(),
+            Some(RigctldError::Connect(_))
+        ));
+    }
+}
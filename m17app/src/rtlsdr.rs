@@ -54,12 +54,12 @@ impl InputSource for RtlSdr {
             while let Ok(n) = stdout.read(&mut buf) {
                 let mut start_idx = 0;
                 let mut samples = vec![];
-                if let Some(left) = leftover {
-                    if n > 0 {
-                        samples.push(i16::from_le_bytes([left, buf[0]]));
-                        start_idx = 1;
-                        leftover = None;
-                    }
+                if let Some(left) = leftover
+                    && n > 0
+                {
+                    samples.push(i16::from_le_bytes([left, buf[0]]));
+                    start_idx = 1;
+                    leftover = None;
                 }
                 for sample in buf[start_idx..n].chunks(2) {
                     if sample.len() == 2 {
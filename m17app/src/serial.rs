@@ -1,7 +1,20 @@
-use serialport::SerialPort;
+use std::io;
+use std::time::{Duration, Instant};
+
+use serialport::{ClearBuffer, SerialPort};
+use thiserror::Error;
 
 use crate::{error::SoundmodemError, soundmodem::Ptt};
 
+/// How long to wait for the radio to answer a CAT command, however much other traffic arrives.
+const CAT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Longest response we expect from the radio, to stop a noisy line keeping us waiting forever.
+const CAT_MAX_RESPONSE: usize = 64;
+
+/// CI-V address we use as the controller, as is conventional.
+const CIV_CONTROLLER: u8 = 0xE0;
+
 /// The pin on the serial port which is driving PTT
 pub enum PttPin {
     // Ready To Send (RTS)
@@ -46,3 +59,359 @@ impl Ptt for SerialPtt {
         }?)
     }
 }
+
+/// Command set spoken by the radio on its CAT serial port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatProtocol {
+    /// Kenwood and Elecraft ASCII commands, `TX;` and `RX;`.
+    Kenwood,
+    /// Yaesu ASCII commands used by the FT-991, FT-710, FTDX10 and similar, `TX1;` and `TX0;`.
+    Yaesu,
+    /// Icom CI-V, given the radio's bus address, e.g., 0x94 for the IC-7300.
+    IcomCiv { address: u8 },
+}
+
+/// Key the radio by sending CAT transmit commands over a serial port.
+///
+/// After each command the radio is checked to confirm that it accepted it. If the radio does not
+/// answer within a short timeout, an error is reported.
+pub struct CatPtt {
+    port: Box<dyn SerialPort>,
+    protocol: CatProtocol,
+}
+
+impl CatPtt {
+    /// Open the serial port at the given baud rate, which must match the radio's CAT setting.
+    ///
+    /// PTT is switched off once the port is open.
+    pub fn new(
+        port_name: &str,
+        baud_rate: u32,
+        protocol: CatProtocol,
+    ) -> Result<Self, SoundmodemError> {
+        let port = serialport::new(port_name, baud_rate).open()?;
+        Self::from_port(port, protocol)
+    }
+
+    /// Use a serial port that has already been opened and configured.
+    ///
+    /// PTT is switched off immediately.
+    pub fn from_port(
+        mut port: Box<dyn SerialPort>,
+        protocol: CatProtocol,
+    ) -> Result<Self, SoundmodemError> {
+        port.set_timeout(CAT_TIMEOUT)?;
+        let mut s = Self { port, protocol };
+        s.ptt_off()?;
+        Ok(s)
+    }
+
+    fn set_ptt(&mut self, on: bool) -> Result<(), CatError> {
+        // Discard anything unsolicited so we only see the answers to our own commands
+        let _ = self.port.clear(ClearBuffer::Input);
+        let deadline = Instant::now() + CAT_TIMEOUT;
+        match self.protocol {
+            CatProtocol::Kenwood => {
+                self.send(if on { b"TX;" } else { b"RX;" })?;
+                // Neither command has a response, so read back the TX/RX flag from the status
+                self.send(b"IF;")?;
+                let response = self.read_until(b';', deadline)?;
+                match response.get(28) {
+                    Some(flag) if response.starts_with(b"IF") && *flag == b'0' + on as u8 => Ok(()),
+                    _ => Err(unexpected(response)),
+                }
+            }
+            CatProtocol::Yaesu => {
+                self.send(if on { b"TX1;" } else { b"TX0;" })?;
+                self.send(b"TX;")?;
+                let response = self.read_until(b';', deadline)?;
+                // TX1 means keyed by CAT, TX2 means keyed by the radio itself, e.g., the mic
+                match response.as_slice() {
+                    b"TX1;" | b"TX2;" if on => Ok(()),
+                    b"TX0;" | b"TX2;" if !on => Ok(()),
+                    _ => Err(unexpected(response)),
+                }
+            }
+            CatProtocol::IcomCiv { address } => {
+                let command = [
+                    0xFE,
+                    0xFE,
+                    address,
+                    CIV_CONTROLLER,
+                    0x1C,
+                    0x00,
+                    on as u8,
+                    0xFD,
+                ];
+                self.send(&command)?;
+                loop {
+                    let response = self.read_until(0xFD, deadline)?;
+                    // On a shared CI-V bus we hear our own command and possibly other traffic
+                    let Some(pos) = response.windows(2).position(|w| w == [0xFE, 0xFE]) else {
+                        return Err(unexpected(response));
+                    };
+                    match &response[pos + 2..] {
+                        [CIV_CONTROLLER, from, 0xFB, 0xFD] if *from == address => return Ok(()),
+                        [CIV_CONTROLLER, from, 0xFA, 0xFD] if *from == address => {
+                            return Err(CatError::Rejected);
+                        }
+                        _ => continue,
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, command: &[u8]) -> Result<(), CatError> {
+        self.port.write_all(command).map_err(CatError::Io)
+    }
+
+    /// Read a response up to and including `terminator`, giving up at `deadline`.
+    fn read_until(&mut self, terminator: u8, deadline: Instant) -> Result<Vec<u8>, CatError> {
+        let mut response = vec![];
+        let mut byte = [0u8; 1];
+        while response.len() < CAT_MAX_RESPONSE {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(CatError::Timeout);
+            }
+            self.port
+                .set_timeout(remaining)
+                .map_err(|e| CatError::Io(e.into()))?;
+            match self.port.read(&mut byte) {
+                Ok(0) => return Err(CatError::Timeout),
+                Ok(_) => {
+                    response.push(byte[0]);
+                    if byte[0] == terminator {
+                        // Kenwood and Yaesu radios answer a bad command with `?;`
+                        if response == b"?;" {
+                            return Err(CatError::Rejected);
+                        }
+                        return Ok(response);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => return Err(CatError::Timeout),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(CatError::Io(e)),
+            }
+        }
+        Err(unexpected(response))
+    }
+}
+
+fn unexpected(response: Vec<u8>) -> CatError {
+    CatError::UnexpectedResponse(response.escape_ascii().to_string())
+}
+
+impl Ptt for CatPtt {
+    fn ptt_on(&mut self) -> Result<(), SoundmodemError> {
+        Ok(self.set_ptt(true)?)
+    }
+
+    fn ptt_off(&mut self) -> Result<(), SoundmodemError> {
+        Ok(self.set_ptt(false)?)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CatError {
+    #[error("radio did not respond to CAT command")]
+    Timeout,
+
+    #[error("radio rejected CAT command")]
+    Rejected,
+
+    #[error("unexpected CAT response from radio: '{0}'")]
+    UnexpectedResponse(String),
+
+    #[error("serial port error: {0}")]
+    Io(#[source] io::Error),
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serialport::TTYPort;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    /// Messages received by a fake radio.
+    type Received<T> = Arc<Mutex<Vec<T>>>;
+
+    /// Attach a fake radio to one end of a pseudo-terminal and return the other end.
+    ///
+    /// `respond` is given each message the radio receives, up to and including `terminator`, and
+    /// returns the bytes to send back.
+    fn fake_rig(
+        terminator: u8,
+        mut respond: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static,
+    ) -> Box<dyn SerialPort> {
+        let (mut rig, controller) = TTYPort::pair().unwrap();
+        std::thread::spawn(move || {
+            let mut message = vec![];
+            let mut byte = [0u8; 1];
+            loop {
+                match rig.read(&mut byte) {
+                    Ok(1) => {
+                        message.push(byte[0]);
+                        if byte[0] == terminator {
+                            let response = respond(&message);
+                            if rig.write_all(&response).is_err() {
+                                break;
+                            }
+                            message.clear();
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    _ => break,
+                }
+            }
+        });
+        Box::new(controller)
+    }
+
+    /// Fake Kenwood or Yaesu radio, recording each command it receives.
+    fn fake_ascii_rig(protocol: CatProtocol) -> (Box<dyn SerialPort>, Received<String>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let log = received.clone();
+        let mut tx = false;
+        let port = fake_rig(b';', move |message| {
+            let command = String::from_utf8(message.to_vec()).unwrap();
+            log.lock().unwrap().push(command.clone());
+            let response = match (protocol, command.as_str()) {
+                (CatProtocol::Kenwood, "TX;") | (CatProtocol::Yaesu, "TX1;") => {
+                    tx = true;
+                    String::new()
+                }
+                (CatProtocol::Kenwood, "RX;") | (CatProtocol::Yaesu, "TX0;") => {
+                    tx = false;
+                    String::new()
+                }
+                (CatProtocol::Kenwood, "IF;") => {
+                    format!("IF00014074000     +000000000{}0000000;", tx as u8)
+                }
+                (CatProtocol::Yaesu, "TX;") => format!("TX{};", tx as u8),
+                _ => "?;".to_owned(),
+            };
+            response.into_bytes()
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn kenwood_ptt() {
+        let (port, received) = fake_ascii_rig(CatProtocol::Kenwood);
+        let mut ptt = CatPtt::from_port(port, CatProtocol::Kenwood).unwrap();
+        ptt.ptt_on().unwrap();
+        ptt.ptt_off().unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["RX;", "IF;", "TX;", "IF;", "RX;", "IF;"]
+        );
+    }
+
+    #[test]
+    fn yaesu_ptt() {
+        let (port, received) = fake_ascii_rig(CatProtocol::Yaesu);
+        let mut ptt = CatPtt::from_port(port, CatProtocol::Yaesu).unwrap();
+        ptt.ptt_on().unwrap();
+        ptt.ptt_off().unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["TX0;", "TX;", "TX1;", "TX;", "TX0;", "TX;"]
+        );
+    }
+
+    #[test]
+    fn wrong_protocol_is_rejected() {
+        // A Yaesu radio doesn't understand Kenwood's commands
+        let (port, _) = fake_ascii_rig(CatProtocol::Yaesu);
+        let err = CatPtt::from_port(port, CatProtocol::Kenwood).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CatError>(),
+            Some(CatError::Rejected)
+        ));
+    }
+
+    #[test]
+    fn silent_radio_times_out() {
+        let port = fake_rig(b';', |_| vec![]);
+        let err = CatPtt::from_port(port, CatProtocol::Kenwood).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CatError>(),
+            Some(CatError::Timeout)
+        ));
+    }
+
+    /// Fake Icom radio at CI-V address 0x94 which echoes the bus like a real CI-V interface.
+    fn fake_icom(refuse: bool) -> (Box<dyn SerialPort>, Received<Vec<u8>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let log = received.clone();
+        let port = fake_rig(0xFD, move |message| {
+            log.lock().unwrap().push(message.to_vec());
+            let mut response = message.to_vec();
+            if let [0xFE, 0xFE, 0x94, from, ..] = message {
+                let status = if refuse { 0xFA } else { 0xFB };
+                response.extend_from_slice(&[0xFE, 0xFE, *from, 0x94, status, 0xFD]);
+            }
+            response
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn icom_ptt() {
+        let (port, received) = fake_icom(false);
+        let mut ptt = CatPtt::from_port(port, CatProtocol::IcomCiv { address: 0x94 }).unwrap();
+        ptt.ptt_on().unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                [0xFE, 0xFE, 0x94, 0xE0, 0x1C, 0x00, 0x00, 0xFD],
+                [0xFE, 0xFE, 0x94, 0xE0, 0x1C, 0x00, 0x01, 0xFD]
+            ]
+        );
+    }
+
+    #[test]
+    fn icom_refused_or_wrong_address() {
+        let (port, _) = fake_icom(true);
+        let err = CatPtt::from_port(port, CatProtocol::IcomCiv { address: 0x94 })
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CatError>(),
+            Some(CatError::Rejected)
+        ));
+
+        // We only hear the echo of our own command
+        let (port, _) = fake_icom(false);
+        let err = CatPtt::from_port(port, CatProtocol::IcomCiv { address: 0xA4 })
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CatError>(),
+            Some(CatError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn busy_civ_bus_times_out() {
+        // Other stations keep talking but our radio never answers
+        let (mut rig, controller) = TTYPort::pair().unwrap();
+        std::thread::spawn(move || {
+            while rig.write_all(&[0xFE, 0xFE, 0x00, 0x98, 0x03, 0xFD]).is_ok() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        });
+        let start = Instant::now();
+        let err = CatPtt::from_port(Box::new(controller), CatProtocol::IcomCiv { address: 0x94 })
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CatError>(),
+            Some(CatError::Timeout)
+        ));
+        assert!(start.elapsed() < CAT_TIMEOUT * 2);
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
     borrow::Borrow,
     sync::{
-        Arc, RwLock,
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{Receiver, SyncSender, sync_channel},
     },
     time::{Duration, Instant},
@@ -12,11 +13,13 @@ use cpal::{
     SupportedStreamConfigRange, SupportedStreamConfigsError,
     traits::{DeviceTrait, HostTrait, StreamTrait},
 };
+use m17core::modem::{SAMPLE_RATE, SAMPLES_PER_MS};
 use thiserror::Error;
 
 use crate::soundmodem::{
     InputSource, OutputBuffer, OutputSink, SoundmodemErrorSender, SoundmodemEvent,
 };
+use crate::util::level_meter::LevelMeter;
 
 /// A soundcard for used for transmitting/receiving baseband with a `Soundmodem`.
 ///
@@ -24,18 +27,28 @@ use crate::soundmodem::{
 /// It is fine to use an input from one soundcard and and output from another.
 ///
 /// If you try to create more than one `Soundcard` instance at a time for the same card
-/// then it may not work.
+/// then it may not work. Instead, a stereo card can serve two radios by taking an input and an
+/// output for each one, using `with_channel()` to select the left or right channel.
 pub struct Soundcard {
     event_tx: SyncSender<SoundcardEvent>,
+    /// Identifies each input and output handle so they can be closed individually.
+    next_id: AtomicUsize,
+    /// Latest levels of the left, right and mixed input, in the order of `ALL_CHANNELS`.
+    input_levels: Arc<Mutex<[SoundcardInputLevel; 3]>>,
 }
 
 impl Soundcard {
     pub fn new<S: Into<String>>(card_name: S) -> Result<Self, SoundcardError> {
         let (card_tx, card_rx) = sync_channel(128);
         let (setup_tx, setup_rx) = sync_channel(1);
-        spawn_soundcard_worker(card_rx, setup_tx, card_name.into());
+        let input_levels = Arc::new(Mutex::new([SoundcardInputLevel::default(); 3]));
+        spawn_soundcard_worker(card_rx, setup_tx, card_name.into(), input_levels.clone());
         match setup_rx.recv() {
-            Ok(Ok(())) => Ok(Self { event_tx: card_tx }),
+            Ok(Ok(())) => Ok(Self {
+                event_tx: card_tx,
+                next_id: AtomicUsize::new(0),
+                input_levels,
+            }),
             Ok(Err(e)) => Err(e),
             Err(_) => Err(SoundcardError::SoundcardInit),
         }
@@ -44,15 +57,35 @@ impl Soundcard {
     pub fn input(&self) -> SoundcardInputSource {
         SoundcardInputSource {
             event_tx: self.event_tx.clone(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            channel: SoundcardChannel::Left,
+            gain: 1.0,
         }
     }
 
     pub fn output(&self) -> SoundcardOutputSink {
         SoundcardOutputSink {
             event_tx: self.event_tx.clone(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            channel: SoundcardChannel::Mono,
+            gain: 1.0,
+            keying_tone: None,
         }
     }
 
+    /// Current level of the signal arriving on an input channel, for setting up the interface.
+    ///
+    /// This is measured before any gain applied with `SoundcardInputSource::with_gain()` and is
+    /// only updated while an input is running. `SoundmodemStats` shows the level after gain.
+    pub fn input_level(&self, channel: SoundcardChannel) -> SoundcardInputLevel {
+        let idx = ALL_CHANNELS.iter().position(|c| *c == channel).unwrap();
+        self.input_levels.lock().unwrap()[idx]
+    }
+
+    /// Invert received samples before they reach the soundmodem.
+    ///
+    /// This is rarely needed since `Soundmodem` detects received polarity automatically unless
+    /// told otherwise with `set_rx_polarity()`.
     pub fn set_rx_inverted(&self, inverted: bool) {
         let _ = self.event_tx.send(SoundcardEvent::SetRxInverted(inverted));
     }
@@ -114,44 +147,147 @@ fn config_is_compatible<C: Borrow<SupportedStreamConfigRange>>(config: C) -> boo
     let config = config.borrow();
     (config.channels() == 1 || config.channels() == 2)
         && config.sample_format() == SampleFormat::I16
-        && config.min_sample_rate().0 <= 48000
-        && config.max_sample_rate().0 >= 48000
+        && config.min_sample_rate().0 <= SAMPLE_RATE as u32
+        && config.max_sample_rate().0 >= SAMPLE_RATE as u32
 }
 
 enum SoundcardEvent {
     SetRxInverted(bool),
     SetTxInverted(bool),
     StartInput {
+        id: usize,
+        channel: SoundcardChannel,
+        gain: f32,
         samples: SyncSender<SoundmodemEvent>,
         errors: SoundmodemErrorSender,
     },
-    CloseInput,
+    CloseInput {
+        id: usize,
+    },
     StartOutput {
+        id: usize,
+        channel: SoundcardChannel,
+        gain: f32,
         event_tx: SyncSender<SoundmodemEvent>,
         buffer: Arc<RwLock<OutputBuffer>>,
         errors: SoundmodemErrorSender,
+        keying_tone: Option<f32>,
+    },
+    CloseOutput {
+        id: usize,
     },
-    CloseOutput,
+}
+
+/// Which channel of a stereo sound card carries the M17 baseband.
+///
+/// On a mono sound card the single channel is always used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundcardChannel {
+    Left,
+    Right,
+    /// For input, the average of both channels. For output, the same signal on both channels.
+    Mono,
+}
+
+/// Level of the signal arriving at a sound card input, before any software gain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoundcardInputLevel {
+    /// Largest absolute sample value over the most recent 100 ms.
+    pub peak: u16,
+    /// RMS level in dBFS over the most recent 100 ms.
+    pub rms_dbfs: f32,
+    /// At least one sample in the most recent 100 ms was at full scale.
+    pub clipped: bool,
+}
+
+impl Default for SoundcardInputLevel {
+    fn default() -> Self {
+        Self {
+            peak: 0,
+            rms_dbfs: f32::NEG_INFINITY,
+            clipped: false,
+        }
+    }
 }
 
 pub struct SoundcardInputSource {
     event_tx: SyncSender<SoundcardEvent>,
+    id: usize,
+    channel: SoundcardChannel,
+    gain: f32,
+}
+
+impl SoundcardInputSource {
+    /// Receive baseband from this channel. Default `Left`.
+    ///
+    /// Inputs on different channels of the same card can serve separate soundmodems.
+    pub fn with_channel(mut self, channel: SoundcardChannel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Amplify received samples by this many dB, or attenuate them if negative. Default 0 dB.
+    ///
+    /// Samples are clipped at full scale.
+    pub fn with_gain(mut self, gain_db: f32) -> Self {
+        self.gain = db_to_amplitude(gain_db);
+        self
+    }
 }
 
 impl InputSource for SoundcardInputSource {
     fn start(&self, samples: SyncSender<SoundmodemEvent>, errors: SoundmodemErrorSender) {
-        let _ = self
-            .event_tx
-            .send(SoundcardEvent::StartInput { samples, errors });
+        let _ = self.event_tx.send(SoundcardEvent::StartInput {
+            id: self.id,
+            channel: self.channel,
+            gain: self.gain,
+            samples,
+            errors,
+        });
     }
 
     fn close(&self) {
-        let _ = self.event_tx.send(SoundcardEvent::CloseInput);
+        let _ = self
+            .event_tx
+            .send(SoundcardEvent::CloseInput { id: self.id });
     }
 }
 
 pub struct SoundcardOutputSink {
     event_tx: SyncSender<SoundcardEvent>,
+    id: usize,
+    channel: SoundcardChannel,
+    gain: f32,
+    keying_tone: Option<f32>,
+}
+
+impl SoundcardOutputSink {
+    /// Transmit baseband on this channel. Default `Mono`, i.e., both channels.
+    ///
+    /// Outputs on different channels of the same card can serve separate soundmodems.
+    pub fn with_channel(mut self, channel: SoundcardChannel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Amplify transmitted samples by this many dB, or attenuate them if negative. Default 0 dB.
+    ///
+    /// Samples are clipped at full scale.
+    pub fn with_gain(mut self, gain_db: f32) -> Self {
+        self.gain = db_to_amplitude(gain_db);
+        self
+    }
+
+    /// Key the radio by playing a tone of this frequency on the other channel while PTT is on.
+    ///
+    /// This suits interfaces which detect a tone on one channel to key the radio. The tone goes
+    /// on the left channel if the baseband is on the right, otherwise the baseband is sent on the
+    /// left channel only and the tone on the right. It has no effect on a mono sound card.
+    /// `Soundmodem::set_vox_lead_in()` may be needed to give the interface time to key up.
+    pub fn with_keying_tone(mut self, frequency_hz: f32) -> Self {
+        self.keying_tone = Some(frequency_hz);
+        self
+    }
 }
 
 impl OutputSink for SoundcardOutputSink {
@@ -162,21 +298,48 @@ impl OutputSink for SoundcardOutputSink {
         errors: SoundmodemErrorSender,
     ) {
         let _ = self.event_tx.send(SoundcardEvent::StartOutput {
+            id: self.id,
+            channel: self.channel,
+            gain: self.gain,
             event_tx,
             buffer,
             errors,
+            keying_tone: self.keying_tone,
         });
     }
 
     fn close(&self) {
-        let _ = self.event_tx.send(SoundcardEvent::CloseOutput);
+        let _ = self
+            .event_tx
+            .send(SoundcardEvent::CloseOutput { id: self.id });
     }
 }
 
+/// A started `SoundcardInputSource`.
+struct InputConsumer {
+    id: usize,
+    channel: SoundcardChannel,
+    gain: f32,
+    samples: SyncSender<SoundmodemEvent>,
+    errors: SoundmodemErrorSender,
+}
+
+/// A started `SoundcardOutputSink`.
+struct OutputProducer {
+    id: usize,
+    channel: SoundcardChannel,
+    gain: f32,
+    event_tx: SyncSender<SoundmodemEvent>,
+    buffer: Arc<RwLock<OutputBuffer>>,
+    errors: SoundmodemErrorSender,
+    keying_tone: Option<KeyingTone>,
+}
+
 fn spawn_soundcard_worker(
     event_rx: Receiver<SoundcardEvent>,
     setup_tx: SyncSender<Result<(), SoundcardError>>,
     card_name: String,
+    input_levels: Arc<Mutex<[SoundcardInputLevel; 3]>>,
 ) {
     std::thread::spawn(move || {
         let host = cpal::default_host();
@@ -190,16 +353,35 @@ fn spawn_soundcard_worker(
         };
 
         let _ = setup_tx.send(Ok(()));
-        let mut rx_inverted = false;
-        let mut tx_inverted = false;
+        let rx_inverted = Arc::new(AtomicBool::new(false));
+        let tx_inverted = Arc::new(AtomicBool::new(false));
+        let inputs: Arc<Mutex<Vec<InputConsumer>>> = Arc::new(Mutex::new(vec![]));
+        let outputs: Arc<Mutex<Vec<OutputProducer>>> = Arc::new(Mutex::new(vec![]));
         let mut input_stream: Option<Stream> = None;
         let mut output_stream: Option<Stream> = None;
 
         while let Ok(ev) = event_rx.recv() {
             match ev {
-                SoundcardEvent::SetRxInverted(inv) => rx_inverted = inv,
-                SoundcardEvent::SetTxInverted(inv) => tx_inverted = inv,
-                SoundcardEvent::StartInput { samples, errors } => {
+                SoundcardEvent::SetRxInverted(inv) => rx_inverted.store(inv, Ordering::Relaxed),
+                SoundcardEvent::SetTxInverted(inv) => tx_inverted.store(inv, Ordering::Relaxed),
+                SoundcardEvent::StartInput {
+                    id,
+                    channel,
+                    gain,
+                    samples,
+                    errors,
+                } => {
+                    inputs.lock().unwrap().push(InputConsumer {
+                        id,
+                        channel,
+                        gain,
+                        samples,
+                        errors: errors.clone(),
+                    });
+                    if input_stream.is_some() {
+                        // Already running for another input on this card
+                        continue;
+                    }
                     let mut input_configs = match device.supported_input_configs() {
                         Ok(c) => c,
                         Err(e) => {
@@ -214,25 +396,62 @@ fn spawn_soundcard_worker(
                             continue;
                         }
                     };
-                    let input_config = input_config.with_sample_rate(SampleRate(48000));
+                    let input_config =
+                        input_config.with_sample_rate(SampleRate(SAMPLE_RATE as u32));
                     let channels = input_config.channels();
-                    let errors_1 = errors.clone();
+                    let rx_inverted = rx_inverted.clone();
+                    let inputs_1 = inputs.clone();
+                    let inputs_2 = inputs.clone();
+                    let input_levels = input_levels.clone();
+                    // 100 ms blocks
+                    let mut meters = [
+                        LevelMeter::new(100 * SAMPLES_PER_MS),
+                        LevelMeter::new(100 * SAMPLES_PER_MS),
+                        LevelMeter::new(100 * SAMPLES_PER_MS),
+                    ];
                     let stream = match device.build_input_stream(
                         &input_config.into(),
                         move |data: &[i16], _info: &cpal::InputCallbackInfo| {
-                            let mut out = vec![];
-                            for d in data.chunks(channels as usize) {
-                                // if we were given multi-channel input we'll pick the first channel
-                                let mut sample = d[0];
-                                if rx_inverted {
-                                    sample = sample.saturating_neg();
+                            let mut completed = false;
+                            for frame in data.chunks(channels as usize) {
+                                for (meter, channel) in meters.iter_mut().zip(ALL_CHANNELS) {
+                                    completed |= meter.update(input_sample(frame, channel));
                                 }
-                                out.push(sample);
                             }
-                            let _ = samples.try_send(SoundmodemEvent::BasebandInput(out.into()));
+                            if completed {
+                                let mut levels = input_levels.lock().unwrap();
+                                for (level, meter) in levels.iter_mut().zip(&meters) {
+                                    *level = SoundcardInputLevel {
+                                        peak: meter.peak,
+                                        rms_dbfs: meter.rms_dbfs,
+                                        clipped: meter.clipped,
+                                    };
+                                }
+                            }
+                            let inverted = rx_inverted.load(Ordering::Relaxed);
+                            for input in inputs_1.lock().unwrap().iter() {
+                                let out: Arc<[i16]> = data
+                                    .chunks(channels as usize)
+                                    .map(|frame| {
+                                        let sample = input_sample(frame, input.channel);
+                                        let sample = apply_gain(sample, input.gain);
+                                        if inverted {
+                                            sample.saturating_neg()
+                                        } else {
+                                            sample
+                                        }
+                                    })
+                                    .collect();
+                                let _ = input.samples.try_send(SoundmodemEvent::BasebandInput(out));
+                            }
                         },
                         move |e| {
-                            errors_1.send_error(SoundcardError::Stream(e));
+                            // Every input on this card is affected
+                            for input in inputs_2.lock().unwrap().iter() {
+                                input
+                                    .errors
+                                    .send_error(SoundcardError::Stream(copy_stream_error(&e)));
+                            }
                         },
                         None,
                     ) {
@@ -248,14 +467,35 @@ fn spawn_soundcard_worker(
                     }
                     input_stream = Some(stream);
                 }
-                SoundcardEvent::CloseInput => {
-                    let _ = input_stream.take();
+                SoundcardEvent::CloseInput { id } => {
+                    let mut inputs = inputs.lock().unwrap();
+                    inputs.retain(|i| i.id != id);
+                    if inputs.is_empty() {
+                        let _ = input_stream.take();
+                    }
                 }
                 SoundcardEvent::StartOutput {
+                    id,
+                    channel,
+                    gain,
                     event_tx,
                     buffer,
                     errors,
+                    keying_tone,
                 } => {
+                    outputs.lock().unwrap().push(OutputProducer {
+                        id,
+                        channel,
+                        gain,
+                        event_tx,
+                        buffer,
+                        errors: errors.clone(),
+                        keying_tone: keying_tone.map(KeyingTone::new),
+                    });
+                    if output_stream.is_some() {
+                        // Already running for another output on this card
+                        continue;
+                    }
                     let mut output_configs = match device.supported_output_configs() {
                         Ok(c) => c,
                         Err(e) => {
@@ -270,38 +510,62 @@ fn spawn_soundcard_worker(
                             continue;
                         }
                     };
-                    let output_config = output_config.with_sample_rate(SampleRate(48000));
+                    let output_config =
+                        output_config.with_sample_rate(SampleRate(SAMPLE_RATE as u32));
                     let channels = output_config.channels();
-                    let errors_1 = errors.clone();
+                    let tx_inverted = tx_inverted.clone();
+                    let outputs_1 = outputs.clone();
+                    let outputs_2 = outputs.clone();
                     let stream = match device.build_output_stream(
                         &output_config.into(),
                         move |data: &mut [i16], info: &cpal::OutputCallbackInfo| {
-                            let mut taken = 0;
                             let ts = info.timestamp();
                             let latency = ts
                                 .playback
                                 .duration_since(&ts.callback)
                                 .unwrap_or(Duration::ZERO);
-                            let mut buffer = buffer.write().unwrap();
-                            buffer.latency = latency;
-                            for out in data.chunks_mut(channels as usize) {
-                                if let Some(s) = buffer.samples.pop_front() {
-                                    out.fill(if tx_inverted { s.saturating_neg() } else { s });
-                                    taken += 1;
-                                } else if buffer.idling {
-                                    out.fill(0);
-                                } else {
-                                    let _ = event_tx.send(SoundmodemEvent::OutputUnderrun);
-                                    break;
+                            let inverted = tx_inverted.load(Ordering::Relaxed);
+                            // Outputs on different channels are mixed together
+                            data.fill(0);
+                            for output in outputs_1.lock().unwrap().iter_mut() {
+                                let mut taken = 0;
+                                let mut buffer = output.buffer.write().unwrap();
+                                buffer.latency = latency;
+                                for frame in data.chunks_mut(channels as usize) {
+                                    let sample = if let Some(s) = buffer.samples.pop_front() {
+                                        taken += 1;
+                                        let s = apply_gain(s, output.gain);
+                                        if inverted { s.saturating_neg() } else { s }
+                                    } else if buffer.idling {
+                                        0
+                                    } else {
+                                        let _ =
+                                            output.event_tx.send(SoundmodemEvent::OutputUnderrun);
+                                        break;
+                                    };
+                                    let tone = match output.keying_tone.as_mut() {
+                                        Some(tone) if frame.len() >= 2 => {
+                                            Some(tone.next_sample(buffer.ptt))
+                                        }
+                                        _ => None,
+                                    };
+                                    write_output_frame(frame, sample, output.channel, tone);
                                 }
+                                let _ = output.event_tx.send(
+                                    SoundmodemEvent::DidReadFromOutputBuffer {
+                                        len: taken,
+                                        timestamp: Instant::now(),
+                                    },
+                                );
                             }
-                            let _ = event_tx.send(SoundmodemEvent::DidReadFromOutputBuffer {
-                                len: taken,
-                                timestamp: Instant::now(),
-                            });
                         },
                         move |e| {
-                            errors_1.send_error(SoundcardError::Stream(e));
+                            // Every output on this card is affected
+                            for output in outputs_2.lock().unwrap().iter() {
+                                output
+                                    .errors
+                                    .send_error(SoundcardError::Stream(copy_stream_error(&e)));
+                            }
                         },
                         None,
                     ) {
@@ -317,14 +581,113 @@ fn spawn_soundcard_worker(
                     }
                     output_stream = Some(stream);
                 }
-                SoundcardEvent::CloseOutput => {
-                    let _ = output_stream.take();
+                SoundcardEvent::CloseOutput { id } => {
+                    let mut outputs = outputs.lock().unwrap();
+                    outputs.retain(|o| o.id != id);
+                    if outputs.is_empty() {
+                        let _ = output_stream.take();
+                    }
                 }
             }
         }
     });
 }
 
+/// Order of the meters kept for each input channel.
+const ALL_CHANNELS: [SoundcardChannel; 3] = [
+    SoundcardChannel::Left,
+    SoundcardChannel::Right,
+    SoundcardChannel::Mono,
+];
+
+/// Pick out the baseband sample from one frame of sound card input.
+fn input_sample(frame: &[i16], channel: SoundcardChannel) -> i16 {
+    match (channel, frame) {
+        (SoundcardChannel::Right, [_, right, ..]) => *right,
+        (SoundcardChannel::Mono, [left, right, ..]) => ((*left as i32 + *right as i32) / 2) as i16,
+        _ => frame[0],
+    }
+}
+
+/// Add a baseband sample and optional keying tone to one frame of sound card output.
+fn write_output_frame(
+    frame: &mut [i16],
+    sample: i16,
+    channel: SoundcardChannel,
+    tone: Option<i16>,
+) {
+    if let [only] = frame {
+        *only = only.saturating_add(sample);
+        return;
+    }
+    // The tone needs a channel to itself
+    let tone_idx = if channel == SoundcardChannel::Right {
+        0
+    } else {
+        1
+    };
+    for (idx, out) in frame.iter_mut().enumerate() {
+        let baseband = match channel {
+            SoundcardChannel::Left => idx == 0,
+            SoundcardChannel::Right => idx == 1,
+            SoundcardChannel::Mono => tone.is_none() || idx != tone_idx,
+        };
+        if baseband {
+            *out = out.saturating_add(sample);
+        } else if let Some(tone) = tone.filter(|_| idx == tone_idx) {
+            *out = out.saturating_add(tone);
+        }
+    }
+}
+
+/// `StreamError` is not `Clone` but every input or output on the card needs to hear about it.
+fn copy_stream_error(e: &StreamError) -> StreamError {
+    match e {
+        StreamError::DeviceNotAvailable => StreamError::DeviceNotAvailable,
+        StreamError::BackendSpecific { err } => StreamError::BackendSpecific { err: err.clone() },
+    }
+}
+
+fn apply_gain(sample: i16, gain: f32) -> i16 {
+    if gain == 1.0 {
+        return sample;
+    }
+    (sample as f32 * gain)
+        .round()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Convert a gain in dB to the factor it multiplies sample values by.
+fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Sine wave played on the second channel to key the radio.
+struct KeyingTone {
+    phase: f32,
+    step: f32,
+}
+
+impl KeyingTone {
+    fn new(frequency_hz: f32) -> Self {
+        Self {
+            phase: 0.0,
+            step: std::f32::consts::TAU * frequency_hz / SAMPLE_RATE as f32,
+        }
+    }
+
+    fn next_sample(&mut self, ptt: bool) -> i16 {
+        if !ptt {
+            // Start each transmission cleanly from zero
+            self.phase = 0.0;
+            return 0;
+        }
+        let sample = self.phase.sin() * 16383.0;
+        self.phase = (self.phase + self.step) % std::f32::consts::TAU;
+        sample as i16
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum SoundcardError {
     #[error("sound card init aborted unexpectedly")]
@@ -351,3 +714,54 @@ pub enum SoundcardError {
     #[error("unable to play stream")]
     StreamPlay(#[source] PlayStreamError),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_channel_selection() {
+        let frame = [1000, -3000];
+        assert_eq!(input_sample(&frame, SoundcardChannel::Left), 1000);
+        assert_eq!(input_sample(&frame, SoundcardChannel::Right), -3000);
+        assert_eq!(input_sample(&frame, SoundcardChannel::Mono), -1000);
+        // A mono card has only one choice
+        assert_eq!(input_sample(&[42], SoundcardChannel::Right), 42);
+    }
+
+    #[test]
+    fn output_channel_selection() {
+        let cases = [
+            (SoundcardChannel::Left, None, [500, 0]),
+            (SoundcardChannel::Right, None, [0, 500]),
+            (SoundcardChannel::Mono, None, [500, 500]),
+            (SoundcardChannel::Left, Some(-7), [500, -7]),
+            (SoundcardChannel::Right, Some(-7), [-7, 500]),
+            (SoundcardChannel::Mono, Some(-7), [500, -7]),
+        ];
+        for (channel, tone, expected) in cases {
+            let mut frame = [0i16; 2];
+            write_output_frame(&mut frame, 500, channel, tone);
+            assert_eq!(frame, expected, "{channel:?} {tone:?}");
+        }
+
+        // Two radios sharing the card
+        let mut frame = [0i16; 2];
+        write_output_frame(&mut frame, 100, SoundcardChannel::Left, None);
+        write_output_frame(&mut frame, 200, SoundcardChannel::Right, None);
+        assert_eq!(frame, [100, 200]);
+
+        let mut frame = [0i16; 1];
+        write_output_frame(&mut frame, 500, SoundcardChannel::Right, None);
+        assert_eq!(frame, [500]);
+    }
+
+    #[test]
+    fn gain() {
+        assert_eq!(apply_gain(1000, db_to_amplitude(0.0)), 1000);
+        assert_eq!(apply_gain(1000, db_to_amplitude(6.0206)), 2000);
+        assert_eq!(apply_gain(-1000, db_to_amplitude(-6.0206)), -500);
+        assert_eq!(apply_gain(20000, db_to_amplitude(20.0)), i16::MAX);
+        assert_eq!(apply_gain(-20000, db_to_amplitude(20.0)), i16::MIN);
+    }
+}
@@ -1,23 +1,41 @@
 use crate::error::{M17Error, SoundmodemError};
 use crate::tnc::{Tnc, TncError};
+use crate::util::level_meter::LevelMeter;
 use crate::util::out_buffer::OutBuffer;
-use m17core::kiss::MAX_FRAME_LEN;
-use m17core::modem::{Demodulator, Modulator, ModulatorAction, SoftDemodulator, SoftModulator};
-use m17core::tnc::SoftTnc;
+use m17core::kiss::{MAX_FRAME_LEN, QueueStatus};
+use m17core::modem::{
+    Demodulator, DemodulatorStats, Modulator, ModulatorAction, RxPolarity, SAMPLE_RATE,
+    SAMPLES_PER_MS, SoftDemodulator, SoftModulator,
+};
+use m17core::tnc::{DEFAULT_STREAM_QUEUE_LEN, RxStreamStats, SoftTnc};
 use std::collections::VecDeque;
 use std::fmt::Display;
 use std::fs::File;
+use std::hash::{BuildHasher, RandomState};
 use std::io::{self, Read, Write};
 use std::path::PathBuf;
 use std::sync::RwLock;
-use std::sync::mpsc::{Receiver, Sender, SyncSender, TryRecvError, channel, sync_channel};
+use std::sync::mpsc::{
+    Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError, channel, sync_channel,
+};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use thiserror::Error;
 
+/// Number of packets the soundmodem's TNC can queue for transmission, which is deeper than the
+/// `m17core` default since memory is plentiful here and hosts may send in bulk.
+const PACKET_QUEUE_LEN: usize = 32;
+
+type HostTnc = SoftTnc<PACKET_QUEUE_LEN, DEFAULT_STREAM_QUEUE_LEN>;
+
+/// Duration of one sample, rounded down, for converting between wall clock time and samples.
+const NANOS_PER_SAMPLE: u64 = 1_000_000_000 / SAMPLE_RATE as u64;
+
 pub struct Soundmodem {
     event_tx: SyncSender<SoundmodemEvent>,
+    kiss_in_tx: Sender<Arc<[u8]>>,
     kiss_out: OutBuffer,
+    stats: Arc<Mutex<SoundmodemStats>>,
 }
 
 impl Soundmodem {
@@ -26,13 +44,48 @@ impl Soundmodem {
         output: O,
         ptt: P,
         error: E,
+    ) -> Self {
+        Self::new_with_clock(input, output, ptt, error, false)
+    }
+
+    /// Create a soundmodem whose clock advances with the input samples rather than in real time.
+    ///
+    /// Input sources supply samples as fast as they are able via `InputSource::start_virtual` and
+    /// output is handed over synchronously via `OutputSink::write_virtual`, one input block at a
+    /// time. TNC and PTT timing is exactly as it would be in real time, but a recording can be
+    /// processed as quickly as the CPU allows.
+    ///
+    /// Received frames are held until the host reads them rather than being dropped, so make
+    /// sure something is reading from the soundmodem.
+    pub fn new_virtual_time<I: InputSource, O: OutputSink, P: Ptt, E: ErrorHandler>(
+        input: I,
+        output: O,
+        ptt: P,
+        error: E,
+    ) -> Self {
+        Self::new_with_clock(input, output, ptt, error, true)
+    }
+
+    fn new_with_clock<I: InputSource, O: OutputSink, P: Ptt, E: ErrorHandler>(
+        input: I,
+        output: O,
+        ptt: P,
+        error: E,
+        virtual_time: bool,
     ) -> Self {
         let (event_tx, event_rx) = sync_channel(128);
+        // Unbounded so that writing never waits for the worker, which in virtual time may itself
+        // be waiting for the host to read
+        let (kiss_in_tx, kiss_in_rx) = channel();
         let (kiss_out_tx, kiss_out_rx) = sync_channel(128);
+        let stats = Arc::new(Mutex::new(SoundmodemStats::default()));
         spawn_soundmodem_worker(
             event_tx.clone(),
             event_rx,
+            kiss_in_rx,
             kiss_out_tx,
+            stats.clone(),
+            virtual_time,
             Box::new(input),
             Box::new(output),
             Box::new(ptt),
@@ -40,9 +93,101 @@ impl Soundmodem {
         );
         Self {
             event_tx,
+            kiss_in_tx,
             kiss_out: OutBuffer::new(kiss_out_rx),
+            stats,
         }
     }
+
+    /// Latest statistics from the running soundmodem.
+    ///
+    /// If this soundmodem will be given to an `M17App`, keep a handle from `try_clone()` so that
+    /// stats remain accessible.
+    pub fn stats(&self) -> SoundmodemStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Receive a copy of the statistics at regular intervals while the soundmodem is running.
+    ///
+    /// Replaces any previously-set handler.
+    pub fn set_stats_handler<S: StatsHandler>(&self, interval: Duration, handler: S) {
+        let _ = self.event_tx.send(SoundmodemEvent::SetStatsHandler(
+            interval,
+            Box::new(handler),
+        ));
+    }
+
+    /// Choose which polarities of received signal to decode. The default is `RxPolarity::Auto`,
+    /// in which case `SoundmodemStats` reports the polarity currently detected.
+    ///
+    /// This applies after any inversion performed by the input source.
+    pub fn set_rx_polarity(&self, polarity: RxPolarity) {
+        let _ = self.event_tx.send(SoundmodemEvent::SetRxPolarity(polarity));
+    }
+
+    /// Filter and search only every `decimation`th input sample, to reduce CPU usage on slow
+    /// hosts. The default is 1.
+    ///
+    /// This costs some sensitivity: see `SoftDemodulator::new_decimating()`. A frame being
+    /// received at the time of the change is lost.
+    ///
+    /// # Panics
+    ///
+    /// `decimation` must be 1, 2 or 5.
+    pub fn set_decimation(&self, decimation: usize) {
+        assert!(
+            matches!(decimation, 1 | 2 | 5),
+            "unsupported decimation {decimation}"
+        );
+        let _ = self
+            .event_tx
+            .send(SoundmodemEvent::SetDecimation(decimation));
+    }
+
+    /// Send extra preamble at the start of each transmission so that a radio keyed by VOX, or by
+    /// a keying tone from the sound card, is already transmitting when the real preamble begins.
+    ///
+    /// This is rounded up to a multiple of 40 ms. Default zero.
+    pub fn set_vox_lead_in(&self, lead_in: Duration) {
+        let _ = self.event_tx.send(SoundmodemEvent::SetVoxLeadIn(lead_in));
+    }
+}
+
+/// Snapshot of a soundmodem's activity since it was started, for monitoring unattended nodes.
+#[derive(Debug, Clone, Default)]
+pub struct SoundmodemStats {
+    /// Samples processed, sync bursts found per type, frames decoded or failed and the detected
+    /// receive polarity.
+    pub demodulator: DemodulatorStats,
+    /// Proportion of input samples, from 0.0 to 1.0, during which data carrier was detected.
+    pub dcd_duty: f32,
+    /// Largest absolute input sample value over the most recent second of input.
+    pub input_peak: u16,
+    /// RMS input level in dBFS over the most recent second of input.
+    pub input_rms_dbfs: f32,
+    /// Number of times the output ran out of samples partway through a transmission.
+    pub output_underruns: u32,
+    /// Modulated samples waiting to be picked up by the output.
+    pub output_buffer_samples: usize,
+    /// Total time PTT has been asserted.
+    pub ptt_on_time: Duration,
+    /// Occupancy of the TNC's transmit queues.
+    pub tnc_queues: QueueStatus,
+    /// Sequencing statistics for received streams.
+    pub rx_streams: RxStreamStats,
+}
+
+pub trait StatsHandler: Send + Sync + 'static {
+    fn soundmodem_stats(&mut self, stats: &SoundmodemStats);
+}
+
+impl<F> StatsHandler for F
+where
+    F: FnMut(&SoundmodemStats) + Send + Sync + 'static,
+{
+    fn soundmodem_stats(&mut self, stats: &SoundmodemStats) {
+        self(stats)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -161,7 +306,9 @@ impl Read for Soundmodem {
 
 impl Write for Soundmodem {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let _ = self.event_tx.try_send(SoundmodemEvent::Kiss(buf.into()));
+        let _ = self.kiss_in_tx.send(buf.into());
+        // If the queue is full the worker is busy and will pick up the KISS anyway
+        let _ = self.event_tx.try_send(SoundmodemEvent::Kiss);
         Ok(buf.len())
     }
 
@@ -174,7 +321,9 @@ impl Tnc for Soundmodem {
     fn try_clone(&mut self) -> Result<Self, TncError> {
         Ok(Self {
             event_tx: self.event_tx.clone(),
+            kiss_in_tx: self.kiss_in_tx.clone(),
             kiss_out: self.kiss_out.clone(),
+            stats: self.stats.clone(),
         })
     }
 
@@ -188,19 +337,33 @@ impl Tnc for Soundmodem {
 }
 
 pub enum SoundmodemEvent {
-    Kiss(Arc<[u8]>),
+    /// The host has written KISS data, which is waiting in a separate channel.
+    Kiss,
     BasebandInput(Arc<[i16]>),
     Start,
     Close,
-    DidReadFromOutputBuffer { len: usize, timestamp: Instant },
+    DidReadFromOutputBuffer {
+        len: usize,
+        timestamp: Instant,
+    },
     OutputUnderrun,
     RuntimeError(ErrorSource, SoundmodemError),
+    SetStatsHandler(Duration, Box<dyn StatsHandler>),
+    SetRxPolarity(RxPolarity),
+    SetDecimation(usize),
+    SetVoxLeadIn(Duration),
+    /// Generated by the worker itself when it is time to release PTT.
+    Wake,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_soundmodem_worker(
     event_tx: SyncSender<SoundmodemEvent>,
     event_rx: Receiver<SoundmodemEvent>,
+    kiss_in_rx: Receiver<Arc<[u8]>>,
     kiss_out_tx: SyncSender<Arc<[u8]>>,
+    stats: Arc<Mutex<SoundmodemStats>>,
+    virtual_time: bool,
     input: Box<dyn InputSource>,
     output: Box<dyn OutputSink>,
     mut ptt_driver: Box<dyn Ptt>,
@@ -210,55 +373,115 @@ fn spawn_soundmodem_worker(
         // TODO: should be able to provide a custom Demodulator for a soundmodem
         let mut demodulator = SoftDemodulator::new();
         let mut modulator = SoftModulator::new();
-        let mut tnc = SoftTnc::new();
+        let mut tnc: HostTnc = SoftTnc::new_with_queues();
+        tnc.set_csma_seed(csma_seed());
         let mut buf = [0u8; MAX_FRAME_LEN];
         let out_buffer = Arc::new(RwLock::new(OutputBuffer::new()));
         let mut out_samples = [0i16; 1024];
         let start = Instant::now();
-        let mut ptt = false;
-        while let Ok(ev) = event_rx.recv() {
+        // In virtual time, number of input samples handled so far
+        let mut virtual_now = 0u64;
+        let mut ptt = PttState::new();
+        let mut output_underruns = 0;
+        // One-second blocks
+        let mut input_level = LevelMeter::new(SAMPLE_RATE);
+        let mut stats_handler: Option<(Duration, Instant, Box<dyn StatsHandler>)> = None;
+        loop {
+            // Release PTT on time rather than waiting for the next sound card event
+            let wake_at = match tnc.ptt_off_time() {
+                Some(at) if !virtual_time => Some(
+                    start
+                        + Duration::from_secs(at / SAMPLE_RATE as u64)
+                        + Duration::from_nanos((at % SAMPLE_RATE as u64) * NANOS_PER_SAMPLE),
+                ),
+                _ => None,
+            };
+            let ev = match wake_at {
+                Some(wake_at) => {
+                    match event_rx.recv_timeout(wake_at.saturating_duration_since(Instant::now())) {
+                        Ok(ev) => ev,
+                        Err(RecvTimeoutError::Timeout) => SoundmodemEvent::Wake,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match event_rx.recv() {
+                    Ok(ev) => ev,
+                    Err(_) => break,
+                },
+            };
+
             // Update clock on TNC before we do anything
-            let sample_time = start.elapsed();
-            let secs = sample_time.as_secs();
-            let nanos = sample_time.subsec_nanos();
-            // Accurate to within approx 1 sample
-            let now_samples = 48000 * secs + (nanos as u64 / 20833);
+            let now_samples = if virtual_time {
+                virtual_now
+            } else {
+                let sample_time = start.elapsed();
+                let secs = sample_time.as_secs();
+                let nanos = sample_time.subsec_nanos();
+                // Accurate to within approx 1 sample
+                SAMPLE_RATE as u64 * secs + (nanos as u64 / NANOS_PER_SAMPLE)
+            };
             tnc.set_now(now_samples);
 
+            // Take whatever the host has written, whichever event woke us
+            for k in kiss_in_rx.try_iter() {
+                let _n = tnc.write_kiss(&k);
+                // TODO: what does it mean if we fail to write it all?
+                // Probably we have to read frames for tx first - revisit this during tx
+                forward_kiss(&mut tnc, &mut buf, &kiss_out_tx, virtual_time);
+            }
+
             // Handle event
             match ev {
-                SoundmodemEvent::Kiss(k) => {
-                    let _n = tnc.write_kiss(&k);
-                    // TODO: what does it mean if we fail to write it all?
-                    // Probably we have to read frames for tx first - revisit this during tx
+                SoundmodemEvent::Kiss => {
+                    // Already handled above
                 }
                 SoundmodemEvent::BasebandInput(b) => {
-                    for sample in &*b {
-                        if let Some((frame, _)) = demodulator.demod(*sample) {
-                            tnc.handle_frame(frame);
-                            loop {
-                                let n = tnc.read_kiss(&mut buf);
-                                if n > 0 {
-                                    let _ = kiss_out_tx.try_send(buf[0..n].into());
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
+                    for s in b.iter() {
+                        input_level.update(*s);
                     }
+                    let block_start = demodulator.sample_count();
+                    demodulator.demod_block(&b, &mut |idx, frame, quality| {
+                        let rx_sample = block_start + idx as u64 + 1;
+                        tnc.handle_frame_with_quality(frame, rx_sample, quality);
+                        forward_kiss(&mut tnc, &mut buf, &kiss_out_tx, virtual_time);
+                    });
                     tnc.set_data_carrier_detect(demodulator.data_carrier_detect());
+                    if virtual_time {
+                        // Play out the same amount of time as a sound card would have
+                        let mut out_buffer = out_buffer.write().unwrap();
+                        let len = b.len().min(out_buffer.samples.len());
+                        if len < b.len() && !out_buffer.idling {
+                            output_underruns += 1;
+                        }
+                        let played: Vec<i16> = out_buffer.samples.drain(0..len).collect();
+                        if !played.is_empty()
+                            && let Err(e) = output.write_virtual(&played)
+                        {
+                            error_handler.soundmodem_error(ErrorSource::Output, e);
+                        }
+                        modulator.update_output_buffer(out_buffer.samples.len(), SAMPLE_RATE, 0);
+                        virtual_now += b.len() as u64;
+                    }
                 }
                 SoundmodemEvent::Start => {
                     let input_errors = SoundmodemErrorSender {
                         source: ErrorSource::Input,
                         event_tx: event_tx.clone(),
                     };
-                    input.start(event_tx.clone(), input_errors);
-                    let output_errors = SoundmodemErrorSender {
-                        source: ErrorSource::Output,
-                        event_tx: event_tx.clone(),
-                    };
-                    output.start(event_tx.clone(), out_buffer.clone(), output_errors);
+                    if virtual_time {
+                        // Errors are reported directly since we are on the worker thread
+                        if let Err(e) = output.start_virtual() {
+                            error_handler.soundmodem_error(ErrorSource::Output, e);
+                        }
+                        input.start_virtual(event_tx.clone(), input_errors);
+                    } else {
+                        let output_errors = SoundmodemErrorSender {
+                            source: ErrorSource::Output,
+                            event_tx: event_tx.clone(),
+                        };
+                        input.start(event_tx.clone(), input_errors);
+                        output.start(event_tx.clone(), out_buffer.clone(), output_errors);
+                    }
                 }
                 SoundmodemEvent::Close => {
                     input.close();
@@ -273,38 +496,45 @@ fn spawn_soundmodem_worker(
                         let out_buffer = out_buffer.read().unwrap();
                         (out_buffer.samples.len(), out_buffer.latency)
                     };
-                    let internal_latency = (internal_latency.as_secs_f32() * 48000.0) as usize;
-                    let dynamic_latency =
-                        len.saturating_sub((timestamp.elapsed().as_secs_f32() * 48000.0) as usize);
+                    let internal_latency =
+                        (internal_latency.as_secs_f32() * SAMPLE_RATE as f32) as usize;
+                    let dynamic_latency = len.saturating_sub(
+                        (timestamp.elapsed().as_secs_f32() * SAMPLE_RATE as f32) as usize,
+                    );
                     modulator.update_output_buffer(
                         occupied,
-                        48000,
+                        SAMPLE_RATE,
                         internal_latency + dynamic_latency,
                     );
                 }
                 SoundmodemEvent::OutputUnderrun => {
+                    // The TNC pads streams and ends them gracefully when the host falls behind,
+                    // so this means we were too slow keeping the output buffer topped up.
                     log::debug!("output underrun");
-                    // TODO: cancel transmission, send empty data frame to host
+                    output_underruns += 1;
                 }
                 SoundmodemEvent::RuntimeError(source, err) => {
                     error_handler.soundmodem_error(source, err);
                 }
-            }
-
-            // Update PTT state
-            let new_ptt = tnc.ptt();
-            if new_ptt != ptt {
-                if new_ptt {
-                    if let Err(e) = ptt_driver.ptt_on() {
-                        error_handler.soundmodem_error(ErrorSource::Ptt, e);
-                    }
-                } else if let Err(e) = ptt_driver.ptt_off() {
-                    error_handler.soundmodem_error(ErrorSource::Ptt, e);
+                SoundmodemEvent::SetStatsHandler(interval, handler) => {
+                    stats_handler = Some((interval, Instant::now() + interval, handler));
+                }
+                SoundmodemEvent::SetRxPolarity(polarity) => {
+                    demodulator.set_polarity(polarity);
+                }
+                SoundmodemEvent::SetDecimation(decimation) => {
+                    demodulator.set_decimation(decimation);
+                }
+                SoundmodemEvent::SetVoxLeadIn(lead_in) => {
+                    tnc.set_vox_lead_in(lead_in.as_millis().min(u16::MAX as u128) as u16);
+                }
+                SoundmodemEvent::Wake => {
+                    // Only needed to advance the clock
                 }
             }
-            ptt = new_ptt;
 
             // Let the modulator do what it wants
+            modulator.set_output_level(tnc.tx_level());
             while let Some(action) = modulator.run() {
                 match action {
                     ModulatorAction::SetIdle(idling) => {
@@ -313,28 +543,166 @@ fn spawn_soundmodem_worker(
                     ModulatorAction::GetNextFrame => {
                         modulator.provide_next_frame(tnc.read_tx_frame());
                     }
-                    ModulatorAction::ReadOutput => loop {
-                        let n = modulator.read_output_samples(&mut out_samples);
-                        if n == 0 {
-                            break;
-                        }
-                        let mut out_buffer = out_buffer.write().unwrap();
-                        for s in &out_samples[0..n] {
-                            out_buffer.samples.push_back(*s);
+                    ModulatorAction::ReadOutput => {
+                        // Key up before the sound card can pick up any of the new samples
+                        ptt.update(
+                            tnc.ptt(),
+                            now_samples,
+                            &out_buffer,
+                            ptt_driver.as_mut(),
+                            error_handler.as_mut(),
+                        );
+                        loop {
+                            let n = modulator.read_output_samples(&mut out_samples);
+                            if n == 0 {
+                                break;
+                            }
+                            let mut out_buffer = out_buffer.write().unwrap();
+                            for s in &out_samples[0..n] {
+                                out_buffer.samples.push_back(*s);
+                            }
                         }
-                    },
+                    }
                     ModulatorAction::TransmissionWillEnd(in_samples) => {
                         tnc.set_tx_end_time(in_samples);
                     }
                 }
             }
+
+            // Update PTT state
+            ptt.update(
+                tnc.ptt(),
+                now_samples,
+                &out_buffer,
+                ptt_driver.as_mut(),
+                error_handler.as_mut(),
+            );
+
+            // Command responses and notifications that arose while handling this event
+            forward_kiss(&mut tnc, &mut buf, &kiss_out_tx, virtual_time);
+
+            let demodulator_stats = demodulator.stats();
+            let latest = SoundmodemStats {
+                demodulator: demodulator_stats,
+                dcd_duty: if demodulator_stats.samples > 0 {
+                    demodulator_stats.dcd_samples as f32 / demodulator_stats.samples as f32
+                } else {
+                    0.0
+                },
+                input_peak: input_level.peak,
+                input_rms_dbfs: input_level.rms_dbfs,
+                output_underruns,
+                output_buffer_samples: out_buffer.read().unwrap().samples.len(),
+                ptt_on_time: Duration::from_secs_f64(
+                    ptt.on_samples(now_samples) as f64 / SAMPLE_RATE as f64,
+                ),
+                tnc_queues: tnc.queue_status(),
+                rx_streams: tnc.rx_stream_stats(),
+            };
+            if let Some((interval, next_report, handler)) = stats_handler.as_mut()
+                && Instant::now() >= *next_report
+            {
+                handler.soundmodem_stats(&latest);
+                *next_report += *interval;
+            }
+            *stats.lock().unwrap() = latest;
         }
     });
 }
 
+/// PTT as last applied to the driver, and how long it has been asserted.
+struct PttState {
+    on: bool,
+    since: Option<u64>,
+    on_samples: u64,
+}
+
+impl PttState {
+    fn new() -> Self {
+        Self {
+            on: false,
+            since: None,
+            on_samples: 0,
+        }
+    }
+
+    /// Apply the PTT state the TNC wants, if it has changed.
+    fn update(
+        &mut self,
+        on: bool,
+        now_samples: u64,
+        out_buffer: &RwLock<OutputBuffer>,
+        driver: &mut dyn Ptt,
+        error_handler: &mut dyn ErrorHandler,
+    ) {
+        if on == self.on {
+            return;
+        }
+        self.on = on;
+        out_buffer.write().unwrap().ptt = on;
+        let result = if on {
+            self.since = Some(now_samples);
+            driver.ptt_on()
+        } else {
+            if let Some(since) = self.since.take() {
+                self.on_samples += now_samples.saturating_sub(since);
+            }
+            driver.ptt_off()
+        };
+        if let Err(e) = result {
+            error_handler.soundmodem_error(ErrorSource::Ptt, e);
+        }
+    }
+
+    /// Total samples for which PTT has been asserted, up to `now_samples`.
+    fn on_samples(&self, now_samples: u64) -> u64 {
+        self.on_samples
+            + self
+                .since
+                .map(|s| now_samples.saturating_sub(s))
+                .unwrap_or_default()
+    }
+}
+
+/// Pass any pending KISS frames from the TNC to the host.
+///
+/// If `wait` is set, block until the host has room rather than dropping frames.
+fn forward_kiss(
+    tnc: &mut HostTnc,
+    buf: &mut [u8],
+    kiss_out_tx: &SyncSender<Arc<[u8]>>,
+    wait: bool,
+) {
+    loop {
+        let n = tnc.read_kiss(buf);
+        if n > 0 {
+            if wait {
+                let _ = kiss_out_tx.send(buf[0..n].into());
+            } else {
+                let _ = kiss_out_tx.try_send(buf[0..n].into());
+            }
+        } else {
+            break;
+        }
+    }
+}
+
+/// Pick a fresh seed for CSMA so that multiple soundmodems don't make the same decisions.
+fn csma_seed() -> u32 {
+    RandomState::new().hash_one(Instant::now()) as u32
+}
+
 pub trait InputSource: Send + Sync + 'static {
     fn start(&self, samples: SyncSender<SoundmodemEvent>, errors: SoundmodemErrorSender);
     fn close(&self);
+
+    /// Start for a soundmodem running in virtual time.
+    ///
+    /// Sources that are not tied to real hardware should supply samples as fast as the soundmodem
+    /// will accept them, using blocking sends. By default this is the same as `start()`.
+    fn start_virtual(&self, samples: SyncSender<SoundmodemEvent>, errors: SoundmodemErrorSender) {
+        self.start(samples, errors)
+    }
 }
 
 pub struct InputRrcFile {
@@ -360,9 +728,8 @@ impl InputSource for InputRrcFile {
         let (end_tx, end_rx) = channel();
         let baseband = self.baseband.clone();
         std::thread::spawn(move || {
-            // assuming 48 kHz for now
             const TICK: Duration = Duration::from_millis(25);
-            const SAMPLES_PER_TICK: usize = 1200;
+            const SAMPLES_PER_TICK: usize = 25 * SAMPLES_PER_MS;
 
             let mut next_tick = Instant::now() + TICK;
             let mut buf = [0i16; SAMPLES_PER_TICK];
@@ -396,6 +763,27 @@ impl InputSource for InputRrcFile {
     fn close(&self) {
         let _ = self.end_tx.lock().unwrap().take();
     }
+
+    fn start_virtual(&self, samples: SyncSender<SoundmodemEvent>, _errors: SoundmodemErrorSender) {
+        let (end_tx, end_rx) = channel();
+        let baseband = self.baseband.clone();
+        std::thread::spawn(move || {
+            // Same block size as real time so the TNC sees the same granularity
+            for block in baseband.chunks(1200 * 2) {
+                if end_rx.try_recv() != Err(TryRecvError::Empty) {
+                    break;
+                }
+                let block: Arc<[i16]> = block
+                    .chunks(2)
+                    .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+                    .collect();
+                if samples.send(SoundmodemEvent::BasebandInput(block)).is_err() {
+                    break;
+                }
+            }
+        });
+        *self.end_tx.lock().unwrap() = Some(end_tx);
+    }
 }
 
 #[derive(Debug, Error)]
@@ -420,9 +808,8 @@ impl InputSource for NullInputSource {
     fn start(&self, samples: SyncSender<SoundmodemEvent>, errors: SoundmodemErrorSender) {
         let (end_tx, end_rx) = channel();
         std::thread::spawn(move || {
-            // assuming 48 kHz for now
             const TICK: Duration = Duration::from_millis(25);
-            const SAMPLES_PER_TICK: usize = 1200;
+            const SAMPLES_PER_TICK: usize = 25 * SAMPLES_PER_MS;
             let mut next_tick = Instant::now() + TICK;
 
             loop {
@@ -447,6 +834,22 @@ impl InputSource for NullInputSource {
     fn close(&self) {
         let _ = self.end_tx.lock().unwrap().take();
     }
+
+    fn start_virtual(&self, samples: SyncSender<SoundmodemEvent>, _errors: SoundmodemErrorSender) {
+        let (end_tx, end_rx) = channel();
+        std::thread::spawn(move || {
+            let silence: Arc<[i16]> = [0i16; 1200].into();
+            while end_rx.try_recv() == Err(TryRecvError::Empty) {
+                if samples
+                    .send(SoundmodemEvent::BasebandInput(silence.clone()))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        *self.end_tx.lock().unwrap() = Some(end_tx);
+    }
 }
 
 #[derive(Debug, Error)]
@@ -463,6 +866,8 @@ impl Default for NullInputSource {
 
 pub struct OutputBuffer {
     pub idling: bool,
+    /// Whether the soundmodem currently has PTT asserted, for sinks that key the radio themselves.
+    pub ptt: bool,
     // TODO: something more efficient
     pub samples: VecDeque<i16>,
     pub latency: Duration,
@@ -472,6 +877,7 @@ impl OutputBuffer {
     pub fn new() -> Self {
         Self {
             idling: true,
+            ptt: false,
             samples: VecDeque::new(),
             latency: Duration::ZERO,
         }
@@ -492,11 +898,29 @@ pub trait OutputSink: Send + Sync + 'static {
         errors: SoundmodemErrorSender,
     );
     fn close(&self);
+
+    /// Start for a soundmodem running in virtual time.
+    ///
+    /// Instead of reading from a buffer, the sink will be given samples via `write_virtual`. By
+    /// default nothing is done, i.e., output is discarded.
+    fn start_virtual(&self) -> Result<(), SoundmodemError> {
+        Ok(())
+    }
+
+    /// Accept the next modulated samples while running in virtual time.
+    ///
+    /// Idle periods between transmissions are not included.
+    fn write_virtual(&self, samples: &[i16]) -> Result<(), SoundmodemError> {
+        let _ = samples;
+        Ok(())
+    }
 }
 
 pub struct OutputRrcFile {
     path: PathBuf,
     end_tx: Mutex<Option<Sender<()>>>,
+    /// Open file when running in virtual time
+    virtual_file: Mutex<Option<File>>,
 }
 
 impl OutputRrcFile {
@@ -504,6 +928,7 @@ impl OutputRrcFile {
         Self {
             path,
             end_tx: Mutex::new(None),
+            virtual_file: Mutex::new(None),
         }
     }
 }
@@ -524,9 +949,8 @@ impl OutputSink for OutputRrcFile {
             }
         };
         std::thread::spawn(move || {
-            // assuming 48 kHz for now
             const TICK: Duration = Duration::from_millis(25);
-            const SAMPLES_PER_TICK: usize = 1200;
+            const SAMPLES_PER_TICK: usize = 25 * SAMPLES_PER_MS;
 
             // flattened BE i16s for writing
             let mut buf = [0u8; SAMPLES_PER_TICK * 2];
@@ -568,6 +992,26 @@ impl OutputSink for OutputRrcFile {
 
     fn close(&self) {
         let _ = self.end_tx.lock().unwrap().take();
+        let _ = self.virtual_file.lock().unwrap().take();
+    }
+
+    fn start_virtual(&self) -> Result<(), SoundmodemError> {
+        let file = File::create(self.path.clone()).map_err(OutputRrcError::Open)?;
+        *self.virtual_file.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    fn write_virtual(&self, samples: &[i16]) -> Result<(), SoundmodemError> {
+        let mut virtual_file = self.virtual_file.lock().unwrap();
+        let Some(file) = virtual_file.as_mut() else {
+            return Ok(());
+        };
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        if let Err(e) = file.write_all(&bytes) {
+            *virtual_file = None;
+            return Err(OutputRrcError::WriteError(e).into());
+        }
+        Ok(())
     }
 }
 
@@ -607,9 +1051,8 @@ impl OutputSink for NullOutputSink {
     ) {
         let (end_tx, end_rx) = channel();
         std::thread::spawn(move || {
-            // assuming 48 kHz for now
             const TICK: Duration = Duration::from_millis(25);
-            const SAMPLES_PER_TICK: usize = 1200;
+            const SAMPLES_PER_TICK: usize = 25 * SAMPLES_PER_MS;
             let mut next_tick = Instant::now() + TICK;
 
             loop {
@@ -674,3 +1117,271 @@ impl Ptt for NullPtt {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use m17core::kiss::{KissBuffer, KissCommand, KissFrame, PORT_PACKET_BASIC};
+
+    /// Delivers one second of a square wave at half of full scale, then nothing.
+    struct SquareWaveInput;
+
+    impl InputSource for SquareWaveInput {
+        fn start(&self, samples: SyncSender<SoundmodemEvent>, _errors: SoundmodemErrorSender) {
+            for _ in 0..40 {
+                let block: Vec<i16> = (0..1200)
+                    .map(|i| if i % 20 < 10 { 16384 } else { -16384 })
+                    .collect();
+                let _ = samples.send(SoundmodemEvent::BasebandInput(block.into()));
+            }
+        }
+
+        fn close(&self) {}
+    }
+
+    #[test]
+    fn stats_reflect_input() {
+        let mut soundmodem = Soundmodem::new(
+            SquareWaveInput,
+            NullOutputSink::new(),
+            NullPtt::new(),
+            NullErrorHandler::new(),
+        );
+        let (reports_tx, reports_rx) = channel();
+        let reports_tx = Mutex::new(reports_tx);
+        soundmodem.set_stats_handler(Duration::ZERO, move |stats: &SoundmodemStats| {
+            let _ = reports_tx.lock().unwrap().send(stats.demodulator.samples);
+        });
+        soundmodem.start();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut stats = soundmodem.stats();
+        while stats.demodulator.samples < 48000 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+            stats = soundmodem.stats();
+        }
+        soundmodem.close();
+
+        assert_eq!(stats.demodulator.samples, 48000);
+        assert_eq!(stats.demodulator.frames_decoded, 0);
+        assert_eq!(stats.input_peak, 16384);
+        assert!((stats.input_rms_dbfs - -6.02).abs() < 0.1);
+        assert_eq!(stats.ptt_on_time, Duration::ZERO);
+        assert!(reports_rx.try_iter().any(|samples| samples > 0));
+    }
+
+    #[derive(Clone, Default)]
+    struct CaptureOutput(Arc<Mutex<Vec<i16>>>);
+
+    impl OutputSink for CaptureOutput {
+        fn start(
+            &self,
+            _event_tx: SyncSender<SoundmodemEvent>,
+            _buffer: Arc<RwLock<OutputBuffer>>,
+            _errors: SoundmodemErrorSender,
+        ) {
+            panic!("should be running in virtual time");
+        }
+
+        fn close(&self) {}
+
+        fn write_virtual(&self, samples: &[i16]) -> Result<(), SoundmodemError> {
+            self.0.lock().unwrap().extend_from_slice(samples);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn virtual_time_transmission() {
+        let output = CaptureOutput::default();
+        let mut soundmodem = Soundmodem::new_virtual_time(
+            NullInputSource::new(),
+            output.clone(),
+            NullPtt::new(),
+            NullErrorHandler::new(),
+        );
+        soundmodem.start();
+        let packet = KissFrame::new_basic_packet(b"hello").unwrap();
+        soundmodem.write_all(packet.as_bytes()).unwrap();
+
+        // Two seconds of virtual time is ample for the transmission to finish
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut stats = soundmodem.stats();
+        while stats.demodulator.samples < 96000 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+            stats = soundmodem.stats();
+        }
+        soundmodem.close();
+        assert!(stats.demodulator.samples >= 96000);
+
+        // PTT was asserted for as long as the modulated audio, to within one input block
+        let mut samples = output.0.lock().unwrap().clone();
+        let audio_time = Duration::from_secs_f64(samples.len() as f64 / 48000.0);
+        assert!(samples.len() > 1920 * 3);
+        assert!(stats.ptt_on_time.abs_diff(audio_time) <= Duration::from_millis(25));
+        assert_eq!(stats.output_underruns, 0);
+
+        // Another soundmodem receives the packet from the recorded audio
+        samples.extend_from_slice(&[0i16; 1920]);
+        let mut receiver = Soundmodem::new_virtual_time(
+            RecordingInput(samples.into()),
+            NullOutputSink::new(),
+            NullPtt::new(),
+            NullErrorHandler::new(),
+        );
+        let frames = kiss_frames(&mut receiver);
+        receiver.start();
+        let received = next_basic_packet(&frames);
+        receiver.close();
+        assert!(received.unwrap().ends_with(b"hello"));
+    }
+
+    #[test]
+    fn host_can_write_everything_before_reading() {
+        let mut soundmodem = Soundmodem::new_virtual_time(
+            NullInputSource::new(),
+            NullOutputSink::new(),
+            NullPtt::new(),
+            NullErrorHandler::new(),
+        );
+        soundmodem.start();
+
+        // Each query gets a reply, which is more than the output queue holds
+        const QUERIES: usize = 200;
+        let mut writer = soundmodem.try_clone().unwrap();
+        let (done_tx, done_rx) = channel();
+        std::thread::spawn(move || {
+            let query = KissFrame::new_query_queue_status(PORT_PACKET_BASIC);
+            for _ in 0..QUERIES {
+                writer.write_all(query.as_bytes()).unwrap();
+            }
+            let _ = done_tx.send(());
+        });
+        let written = done_rx.recv_timeout(Duration::from_secs(10));
+
+        let frames = kiss_frames(&mut soundmodem);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut replies = 0;
+        while replies < QUERIES {
+            match frames.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(frame) if frame.command() == Ok(KissCommand::SetHardware) => replies += 1,
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+        soundmodem.close();
+        assert!(written.is_ok(), "writing blocked");
+        assert_eq!(replies, QUERIES);
+    }
+
+    /// Audio of a TNC transmitting `kiss`, followed by some silence.
+    fn modulate_kiss(kiss: &[u8]) -> Vec<i16> {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_PACKET_BASIC, true).as_bytes());
+        tnc.write_kiss(kiss);
+        let mut modulator = SoftModulator::new();
+        let mut samples = vec![];
+        let mut buf = [0i16; 1024];
+        while let Some(frame) = tnc.read_tx_frame() {
+            modulator.provide_next_frame(Some(frame));
+            loop {
+                let n = modulator.read_output_samples(&mut buf);
+                if n == 0 {
+                    break;
+                }
+                samples.extend_from_slice(&buf[0..n]);
+            }
+        }
+        samples.extend_from_slice(&[0i16; 1920]);
+        samples
+    }
+
+    /// Plays a recording in virtual time, then silence.
+    struct RecordingInput(Arc<[i16]>);
+
+    impl InputSource for RecordingInput {
+        fn start(&self, _samples: SyncSender<SoundmodemEvent>, _errors: SoundmodemErrorSender) {
+            panic!("should be running in virtual time");
+        }
+
+        fn close(&self) {}
+
+        fn start_virtual(
+            &self,
+            samples: SyncSender<SoundmodemEvent>,
+            _errors: SoundmodemErrorSender,
+        ) {
+            let recording = self.0.clone();
+            std::thread::spawn(move || {
+                let silence = [0i16; 1200];
+                for block in recording
+                    .chunks(1200)
+                    .chain(std::iter::repeat(&silence[..]))
+                {
+                    if samples
+                        .send(SoundmodemEvent::BasebandInput(block.into()))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// KISS frames the soundmodem passes to the host, read on another thread.
+    fn kiss_frames(soundmodem: &mut Soundmodem) -> Receiver<KissFrame> {
+        let mut reader = soundmodem.try_clone().unwrap();
+        let (frames_tx, frames_rx) = channel();
+        std::thread::spawn(move || {
+            let mut kiss = KissBuffer::new();
+            loop {
+                let Ok(n) = reader.read(kiss.buf_remaining()) else {
+                    return;
+                };
+                kiss.did_write(n);
+                while let Some(frame) = kiss.next_frame() {
+                    if frames_tx.send(frame.clone()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        frames_rx
+    }
+
+    /// Payload of the next data frame on the basic packet port.
+    fn next_basic_packet(frames: &Receiver<KissFrame>) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let frame = frames
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok()?;
+            if frame.port() == Ok(PORT_PACKET_BASIC)
+                && frame.command() == Ok(KissCommand::DataFrame)
+            {
+                let mut payload = [0u8; MAX_FRAME_LEN];
+                let n = frame.decode_payload(&mut payload).unwrap();
+                return Some(payload[0..n].to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn decimated_soundmodem_decodes() {
+        let packet = KissFrame::new_basic_packet(b"hello").unwrap();
+        let recording = modulate_kiss(packet.as_bytes());
+        let mut soundmodem = Soundmodem::new_virtual_time(
+            RecordingInput(recording.into()),
+            NullOutputSink::new(),
+            NullPtt::new(),
+            NullErrorHandler::new(),
+        );
+        soundmodem.set_decimation(5);
+        let frames = kiss_frames(&mut soundmodem);
+        soundmodem.start();
+        let received = next_basic_packet(&frames);
+        soundmodem.close();
+        assert!(received.unwrap().ends_with(b"hello"));
+    }
+}
@@ -0,0 +1,75 @@
+//! Signal level measured over consecutive blocks of samples
+
+/// Measures the peak and RMS level of a stream of samples over fixed-length blocks.
+///
+/// The public fields describe the most recently completed block.
+pub(crate) struct LevelMeter {
+    /// Largest absolute sample value
+    pub(crate) peak: u16,
+    /// RMS level in dBFS
+    pub(crate) rms_dbfs: f32,
+    /// At least one sample was at full scale
+    pub(crate) clipped: bool,
+    block_samples: usize,
+    block_peak: u16,
+    block_sum_squares: f64,
+    block_len: usize,
+    block_clipped: bool,
+}
+
+impl LevelMeter {
+    pub(crate) fn new(block_samples: usize) -> Self {
+        Self {
+            peak: 0,
+            rms_dbfs: f32::NEG_INFINITY,
+            clipped: false,
+            block_samples,
+            block_peak: 0,
+            block_sum_squares: 0.0,
+            block_len: 0,
+            block_clipped: false,
+        }
+    }
+
+    /// Add a sample, returning true if this completed a block.
+    pub(crate) fn update(&mut self, sample: i16) -> bool {
+        self.block_peak = self.block_peak.max(sample.unsigned_abs());
+        self.block_sum_squares += (sample as f64) * (sample as f64);
+        self.block_clipped |= sample == i16::MAX || sample <= -i16::MAX;
+        self.block_len += 1;
+        if self.block_len < self.block_samples {
+            return false;
+        }
+        let rms = (self.block_sum_squares / self.block_len as f64).sqrt();
+        self.peak = self.block_peak;
+        self.rms_dbfs = (20.0 * (rms / i16::MAX as f64).log10()) as f32;
+        self.clipped = self.block_clipped;
+        self.block_peak = 0;
+        self.block_sum_squares = 0.0;
+        self.block_len = 0;
+        self.block_clipped = false;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_meter() {
+        let mut meter = LevelMeter::new(4800);
+        for i in 0..4800 {
+            let completed = meter.update(if i % 2 == 0 { 16384 } else { -16384 });
+            assert_eq!(completed, i == 4799);
+        }
+        assert_eq!(meter.peak, 16384);
+        assert!((meter.rms_dbfs - -6.02).abs() < 0.1);
+        assert!(!meter.clipped);
+
+        for i in 0..4800 {
+            meter.update(if i == 10 { i16::MIN } else { 0 });
+        }
+        assert!(meter.clipped);
+    }
+}
@@ -1 +1,2 @@
+pub(crate) mod level_meter;
 pub mod out_buffer;
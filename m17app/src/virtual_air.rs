@@ -0,0 +1,327 @@
+//! Simulated radio channel connecting multiple soundmodems in the same process
+
+use std::sync::mpsc::{Sender, SyncSender, TryRecvError, channel};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use m17core::modem::SAMPLES_PER_MS;
+use m17core::simulation::{Channel, Impairments};
+use thiserror::Error;
+
+use crate::error::SoundmodemError;
+use crate::soundmodem::{
+    InputSource, OutputBuffer, OutputSink, Ptt, SoundmodemErrorSender, SoundmodemEvent,
+};
+
+/// A shared radio channel for testing applications end to end without real radios.
+///
+/// Each `VirtualRadio` provides an input, output and PTT for a `Soundmodem`. While a radio's PTT
+/// is asserted its output is transmitted onto the air, where it is summed with every other
+/// transmission, so collisions are heard as they would be for real. Every radio that is not
+/// transmitting receives the sum through its own `Impairments`.
+///
+/// Runs in real time. The channel stops when `VirtualAir` is dropped.
+pub struct VirtualAir {
+    radios: Arc<Mutex<Vec<RadioState>>>,
+    _end_tx: Sender<()>,
+}
+
+impl VirtualAir {
+    pub fn new() -> Self {
+        let radios = Arc::new(Mutex::new(Vec::new()));
+        let (end_tx, end_rx) = channel();
+        let air_radios = radios.clone();
+        std::thread::spawn(move || {
+            const TICK: Duration = Duration::from_millis(25);
+            const SAMPLES_PER_TICK: usize = 25 * SAMPLES_PER_MS;
+            let mut next_tick = Instant::now() + TICK;
+
+            while end_rx.try_recv() == Err(TryRecvError::Empty) {
+                std::thread::sleep(next_tick.duration_since(Instant::now()));
+                next_tick += TICK;
+                let overflowed = transfer(&mut air_radios.lock().unwrap(), SAMPLES_PER_TICK);
+                // Report without holding the lock, since this can block
+                for errors in overflowed {
+                    errors.send_error(VirtualAirError::Overflow);
+                }
+            }
+        });
+        Self {
+            radios,
+            _end_tx: end_tx,
+        }
+    }
+
+    /// Add a radio whose receiver hears the channel through `impairments`.
+    pub fn add_radio(&self, impairments: Impairments) -> VirtualRadio {
+        let mut radios = self.radios.lock().unwrap();
+        let id = radios.len();
+        radios.push(RadioState {
+            ptt: false,
+            input: None,
+            output: None,
+            channel: Channel::new(impairments, id as u32 + 1),
+        });
+        VirtualRadio {
+            radios: self.radios.clone(),
+            id,
+        }
+    }
+}
+
+impl Default for VirtualAir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One station's connection to a `VirtualAir`.
+///
+/// Use `input()`, `output()` and `ptt()` to retrieve handles for a `Soundmodem`.
+#[derive(Clone)]
+pub struct VirtualRadio {
+    radios: Arc<Mutex<Vec<RadioState>>>,
+    id: usize,
+}
+
+impl VirtualRadio {
+    pub fn input(&self) -> VirtualRadioInput {
+        VirtualRadioInput(self.clone())
+    }
+
+    pub fn output(&self) -> VirtualRadioOutput {
+        VirtualRadioOutput(self.clone())
+    }
+
+    pub fn ptt(&self) -> VirtualRadioPtt {
+        VirtualRadioPtt(self.clone())
+    }
+
+    /// Change how this radio's receiver hears the channel.
+    pub fn set_impairments(&self, impairments: Impairments) {
+        self.with_state(|r| r.channel.set_impairments(impairments));
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut RadioState) -> T) -> T {
+        f(&mut self.radios.lock().unwrap()[self.id])
+    }
+}
+
+pub struct VirtualRadioInput(VirtualRadio);
+
+impl InputSource for VirtualRadioInput {
+    fn start(&self, samples: SyncSender<SoundmodemEvent>, errors: SoundmodemErrorSender) {
+        self.0.with_state(|r| r.input = Some((samples, errors)));
+    }
+
+    fn close(&self) {
+        self.0.with_state(|r| r.input = None);
+    }
+}
+
+pub struct VirtualRadioOutput(VirtualRadio);
+
+impl OutputSink for VirtualRadioOutput {
+    fn start(
+        &self,
+        event_tx: SyncSender<SoundmodemEvent>,
+        buffer: Arc<RwLock<OutputBuffer>>,
+        _errors: SoundmodemErrorSender,
+    ) {
+        self.0.with_state(|r| r.output = Some((event_tx, buffer)));
+    }
+
+    fn close(&self) {
+        self.0.with_state(|r| r.output = None);
+    }
+}
+
+pub struct VirtualRadioPtt(VirtualRadio);
+
+impl Ptt for VirtualRadioPtt {
+    fn ptt_on(&mut self) -> Result<(), SoundmodemError> {
+        self.0.with_state(|r| r.ptt = true);
+        Ok(())
+    }
+
+    fn ptt_off(&mut self) -> Result<(), SoundmodemError> {
+        self.0.with_state(|r| r.ptt = false);
+        Ok(())
+    }
+}
+
+struct RadioState {
+    ptt: bool,
+    input: Option<(SyncSender<SoundmodemEvent>, SoundmodemErrorSender)>,
+    output: Option<(SyncSender<SoundmodemEvent>, Arc<RwLock<OutputBuffer>>)>,
+    /// Impairments on the way to this radio's receiver
+    channel: Channel,
+}
+
+/// Move one tick's worth of samples from every transmitter to every receiver.
+///
+/// Returns the error senders for any radios whose soundmodem could not accept the samples.
+fn transfer(radios: &mut [RadioState], len: usize) -> Vec<SoundmodemErrorSender> {
+    let mut overflowed = vec![];
+    let mut air = vec![0i16; len];
+    for radio in radios.iter_mut() {
+        let Some((event_tx, buffer)) = &radio.output else {
+            continue;
+        };
+        // Like a sound card, pick up samples whether or not the transmitter is keyed
+        let mut buffer = buffer.write().unwrap();
+        let mut taken = 0;
+        for a in air.iter_mut() {
+            let Some(s) = buffer.samples.pop_front() else {
+                if !buffer.idling {
+                    let _ = event_tx.try_send(SoundmodemEvent::OutputUnderrun);
+                }
+                break;
+            };
+            if radio.ptt {
+                *a = a.saturating_add(s);
+            }
+            taken += 1;
+        }
+        let _ = event_tx.try_send(SoundmodemEvent::DidReadFromOutputBuffer {
+            len: taken,
+            timestamp: Instant::now(),
+        });
+    }
+
+    for radio in radios.iter_mut() {
+        let Some((samples, errors)) = &radio.input else {
+            continue;
+        };
+        // A transmitting radio's receiver is muted
+        let heard = if radio.ptt {
+            vec![0i16; len]
+        } else {
+            air.clone()
+        };
+        let mut received = Vec::with_capacity(len + 1);
+        radio.channel.process(&heard, |s| received.push(s));
+        if samples
+            .try_send(SoundmodemEvent::BasebandInput(received.into()))
+            .is_err()
+        {
+            overflowed.push(errors.clone());
+        }
+    }
+    overflowed
+}
+
+#[derive(Debug, Error)]
+pub enum VirtualAirError {
+    #[error("overflow occurred feeding sample to soundmodem")]
+    Overflow,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soundmodem::{NullErrorHandler, Soundmodem};
+    use crate::tnc::Tnc;
+    use m17core::kiss::{KissBuffer, KissFrame, PORT_PACKET_BASIC};
+    use std::io::{Read, Write};
+    use std::sync::mpsc::RecvTimeoutError;
+
+    fn soundmodem(radio: &VirtualRadio) -> Soundmodem {
+        let mut soundmodem = Soundmodem::new(
+            radio.input(),
+            radio.output(),
+            radio.ptt(),
+            NullErrorHandler::new(),
+        );
+        soundmodem.start();
+        soundmodem
+    }
+
+    #[test]
+    fn packet_over_the_air() {
+        let air = VirtualAir::new();
+        let sender = air.add_radio(Impairments::new());
+        let receiver = air.add_radio(Impairments {
+            noise: 500.0,
+            frequency_offset_hz: 100.0,
+            clock_skew_ppm: 50.0,
+            ..Impairments::new()
+        });
+        let mut tx = soundmodem(&sender);
+        let mut rx = soundmodem(&receiver);
+
+        let (packets_tx, packets_rx) = channel();
+        let mut reader = rx.try_clone().unwrap();
+        std::thread::spawn(move || {
+            let mut kiss = KissBuffer::new();
+            loop {
+                let buf = kiss.buf_remaining();
+                let Ok(n) = reader.read(buf) else {
+                    return;
+                };
+                kiss.did_write(n);
+                while let Some(frame) = kiss.next_frame() {
+                    if frame.port() == Ok(PORT_PACKET_BASIC) {
+                        let mut payload = [0u8; 100];
+                        let n = frame.decode_payload(&mut payload).unwrap();
+                        let _ = packets_tx.send(payload[0..n].to_vec());
+                    }
+                }
+            }
+        });
+
+        let packet = KissFrame::new_basic_packet(b"over the air").unwrap();
+        tx.write_all(packet.as_bytes()).unwrap();
+        let received = packets_rx.recv_timeout(Duration::from_secs(5));
+        tx.close();
+        rx.close();
+        match received {
+            Ok(payload) => assert!(payload.ends_with(b"over the air")),
+            Err(RecvTimeoutError::Timeout) => panic!("packet was not received"),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
+    #[test]
+    fn second_station_defers_to_busy_channel() {
+        let air = VirtualAir::new();
+        let first = air.add_radio(Impairments::new());
+        let second = air.add_radio(Impairments::new());
+        let mut first_tx = soundmodem(&first);
+        let mut second_tx = soundmodem(&second);
+        let keyed = || {
+            let radios = air.radios.lock().unwrap();
+            (radios[first.id].ptt, radios[second.id].ptt)
+        };
+
+        // About 1.4 seconds on air
+        let long = KissFrame::new_basic_packet(&[0x55; 800]).unwrap();
+        first_tx.write_all(long.as_bytes()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !keyed().0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        // Give the second station's demodulator time to hear the transmission
+        std::thread::sleep(Duration::from_millis(300));
+        let short = KissFrame::new_basic_packet(b"after you").unwrap();
+        second_tx.write_all(short.as_bytes()).unwrap();
+
+        let mut first_keyed = false;
+        let mut second_keyed = false;
+        let mut overlapped = false;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !second_keyed && Instant::now() < deadline {
+            let (a, b) = keyed();
+            first_keyed |= a;
+            second_keyed |= b;
+            overlapped |= a && b;
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        first_tx.close();
+        second_tx.close();
+
+        assert!(first_keyed);
+        assert!(second_keyed, "second station never transmitted");
+        assert!(!overlapped, "second station keyed up over the first");
+    }
+}
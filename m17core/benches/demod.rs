@@ -0,0 +1,288 @@
+//! Demodulator throughput over a noisy recording of back-to-back stream transmissions.
+//!
+//! Run with `cargo bench -p m17core`. Add `--features fixed-point` to include the integer
+//! demodulator.
+
+use std::time::Instant;
+
+use m17core::modem::{
+    Demodulator, Modulator, ModulatorFrame, SAMPLE_RATE, SoftDemodulator, SoftModulator,
+};
+use m17core::protocol::{LsfFrame, StreamFrame};
+use m17core::simulation::{Channel, Impairments};
+
+/// Ten seconds of audio: a few transmissions with silence in between.
+fn test_signal() -> Vec<i16> {
+    let mut modulated = vec![];
+    let mut buf = [0i16; 1024];
+    let mut modulator = SoftModulator::new();
+    let lsf = LsfFrame([0u8; 30]);
+    for _ in 0..5 {
+        let mut frames = vec![
+            ModulatorFrame::Preamble {
+                tx_delay: 0,
+                ptt_lead: 0,
+            },
+            ModulatorFrame::Lsf(lsf.clone()),
+        ];
+        for frame_number in 0..25 {
+            frames.push(ModulatorFrame::Stream(StreamFrame {
+                lich_idx: (frame_number % 6) as u8,
+                frame_number,
+                stream_data: [frame_number as u8; 16],
+                ..Default::default()
+            }));
+        }
+        frames.push(ModulatorFrame::EndOfTransmission);
+        for frame in frames {
+            modulator.provide_next_frame(Some(frame));
+            loop {
+                let n = modulator.read_output_samples(&mut buf);
+                if n == 0 {
+                    break;
+                }
+                modulated.extend_from_slice(&buf[0..n]);
+            }
+        }
+        modulated.extend_from_slice(&[0i16; 19200]);
+    }
+
+    let mut channel = Channel::new(
+        Impairments {
+            noise: 1000.0,
+            ..Impairments::new()
+        },
+        1,
+    );
+    let mut signal = vec![];
+    channel.process(&modulated, |s| signal.push(s));
+    signal
+}
+
+fn report(name: &str, samples: usize, frames: usize, start: Instant) {
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "{name:<32} {frames:>4} frames  {:>10.0} samples/s  {:>7.1}x real time",
+        samples as f64 / secs,
+        samples as f64 / secs / SAMPLE_RATE as f64
+    );
+}
+
+fn main() {
+    let signal = test_signal();
+
+    let start = Instant::now();
+    let mut demod = baseline::BaselineDemodulator::new();
+    let frames = signal.iter().filter(|s| demod.demod(**s)).count();
+    report("baseline (sync search only)", signal.len(), frames, start);
+
+    let start = Instant::now();
+    let mut demod = SoftDemodulator::new();
+    let frames = signal.iter().filter(|s| demod.demod(**s).is_some()).count();
+    report("per-sample", signal.len(), frames, start);
+
+    for decimation in [1, 2, 5] {
+        let start = Instant::now();
+        let mut demod = SoftDemodulator::new_decimating(decimation);
+        let mut frames = 0;
+        for block in signal.chunks(1200) {
+            demod.demod_block(block, &mut |_, _, _| frames += 1);
+        }
+        report(
+            &format!("blocks, decimation {decimation}"),
+            signal.len(),
+            frames,
+            start,
+        );
+    }
+
+    #[cfg(feature = "fixed-point")]
+    for decimation in [1, 2, 5] {
+        let start = Instant::now();
+        let mut demod = m17core::modem::FixedDemodulator::new_fixed_point_decimating(decimation);
+        let mut frames = 0;
+        for block in signal.chunks(1200) {
+            demod.demod_block(block, &mut |_, _, _| frames += 1);
+        }
+        report(
+            &format!("fixed point, decimation {decimation}"),
+            signal.len(),
+            frames,
+            start,
+        );
+    }
+}
+
+/// The demodulator as it was before filtering was reworked for throughput, for comparison.
+///
+/// Each sample is filtered through a circular window and every burst type is normalised
+/// separately. Decoding a frame is not reachable from outside the crate and costs the same in
+/// both, so this only finds sync bursts and reports when it would have decoded.
+#[allow(clippy::needless_range_loop)]
+mod baseline {
+    const LSF_SYNC: [i8; 8] = [1, 1, 1, 1, -1, -1, 1, -1];
+    const BERT_SYNC: [i8; 8] = [-1, 1, -1, -1, 1, 1, 1, 1];
+    const STREAM_SYNC: [i8; 8] = [-1, -1, -1, -1, 1, 1, -1, 1];
+    const PACKET_SYNC: [i8; 8] = [1, -1, 1, 1, -1, -1, -1, -1];
+    const PREAMBLE: [i8; 8] = [1, -1, 1, -1, 1, -1, 1, -1];
+    const END_OF_TRANSMISSION: [i8; 8] = [1, 1, 1, 1, 1, 1, -1, 1];
+
+    const SYNC_MIN_GAIN: f32 = 16.0;
+    const SYNC_BIT_THRESHOLD: f32 = 0.3;
+    const SYNC_THRESHOLD: f32 = 100.0;
+
+    struct DecodeCandidate {
+        burst: [i8; 8],
+        age: u8,
+        diff: f32,
+    }
+
+    pub struct BaselineDemodulator {
+        rrc: [f32; 81],
+        filter_win: [i16; 81],
+        filter_cursor: usize,
+        rx_win: [f32; 1920],
+        rx_cursor: usize,
+        candidate: Option<DecodeCandidate>,
+        sample: u64,
+        samples_until_decode: Option<u16>,
+        dcd: Option<u64>,
+    }
+
+    impl BaselineDemodulator {
+        pub fn new() -> Self {
+            Self {
+                rrc: rrc_taps(),
+                filter_win: [0; 81],
+                filter_cursor: 0,
+                rx_win: [0.0; 1920],
+                rx_cursor: 0,
+                candidate: None,
+                sample: 0,
+                samples_until_decode: None,
+                dcd: None,
+            }
+        }
+
+        /// Returns true if this sample completes a frame that would be decoded.
+        pub fn demod(&mut self, sample: i16) -> bool {
+            self.filter_win[self.filter_cursor] = sample;
+            self.filter_cursor = (self.filter_cursor + 1) % 81;
+            let mut out: f32 = 0.0;
+            for i in 0..81 {
+                let filter_idx = (self.filter_cursor + i) % 81;
+                out += self.rrc[i] * self.filter_win[filter_idx] as f32;
+            }
+
+            self.rx_win[self.rx_cursor] = out;
+            self.rx_cursor = (self.rx_cursor + 1) % 1920;
+
+            self.sample += 1;
+            if let Some(end_sample) = self.dcd
+                && self.sample > end_sample
+            {
+                self.dcd = None;
+            }
+
+            if let Some(samples_until_decode) = self.samples_until_decode {
+                let sud = samples_until_decode - 1;
+                if sud > 0 {
+                    self.samples_until_decode = Some(sud);
+                    return false;
+                }
+                self.samples_until_decode = None;
+                if self.candidate.take().is_some() {
+                    return true;
+                }
+            }
+
+            let mut burst_window = [0f32; 8];
+            for i in 0..8 {
+                let c = (self.rx_cursor + 1920 - 1 - ((7 - i) * 10)) % 1920;
+                burst_window[i] = self.rx_win[c];
+            }
+
+            for burst in [PREAMBLE, END_OF_TRANSMISSION] {
+                if sync_burst_correlation(burst, &burst_window) < SYNC_THRESHOLD {
+                    self.dcd = Some(self.sample + 240);
+                }
+            }
+
+            for burst in [LSF_SYNC, BERT_SYNC, STREAM_SYNC, PACKET_SYNC] {
+                let diff = sync_burst_correlation(burst, &burst_window);
+                if diff < SYNC_THRESHOLD {
+                    let mut new_candidate = true;
+                    if let Some(c) = self.candidate.as_mut()
+                        && diff > c.diff
+                    {
+                        c.age += 1;
+                        new_candidate = false;
+                    }
+                    if new_candidate {
+                        self.candidate = Some(DecodeCandidate {
+                            burst,
+                            age: 1,
+                            diff,
+                        });
+                    }
+                }
+                if diff >= SYNC_THRESHOLD
+                    && let Some(c) = self.candidate.as_ref()
+                    && c.burst == burst
+                {
+                    self.samples_until_decode = Some((184 * 10) - (c.age as u16));
+                    self.dcd = Some(self.sample + 1920 + 1920);
+                }
+            }
+
+            false
+        }
+    }
+
+    fn sync_burst_correlation(target: [i8; 8], samples: &[f32; 8]) -> f32 {
+        let mut pos_max: f32 = f32::MIN;
+        let mut neg_max: f32 = f32::MAX;
+        for i in 0..8 {
+            pos_max = pos_max.max(samples[i]);
+            neg_max = neg_max.min(samples[i]);
+        }
+        let gain = (pos_max - neg_max) / 2.0;
+        let shift = pos_max + neg_max;
+        if gain < SYNC_MIN_GAIN {
+            return f32::MAX;
+        }
+        let mut diff = 0.0;
+        for i in 0..8 {
+            let sym_diff = (((samples[i] - shift) / gain) - target[i] as f32).abs();
+            if sym_diff > SYNC_BIT_THRESHOLD {
+                return f32::MAX;
+            }
+            diff += sym_diff;
+        }
+        diff
+    }
+
+    /// Root raised cosine taps for 4800 baud at 48 kHz with a roll-off of 0.5, the same as the
+    /// demodulator's own filter.
+    fn rrc_taps() -> [f32; 81] {
+        use std::f64::consts::PI;
+        let beta = 0.5;
+        let sps = 10.0;
+        let mut taps = [0f32; 81];
+        for (n, tap) in taps.iter_mut().enumerate() {
+            let t = (n as f64 - 40.0) / sps;
+            let h = if t == 0.0 {
+                1.0 - beta + 4.0 * beta / PI
+            } else if (4.0 * beta * t).abs() == 1.0 {
+                beta / 2f64.sqrt()
+                    * ((1.0 + 2.0 / PI) * (PI / (4.0 * beta)).sin()
+                        + (1.0 - 2.0 / PI) * (PI / (4.0 * beta)).cos())
+            } else {
+                ((PI * t * (1.0 - beta)).sin() + 4.0 * beta * t * (PI * t * (1.0 + beta)).cos())
+                    / (PI * t * (1.0 - (4.0 * beta * t).powi(2)))
+            };
+            *tap = (h / sps.sqrt()) as f32;
+        }
+        taps
+    }
+}
@@ -12,7 +12,7 @@ where
         self.0.as_ref()[idx / 8] >> (7 - (idx % 8)) & 0x01
     }
 
-    pub(crate) fn iter(&self) -> BitsIterator<T> {
+    pub(crate) fn iter(&self) -> BitsIterator<'_, T> {
         BitsIterator { bits: self, idx: 0 }
     }
 }
@@ -15,12 +15,12 @@ const PLUS_ONE: [u8; 2] = [0, 0];
 const MINUS_ONE: [u8; 2] = [1, 0];
 const MINUS_THREE: [u8; 2] = [1, 1];
 
-fn decode_sample(sample: f32) -> [u8; 2] {
-    if sample > 0.667 {
+fn decode_sample(symbol: i8) -> [u8; 2] {
+    if symbol > 1 {
         PLUS_THREE
-    } else if sample > 0.0 {
+    } else if symbol > 0 {
         PLUS_ONE
-    } else if sample > -0.667 {
+    } else if symbol > -2 {
         MINUS_ONE
     } else {
         MINUS_THREE
@@ -48,13 +48,32 @@ impl SyncBurst {
             Self::EndOfTransmission => END_OF_TRANSMISSION,
         }
     }
+
+    /// The burst which this one matches when received with inverted polarity.
+    ///
+    /// LSF and stream syncs are each other's inverse, as are BERT and packet. The preamble is
+    /// its own inverse if shifted by one symbol, and the EOT burst has no counterpart.
+    pub(crate) fn inverted(&self) -> Self {
+        match self {
+            Self::Lsf => Self::Stream,
+            Self::Bert => Self::Packet,
+            Self::Stream => Self::Lsf,
+            Self::Packet => Self::Bert,
+            Self::Preamble => Self::Preamble,
+            Self::EndOfTransmission => Self::EndOfTransmission,
+        }
+    }
 }
 
 const SYNC_MIN_GAIN: f32 = 16.0;
-const SYNC_BIT_THRESHOLD: f32 = 0.3;
+pub(crate) const SYNC_BIT_THRESHOLD: f32 = 0.3;
 pub const SYNC_THRESHOLD: f32 = 100.0;
 
-pub(crate) fn sync_burst_correlation(target: [i8; 8], samples: &[f32]) -> (f32, f32, f32) {
+/// Normalise a window of 8 symbols so that it can be compared against sync bursts.
+///
+/// Returns the normalised symbols along with the gain and shift that were removed, or `None` if
+/// the signal is too weak to contain a sync burst.
+pub(crate) fn sync_burst_normalise(samples: &[f32]) -> Option<([f32; 8], f32, f32)> {
     let mut pos_max: f32 = f32::MIN;
     let mut neg_max: f32 = f32::MAX;
     for i in 0..8 {
@@ -64,22 +83,77 @@ pub(crate) fn sync_burst_correlation(target: [i8; 8], samples: &[f32]) -> (f32,
     let gain = (pos_max - neg_max) / 2.0;
     let shift = pos_max + neg_max;
     if gain < SYNC_MIN_GAIN {
-        return (f32::MAX, gain, shift);
+        return None;
+    }
+    let mut normalised = [0f32; 8];
+    for i in 0..8 {
+        normalised[i] = (samples[i] - shift) / gain;
     }
+    Some((normalised, gain, shift))
+}
 
+/// How far normalised symbols are from a sync burst. Lower is better; see `SYNC_THRESHOLD`.
+///
+/// If any one symbol is further than `bit_threshold` from its target then there is no match.
+pub(crate) fn sync_burst_correlation(
+    target: [i8; 8],
+    normalised: &[f32; 8],
+    bit_threshold: f32,
+) -> f32 {
     let mut diff = 0.0;
     for i in 0..8 {
-        let sym_diff = (((samples[i] - shift) / gain) - target[i] as f32).abs();
-        if sym_diff > SYNC_BIT_THRESHOLD {
-            return (f32::MAX, gain, shift);
+        let sym_diff = (normalised[i] - target[i] as f32).abs();
+        if sym_diff > bit_threshold {
+            return f32::MAX;
+        }
+        diff += sym_diff;
+    }
+    diff
+}
+
+/// Fixed point equivalent of `sync_burst_normalise`. Normalised symbols are in Q8.
+#[cfg(feature = "fixed-point")]
+pub(crate) fn sync_burst_normalise_q8(samples: &[i32]) -> Option<([i32; 8], i32, i32)> {
+    let mut pos_max = i32::MIN;
+    let mut neg_max = i32::MAX;
+    for i in 0..8 {
+        pos_max = pos_max.max(samples[i]);
+        neg_max = neg_max.min(samples[i]);
+    }
+    let gain = (pos_max - neg_max) / 2;
+    let shift = pos_max + neg_max;
+    if gain < SYNC_MIN_GAIN as i32 {
+        return None;
+    }
+    let mut normalised = [0i32; 8];
+    for i in 0..8 {
+        normalised[i] = (samples[i] - shift) * 256 / gain;
+    }
+    Some((normalised, gain, shift))
+}
+
+/// Fixed point equivalent of `sync_burst_correlation`, with the threshold and result in Q8.
+#[cfg(feature = "fixed-point")]
+pub(crate) fn sync_burst_correlation_q8(
+    target: [i8; 8],
+    normalised: &[i32; 8],
+    bit_threshold: i32,
+) -> i32 {
+    let mut diff = 0;
+    for i in 0..8 {
+        let sym_diff = (normalised[i] - target[i] as i32 * 256).abs();
+        if sym_diff > bit_threshold {
+            return i32::MAX;
         }
         diff += sym_diff;
     }
-    (diff, gain, shift)
+    diff
 }
 
 /// Decode frame and return contents after the sync burst
-pub(crate) fn frame_initial_decode(frame: &[f32] /* length 192 */) -> [u8; 46] {
+///
+/// Symbols are the levels that were received: -3, -1, +1 or +3.
+pub(crate) fn frame_initial_decode(frame: &[i8] /* length 192 */) -> [u8; 46] {
     let mut decoded = [0u8; 48];
     let mut decoded_bits = BitsMut::new(&mut decoded);
     for (idx, s) in frame.iter().enumerate() {
@@ -91,7 +165,7 @@ pub(crate) fn frame_initial_decode(frame: &[f32] /* length 192 */) -> [u8; 46] {
     interleave(&decoded[2..])
 }
 
-pub(crate) fn parse_lsf(frame: &[f32] /* length 192 */) -> Option<(LsfFrame, u8)> {
+pub(crate) fn parse_lsf(frame: &[i8] /* length 192 */) -> Option<(LsfFrame, u8)> {
     let deinterleaved = frame_initial_decode(frame);
     debug!("deinterleaved: {:?}", deinterleaved);
     let (lsf, errors) = match fec::decode(&deinterleaved, 240, p_1) {
@@ -111,7 +185,7 @@ pub(crate) fn parse_lsf(frame: &[f32] /* length 192 */) -> Option<(LsfFrame, u8)
     Some((lsf, errors))
 }
 
-pub(crate) fn parse_stream(frame: &[f32] /* length 192 */) -> Option<(StreamFrame, u8)> {
+pub(crate) fn parse_stream(frame: &[i8] /* length 192 */) -> Option<(StreamFrame, u8)> {
     let deinterleaved = frame_initial_decode(frame);
     let stream_part = &deinterleaved[12..];
     let (stream, errors) = fec::decode(stream_part, 144, p_2)?;
@@ -140,7 +214,7 @@ pub(crate) fn parse_stream(frame: &[f32] /* length 192 */) -> Option<(StreamFram
     }
 }
 
-pub(crate) fn parse_packet(frame: &[f32] /* length 192 */) -> Option<(PacketFrame, u8)> {
+pub(crate) fn parse_packet(frame: &[i8] /* length 192 */) -> Option<(PacketFrame, u8)> {
     let deinterleaved = frame_initial_decode(frame);
     let (packet, errors) = fec::decode(&deinterleaved, 206, p_3)?;
     let final_frame = (packet[25] & 0x80) > 0;
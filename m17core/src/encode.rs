@@ -2,18 +2,20 @@ use crate::{
     bits::Bits,
     fec::{self, p_1, p_2, p_3},
     interleave::interleave,
+    modem::CalibrationPattern,
+    prng::Prng,
     protocol::{
         LSF_SYNC, LsfFrame, PACKET_SYNC, PacketFrame, PacketFrameCounter, STREAM_SYNC, StreamFrame,
     },
     random::random_xor,
 };
 
-pub(crate) fn encode_lsf(frame: &LsfFrame) -> [f32; 192] {
+pub(crate) fn encode_lsf(frame: &LsfFrame) -> [i8; 192] {
     let type3 = fec::encode(&frame.0, 240, p_1);
     interleave_to_dibits(type3, LSF_SYNC)
 }
 
-pub(crate) fn encode_stream(frame: &StreamFrame) -> [f32; 192] {
+pub(crate) fn encode_stream(frame: &StreamFrame) -> [i8; 192] {
     let lich = encode_lich(frame.lich_idx, &frame.lich_part);
     let mut type1 = [0u8; 18];
     let frame_number = frame.frame_number | if frame.end_of_stream { 0x8000 } else { 0x0000 };
@@ -26,7 +28,7 @@ pub(crate) fn encode_stream(frame: &StreamFrame) -> [f32; 192] {
     interleave_to_dibits(combined, STREAM_SYNC)
 }
 
-pub(crate) fn encode_packet(frame: &PacketFrame) -> [f32; 192] {
+pub(crate) fn encode_packet(frame: &PacketFrame) -> [i8; 192] {
     let mut type1 = [0u8; 26]; // only 206 out of 208 bits filled
     match frame.counter {
         PacketFrameCounter::Frame { index } => {
@@ -47,20 +49,37 @@ pub(crate) fn encode_packet(frame: &PacketFrame) -> [f32; 192] {
 /// Polarity needs to be flipped for BERT, however we don't support this yet.
 /// STREAM and PACKET don't need to be considered as they are an invalid way to
 /// begin a transmission.
-pub(crate) fn generate_preamble() -> [f32; 192] {
+pub(crate) fn generate_preamble() -> [i8; 192] {
     // TODO: should all these encode/generate functions return owning iterators?
     // Then I could avoid making this array which I'm just going to have to copy anyway
-    let mut out = [1.0f32; 192];
+    let mut out = [3i8; 192];
     for n in out.iter_mut().skip(1).step_by(2) {
-        *n = -1.0;
+        *n = -3;
     }
     out
 }
 
-pub(crate) fn generate_end_of_transmission() -> [f32; 192] {
-    let mut out = [1.0f32; 192];
+/// Generate 40 ms of a transmit calibration pattern.
+///
+/// `prng` supplies the symbols for `CalibrationPattern::Random` and should be kept between calls
+/// so that the pattern continues without repeating.
+pub(crate) fn generate_calibration(pattern: CalibrationPattern, prng: &mut Prng) -> [i8; 192] {
+    let mut out = [0i8; 192];
+    for (i, n) in out.iter_mut().enumerate() {
+        *n = match pattern {
+            CalibrationPattern::Preamble => [3, -3][i % 2],
+            CalibrationPattern::OuterTone => [3, -3][(i / 4) % 2],
+            CalibrationPattern::InnerTone => [1, -1][(i / 4) % 2],
+            CalibrationPattern::Random => [3, 1, -1, -3][(prng.next_u8() >> 6) as usize],
+        };
+    }
+    out
+}
+
+pub(crate) fn generate_end_of_transmission() -> [i8; 192] {
+    let mut out = [3i8; 192];
     for n in out.iter_mut().skip(6).step_by(8) {
-        *n = -1.0;
+        *n = -3;
     }
     out
 }
@@ -80,21 +99,22 @@ pub(crate) fn encode_lich(counter: u8, part: &[u8; 5]) -> [u8; 12] {
     out
 }
 
-fn interleave_to_dibits(combined: [u8; 46], sync_burst: [i8; 8]) -> [f32; 192] {
+/// Symbols are -3, -1, +1 or +3. Sync bursts only use the outer two.
+fn interleave_to_dibits(combined: [u8; 46], sync_burst: [i8; 8]) -> [i8; 192] {
     let mut interleaved = interleave(&combined);
     random_xor(&mut interleaved);
-    let mut out = [0f32; 192];
+    let mut out = [0i8; 192];
     for (val, o) in sync_burst.iter().zip(out.iter_mut()) {
-        *o = *val as f32;
+        *o = *val * 3;
     }
     let bits = Bits::new(&interleaved);
     let mut out_bits = bits.iter();
     for o in out[8..].iter_mut() {
         *o = match (out_bits.next().unwrap(), out_bits.next().unwrap()) {
-            (0, 1) => 1.0,
-            (0, 0) => 1.0 / 3.0,
-            (1, 0) => -1.0 / 3.0,
-            (1, 1) => -1.0,
+            (0, 1) => 3,
+            (0, 0) => 1,
+            (1, 0) => -1,
+            (1, 1) => -3,
             _ => unreachable!(),
         };
     }
@@ -172,7 +172,7 @@ static TRANSITIONS: [Transition; 32] = [
 
 pub(crate) fn p_1(step: usize) -> (bool, bool) {
     let mod61 = step % 61;
-    let is_even = mod61 % 2 == 0;
+    let is_even = mod61.is_multiple_of(2);
     (mod61 > 30 || is_even, mod61 < 30 || is_even)
 }
 
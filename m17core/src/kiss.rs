@@ -1,3 +1,4 @@
+use crate::modem::{CalibrationPattern, SignalQuality};
 use crate::protocol::StreamFrame;
 
 // Note FEND and FESC both have the top two bits set. In the header byte this corresponds
@@ -12,6 +13,97 @@ pub const PORT_PACKET_BASIC: u8 = 0;
 pub const PORT_PACKET_FULL: u8 = 1;
 pub const PORT_STREAM: u8 = 2;
 
+/// M17RT extension carried in a `SetHardware` frame: TX queue occupancy.
+///
+/// The host may send this sub-command with no further payload to ask for the current status.
+/// The TNC responds with a `SetHardware` frame containing this sub-command followed by an
+/// encoded `QueueStatus`. The TNC will also send it unprompted whenever it has to discard
+/// data because a queue is full.
+pub const HW_QUEUE_STATUS: u8 = 0x01;
+
+/// M17RT extension carried in a `SetHardware` frame: set the transmit time-out timer.
+///
+/// Followed by a big-endian u16 number of seconds. Transmissions lasting longer than this will be
+/// ended by the TNC. Zero disables the timer (default).
+pub const HW_TX_TIMEOUT: u8 = 0x02;
+
+/// M17RT extension carried in a `SetHardware` frame: set the transmit duty cycle limit.
+///
+/// Followed by a u8 percentage, then a big-endian u16 window length in seconds. If the TNC has been
+/// transmitting for more than this percentage of the window, it will cease transmitting until the
+/// proportion falls again. 100% disables the limit (default).
+pub const HW_DUTY_CYCLE: u8 = 0x03;
+
+/// M17RT extension carried in a `SetHardware` frame: set the lockout period.
+///
+/// Followed by a big-endian u16 number of seconds. After the time-out timer ends a transmission,
+/// the TNC will refuse to transmit again for this long. Default zero.
+pub const HW_TX_LOCKOUT: u8 = 0x04;
+
+/// M17RT extension carried in a `SetHardware` frame: the TNC is limiting transmissions.
+///
+/// Sent from TNC to host. Followed by a `TxLimitReason` byte, then a big-endian u16 number of
+/// seconds until transmission will be permitted again, or zero if unknown.
+pub const HW_TX_LIMIT: u8 = 0x05;
+
+/// M17RT extension carried in a `SetHardware` frame: a stream transmission ended early.
+///
+/// Sent from TNC to host. Followed by a `StreamEndReason` byte. The TNC has sent its own
+/// end-of-stream frame and will ignore further stream data until the next LSF.
+pub const HW_STREAM_ENDED_EARLY: u8 = 0x06;
+
+/// M17RT extension carried in a `SetHardware` frame: a partly received packet was discarded.
+///
+/// Sent from TNC to host. Followed by a `PacketDiscardReason` byte, the number of payload frames
+/// that had been received, then the 30-byte LSF of the packet so the host can ask for it again.
+pub const HW_PACKET_DISCARDED: u8 = 0x07;
+
+/// M17RT extension carried in a `SetHardware` frame: choose whether received packets that are
+/// compatible with basic mode are also delivered on port 0.
+///
+/// Sent on port 0, followed by a single byte, zero to disable or non-zero to enable. Default
+/// enabled. Hosts that only use port 1 can turn this off to avoid receiving every such packet
+/// twice.
+pub const HW_BASIC_PACKETS: u8 = 0x08;
+
+/// M17RT extension carried in a `SetHardware` frame: signal quality of received frames.
+///
+/// From host to TNC, followed by a single byte to disable (zero) or enable (non-zero) reports.
+/// Default disabled. From TNC to host, followed by an encoded `SignalQuality`, which describes
+/// the next data frame sent to the host on the same port.
+pub const HW_SIGNAL_QUALITY: u8 = 0x09;
+
+/// M17RT extension carried in a `SetHardware` frame: transmit a calibration pattern.
+///
+/// Followed by a single byte: a `CalibrationPattern` value to key up and transmit that pattern
+/// continuously, or zero to stop. Queued data is not sent while the pattern is active. The
+/// transmit time-out, duty cycle and lockout still apply but the channel is not checked first.
+pub const HW_CALIBRATION: u8 = 0x0A;
+
+/// M17RT extension carried in a `SetHardware` frame: set the transmit audio level.
+///
+/// Followed by a u8 percentage of the default output amplitude, up to 200. Default 100.
+pub const HW_TX_LEVEL: u8 = 0x0B;
+
+/// M17RT extension carried in a `SetHardware` frame: set the preamble length.
+///
+/// Followed by a u8 number of 40 ms preamble frames to send at the start of each transmission,
+/// for radios whose AGC or squelch needs longer to settle. Zero is treated as one. Default 1.
+pub const HW_PREAMBLE: u8 = 0x0C;
+
+/// M17RT extension carried in a `SetHardware` frame: set PTT lead and lag.
+///
+/// Followed by a big-endian u16 lead time, then a big-endian u16 lag time, both in milliseconds.
+/// Audio starts at least the lead time (plus TxDelay) after PTT is engaged and PTT is released
+/// the lag time (plus TxTail) after the audio has finished playing. Default zero for both.
+pub const HW_PTT_TIMING: u8 = 0x0D;
+
+/// M17RT extension carried in a `SetHardware` frame: a stream being received was lost.
+///
+/// Sent from TNC to host. Followed by a `StreamLostReason` byte. The stream ended without a frame
+/// marked end-of-stream, so the host should treat it as finished.
+pub const HW_STREAM_LOST: u8 = 0x0E;
+
 /// Maximum theoretical frame size for any valid M17 KISS frame.
 ///
 /// In M17 Full Packet Mode a 30-byte LSF is merged with a packet which may be up to
@@ -166,6 +258,22 @@ impl KissFrame {
         KissFrame { data, len: i }
     }
 
+    /// Request to set the TxTail, in units of 10ms
+    pub fn new_set_tx_tail(port: u8, units: u8) -> Self {
+        let mut data = [0u8; MAX_FRAME_LEN];
+        let mut i = 0;
+        push(&mut data, &mut i, FEND);
+        push(
+            &mut data,
+            &mut i,
+            kiss_header(port, KissCommand::TxTail.proto_value()),
+        );
+        push(&mut data, &mut i, units);
+        push(&mut data, &mut i, FEND);
+
+        KissFrame { data, len: i }
+    }
+
     /// Request to set the persistence parameter P
     pub fn new_set_p(port: u8, units: u8) -> Self {
         let mut data = [0u8; MAX_FRAME_LEN];
@@ -182,6 +290,152 @@ impl KissFrame {
         KissFrame { data, len: i }
     }
 
+    /// Request to set the CSMA slot time, in units of 10ms
+    pub fn new_set_slot_time(port: u8, units: u8) -> Self {
+        let mut data = [0u8; MAX_FRAME_LEN];
+        let mut i = 0;
+        push(&mut data, &mut i, FEND);
+        push(
+            &mut data,
+            &mut i,
+            kiss_header(port, KissCommand::SlotTime.proto_value()),
+        );
+        push(&mut data, &mut i, units);
+        push(&mut data, &mut i, FEND);
+
+        KissFrame { data, len: i }
+    }
+
+    /// Frame carrying an implementation-specific command or report.
+    ///
+    /// By convention in M17RT the first byte of `payload` identifies the kind of message,
+    /// e.g. `HW_QUEUE_STATUS`.
+    pub fn new_set_hardware(port: u8, payload: &[u8]) -> Result<Self, KissError> {
+        // Leave room for escaping in a worst case
+        if payload.len() > (MAX_FRAME_LEN - 3) / 2 {
+            return Err(KissError::PayloadTooBig);
+        }
+        let mut data = [0u8; MAX_FRAME_LEN];
+        let mut i = 0;
+        push(&mut data, &mut i, FEND);
+        push(
+            &mut data,
+            &mut i,
+            kiss_header(port, KissCommand::SetHardware.proto_value()),
+        );
+        i += escape(payload, &mut data[i..]);
+        push(&mut data, &mut i, FEND);
+
+        Ok(KissFrame { data, len: i })
+    }
+
+    /// Host asks the TNC to report how full its transmit queues are.
+    pub fn new_query_queue_status(port: u8) -> Self {
+        Self::new_set_hardware(port, &[HW_QUEUE_STATUS]).unwrap()
+    }
+
+    /// TNC reports how full its transmit queues are.
+    pub fn new_queue_status(port: u8, status: &QueueStatus) -> Self {
+        let mut payload = [0u8; 1 + QueueStatus::LEN];
+        payload[0] = HW_QUEUE_STATUS;
+        payload[1..].copy_from_slice(&status.to_bytes());
+        Self::new_set_hardware(port, &payload).unwrap()
+    }
+
+    /// Host sets the transmit time-out timer. Zero disables.
+    pub fn new_set_tx_timeout(port: u8, seconds: u16) -> Self {
+        let secs = seconds.to_be_bytes();
+        Self::new_set_hardware(port, &[HW_TX_TIMEOUT, secs[0], secs[1]]).unwrap()
+    }
+
+    /// Host sets the maximum proportion of time the TNC may transmit over a rolling window.
+    pub fn new_set_duty_cycle(port: u8, percent: u8, window_seconds: u16) -> Self {
+        let secs = window_seconds.to_be_bytes();
+        Self::new_set_hardware(port, &[HW_DUTY_CYCLE, percent, secs[0], secs[1]]).unwrap()
+    }
+
+    /// Host sets how long the TNC will refuse to transmit after the time-out timer triggers.
+    pub fn new_set_tx_lockout(port: u8, seconds: u16) -> Self {
+        let secs = seconds.to_be_bytes();
+        Self::new_set_hardware(port, &[HW_TX_LOCKOUT, secs[0], secs[1]]).unwrap()
+    }
+
+    /// Host chooses whether compatible packets should also be delivered on port 0.
+    pub fn new_set_basic_packets(port: u8, enabled: bool) -> Self {
+        Self::new_set_hardware(port, &[HW_BASIC_PACKETS, enabled as u8]).unwrap()
+    }
+
+    /// Host asks the TNC to start or stop sending signal quality reports.
+    pub fn new_set_signal_quality_reports(port: u8, enabled: bool) -> Self {
+        Self::new_set_hardware(port, &[HW_SIGNAL_QUALITY, enabled as u8]).unwrap()
+    }
+
+    /// Host starts transmitting a calibration pattern, or stops with `None`.
+    pub fn new_set_calibration(port: u8, pattern: Option<CalibrationPattern>) -> Self {
+        let value = pattern.map(|p| p.proto_value()).unwrap_or(0);
+        Self::new_set_hardware(port, &[HW_CALIBRATION, value]).unwrap()
+    }
+
+    /// Host sets the transmit audio level as a percentage of the default.
+    pub fn new_set_tx_level(port: u8, percent: u8) -> Self {
+        Self::new_set_hardware(port, &[HW_TX_LEVEL, percent]).unwrap()
+    }
+
+    /// Host sets the number of 40 ms preamble frames at the start of each transmission.
+    pub fn new_set_preamble(port: u8, frames: u8) -> Self {
+        Self::new_set_hardware(port, &[HW_PREAMBLE, frames]).unwrap()
+    }
+
+    /// Host sets how far PTT should lead and lag the transmitted audio, in milliseconds.
+    pub fn new_set_ptt_timing(port: u8, lead_ms: u16, lag_ms: u16) -> Self {
+        let lead = lead_ms.to_be_bytes();
+        let lag = lag_ms.to_be_bytes();
+        Self::new_set_hardware(port, &[HW_PTT_TIMING, lead[0], lead[1], lag[0], lag[1]]).unwrap()
+    }
+
+    /// TNC reports the signal quality of the next frame it sends on this port.
+    pub fn new_signal_quality(port: u8, quality: &SignalQuality) -> Self {
+        let mut payload = [0u8; 1 + SignalQuality::LEN];
+        payload[0] = HW_SIGNAL_QUALITY;
+        payload[1..].copy_from_slice(&quality.to_bytes());
+        Self::new_set_hardware(port, &payload).unwrap()
+    }
+
+    /// TNC reports that it ended a stream transmission before the host sent end-of-stream.
+    pub fn new_stream_ended_early(port: u8, reason: StreamEndReason) -> Self {
+        Self::new_set_hardware(port, &[HW_STREAM_ENDED_EARLY, reason.proto_value()]).unwrap()
+    }
+
+    /// TNC reports that a stream it was receiving has ended without an end-of-stream frame.
+    pub fn new_stream_lost(port: u8, reason: StreamLostReason) -> Self {
+        Self::new_set_hardware(port, &[HW_STREAM_LOST, reason.proto_value()]).unwrap()
+    }
+
+    /// TNC reports that it gave up on receiving a packet partway through.
+    pub fn new_packet_discarded(
+        port: u8,
+        reason: PacketDiscardReason,
+        frames_received: u8,
+        lsf: &[u8],
+    ) -> Result<Self, KissError> {
+        if lsf.len() != 30 {
+            return Err(KissError::LsfWrongSize);
+        }
+        let mut payload = [0u8; 33];
+        payload[0] = HW_PACKET_DISCARDED;
+        payload[1] = reason.proto_value();
+        payload[2] = frames_received;
+        payload[3..33].copy_from_slice(lsf);
+        Self::new_set_hardware(port, &payload)
+    }
+
+    /// TNC reports that it is limiting transmissions.
+    pub fn new_tx_limit(port: u8, reason: TxLimitReason, seconds_remaining: u16) -> Self {
+        let secs = seconds_remaining.to_be_bytes();
+        Self::new_set_hardware(port, &[HW_TX_LIMIT, reason.proto_value(), secs[0], secs[1]])
+            .unwrap()
+    }
+
     /// Request to set full duplex or not
     pub fn set_full_duplex(port: u8, full_duplex: bool) -> Self {
         let mut data = [0u8; MAX_FRAME_LEN];
@@ -257,7 +511,10 @@ pub enum KissCommand {
     DataFrame,
     TxDelay,
     P,
+    SlotTime,
+    TxTail,
     FullDuplex,
+    SetHardware,
 }
 
 impl KissCommand {
@@ -266,7 +523,10 @@ impl KissCommand {
             0 => KissCommand::DataFrame,
             1 => KissCommand::TxDelay,
             2 => KissCommand::P,
+            3 => KissCommand::SlotTime,
+            4 => KissCommand::TxTail,
             5 => KissCommand::FullDuplex,
+            6 => KissCommand::SetHardware,
             _ => return Err(KissError::UnsupportedKissCommand),
         })
     }
@@ -276,8 +536,192 @@ impl KissCommand {
             KissCommand::DataFrame => 0,
             KissCommand::TxDelay => 1,
             KissCommand::P => 2,
+            KissCommand::SlotTime => 3,
+            KissCommand::TxTail => 4,
             KissCommand::FullDuplex => 5,
+            KissCommand::SetHardware => 6,
+        }
+    }
+}
+
+/// Why the TNC is limiting transmissions, reported via `HW_TX_LIMIT`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TxLimitReason {
+    /// A transmission ran longer than the time-out timer and was cut short.
+    TimeOut,
+    /// Transmitting would exceed the configured duty cycle.
+    DutyCycle,
+    /// Restrictions have lifted and the TNC may transmit again.
+    Cleared,
+}
+
+impl TxLimitReason {
+    pub fn from_proto(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => TxLimitReason::TimeOut,
+            1 => TxLimitReason::DutyCycle,
+            2 => TxLimitReason::Cleared,
+            _ => return None,
+        })
+    }
+
+    pub fn proto_value(&self) -> u8 {
+        match self {
+            TxLimitReason::TimeOut => 0,
+            TxLimitReason::DutyCycle => 1,
+            TxLimitReason::Cleared => 2,
+        }
+    }
+}
+
+/// Why the TNC ended a stream early, reported via `HW_STREAM_ENDED_EARLY`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StreamEndReason {
+    /// The host did not supply stream data in time and the grace period ran out.
+    Underrun,
+    /// The time-out timer or duty cycle limit cut the transmission short.
+    TxLimit,
+}
+
+impl StreamEndReason {
+    pub fn from_proto(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => StreamEndReason::Underrun,
+            1 => StreamEndReason::TxLimit,
+            _ => return None,
+        })
+    }
+
+    pub fn proto_value(&self) -> u8 {
+        match self {
+            StreamEndReason::Underrun => 0,
+            StreamEndReason::TxLimit => 1,
+        }
+    }
+}
+
+/// Why the TNC stopped receiving a stream, reported via `HW_STREAM_LOST`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StreamLostReason {
+    /// No further frames arrived in time.
+    Timeout,
+    /// A frame arrived whose timing shows it came from a different transmission.
+    ForeignFrame,
+    /// Frame numbers jumped backwards, so a new transmission must have started without an LSF.
+    Restarted,
+    /// Something else started, e.g., a new LSF, a packet or our own transmission.
+    Interrupted,
+}
+
+impl StreamLostReason {
+    pub fn from_proto(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => StreamLostReason::Timeout,
+            1 => StreamLostReason::ForeignFrame,
+            2 => StreamLostReason::Restarted,
+            3 => StreamLostReason::Interrupted,
+            _ => return None,
+        })
+    }
+
+    pub fn proto_value(&self) -> u8 {
+        match self {
+            StreamLostReason::Timeout => 0,
+            StreamLostReason::ForeignFrame => 1,
+            StreamLostReason::Restarted => 2,
+            StreamLostReason::Interrupted => 3,
+        }
+    }
+}
+
+/// Why the TNC discarded a partly received packet, reported via `HW_PACKET_DISCARDED`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketDiscardReason {
+    /// No further frames arrived in time.
+    Timeout,
+    /// A frame arrived whose timing shows it came from a different transmission.
+    ForeignFrame,
+    /// Frames arrived out of order, so some were missed.
+    BadSequence,
+    /// Something else started before the packet finished, e.g., a new LSF or our own transmission.
+    Interrupted,
+    /// The packet's LSF failed its CRC. The LSF is passed on as received, so it may be garbled.
+    BadLsf,
+}
+
+impl PacketDiscardReason {
+    pub fn from_proto(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => PacketDiscardReason::Timeout,
+            1 => PacketDiscardReason::ForeignFrame,
+            2 => PacketDiscardReason::BadSequence,
+            3 => PacketDiscardReason::Interrupted,
+            4 => PacketDiscardReason::BadLsf,
+            _ => return None,
+        })
+    }
+
+    pub fn proto_value(&self) -> u8 {
+        match self {
+            PacketDiscardReason::Timeout => 0,
+            PacketDiscardReason::ForeignFrame => 1,
+            PacketDiscardReason::BadSequence => 2,
+            PacketDiscardReason::Interrupted => 3,
+            PacketDiscardReason::BadLsf => 4,
+        }
+    }
+}
+
+/// Occupancy of the TNC's transmit queues, reported via `HW_QUEUE_STATUS`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct QueueStatus {
+    /// Number of packets waiting to be transmitted, including any partly-transmitted packet.
+    pub packets_queued: u16,
+    /// Maximum number of packets the TNC can hold.
+    pub packet_capacity: u16,
+    /// Number of packets rejected since startup because the queue was full. Wraps around.
+    pub packets_dropped: u16,
+    /// Number of stream frames waiting to be transmitted.
+    pub stream_frames_queued: u16,
+    /// Maximum number of stream frames the TNC can hold.
+    pub stream_capacity: u16,
+    /// Number of stream frames discarded since startup because the queue was full. Wraps around.
+    pub stream_frames_dropped: u16,
+}
+
+impl QueueStatus {
+    /// Encoded length, not including the sub-command byte.
+    pub const LEN: usize = 12;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        for (chunk, value) in out.chunks_mut(2).zip([
+            self.packets_queued,
+            self.packet_capacity,
+            self.packets_dropped,
+            self.stream_frames_queued,
+            self.stream_capacity,
+            self.stream_frames_dropped,
+        ]) {
+            chunk.copy_from_slice(&value.to_be_bytes());
         }
+        out
+    }
+
+    /// Parse from the bytes following the `HW_QUEUE_STATUS` sub-command.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::LEN {
+            return None;
+        }
+        let field = |i: usize| u16::from_be_bytes([buf[i * 2], buf[i * 2 + 1]]);
+        Some(Self {
+            packets_queued: field(0),
+            packet_capacity: field(1),
+            packets_dropped: field(2),
+            stream_frames_queued: field(3),
+            stream_capacity: field(4),
+            stream_frames_dropped: field(5),
+        })
     }
 }
 
@@ -608,4 +1052,24 @@ mod tests {
         assert_eq!(next.port().unwrap(), 1);
         assert!(buffer.next_frame().is_none());
     }
+
+    #[test]
+    fn queue_status_roundtrip() {
+        let status = QueueStatus {
+            packets_queued: 3,
+            packet_capacity: 16,
+            packets_dropped: 1,
+            stream_frames_queued: 300,
+            stream_capacity: 512,
+            stream_frames_dropped: 0xC0DB,
+        };
+        let f = KissFrame::new_queue_status(PORT_STREAM, &status);
+        assert_eq!(f.command().unwrap(), KissCommand::SetHardware);
+        assert_eq!(f.port().unwrap(), PORT_STREAM);
+        let mut buf = [0u8; 1024];
+        let n = f.decode_payload(&mut buf).unwrap();
+        assert_eq!(n, 1 + QueueStatus::LEN);
+        assert_eq!(buf[0], HW_QUEUE_STATUS);
+        assert_eq!(QueueStatus::from_bytes(&buf[1..n]), Some(status));
+    }
 }
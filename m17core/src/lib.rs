@@ -8,6 +8,7 @@ pub mod kiss;
 pub mod modem;
 pub mod protocol;
 pub mod reflector;
+pub mod simulation;
 pub mod tnc;
 
 mod bits;
@@ -15,5 +16,7 @@ mod decode;
 mod encode;
 mod fec;
 mod interleave;
+mod prng;
 mod random;
+mod ring;
 mod shaping;
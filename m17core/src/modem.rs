@@ -1,58 +1,551 @@
 use crate::decode::{
-    SYNC_THRESHOLD, SyncBurst, parse_lsf, parse_packet, parse_stream, sync_burst_correlation,
+    SYNC_BIT_THRESHOLD, SYNC_THRESHOLD, SyncBurst, parse_lsf, parse_packet, parse_stream,
+    sync_burst_correlation, sync_burst_normalise,
 };
+#[cfg(feature = "fixed-point")]
+use crate::decode::{sync_burst_correlation_q8, sync_burst_normalise_q8};
 use crate::encode::{
-    encode_lsf, encode_packet, encode_stream, generate_end_of_transmission, generate_preamble,
+    encode_lsf, encode_packet, encode_stream, generate_calibration, generate_end_of_transmission,
+    generate_preamble,
 };
+use crate::prng::Prng;
 use crate::protocol::{Frame, LsfFrame, PacketFrame, StreamFrame};
-use crate::shaping::RRC_48K;
+use crate::shaping::{RRC_48K, TX_SYMBOL_SCALE};
+#[cfg(feature = "fixed-point")]
+use crate::shaping::{RRC_48K_Q13, RRC_48K_TX_Q8};
+use core::ops::Neg;
 use log::debug;
 
+/// Rate of the baseband audio that is modulated and demodulated, in samples per second.
+///
+/// TODO: Stop assuming 48 kHz everywhere. 24 kHz should be fine too.
+pub const SAMPLE_RATE: usize = 48000;
+
+/// Number of baseband samples in one millisecond.
+pub const SAMPLES_PER_MS: usize = SAMPLE_RATE / 1000;
+
+/// Number of baseband samples in each 4800 baud symbol.
+pub const SAMPLES_PER_SYMBOL: usize = SAMPLE_RATE / 4800;
+
+/// Number of baseband samples in one 40 ms frame of 192 symbols.
+pub const SAMPLES_PER_FRAME: usize = 192 * SAMPLES_PER_SYMBOL;
+
 pub trait Demodulator {
     /// Handle the next sample.
     ///
-    /// If a frame can be decoded, return it, along with an indication of many errors were fixed by FEC.
-    fn demod(&mut self, sample: i16) -> Option<(Frame, u8)>;
+    /// If a frame can be decoded, return it, along with an indication of how cleanly it was received.
+    fn demod(&mut self, sample: i16) -> Option<(Frame, SignalQuality)>;
+
+    /// Handle a block of samples.
+    ///
+    /// `on_frame` is called for each decoded frame with the index of the sample in `samples`
+    /// which completed it.
+    fn demod_block(
+        &mut self,
+        samples: &[i16],
+        on_frame: &mut dyn FnMut(usize, Frame, SignalQuality),
+    ) {
+        for (idx, sample) in samples.iter().enumerate() {
+            if let Some((frame, quality)) = self.demod(*sample) {
+                on_frame(idx, frame, quality);
+            }
+        }
+    }
+
     /// Does somebody else appear to be transmitting at the moment?
     fn data_carrier_detect(&self) -> bool;
 }
 
+/// Measurements of how well a frame was received.
+///
+/// When several frames are combined, e.g., for a whole packet, this describes the worst of them
+/// and the total number of errors.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SignalQuality {
+    /// Estimated signal to noise ratio in dB, based on how far received symbols strayed from their
+    /// ideal values.
+    pub snr_db: f32,
+    /// Correlation distance of the sync burst. Zero is a perfect match; higher is worse.
+    pub sync_diff: f32,
+    /// Number of bit errors corrected by the Viterbi decoder.
+    pub viterbi_errors: u16,
+    /// Number of symbols that were received as the wrong value.
+    pub symbol_errors: u16,
+}
+
+impl SignalQuality {
+    /// Size of the encoded form in bytes.
+    pub const LEN: usize = 8;
+
+    /// Fold in the quality of another frame from the same transmission.
+    pub fn combine(&mut self, other: &SignalQuality) {
+        self.snr_db = self.snr_db.min(other.snr_db);
+        self.sync_diff = self.sync_diff.max(other.sync_diff);
+        self.viterbi_errors = self.viterbi_errors.saturating_add(other.viterbi_errors);
+        self.symbol_errors = self.symbol_errors.saturating_add(other.symbol_errors);
+    }
+
+    /// Encode as big-endian SNR in tenths of a dB (i16), sync distance in hundredths (u16),
+    /// Viterbi errors (u16) and symbol errors (u16).
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        let snr = (self.snr_db * 10.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        let sync = (self.sync_diff * 100.0).clamp(0.0, u16::MAX as f32) as u16;
+        out[0..2].copy_from_slice(&snr.to_be_bytes());
+        out[2..4].copy_from_slice(&sync.to_be_bytes());
+        out[4..6].copy_from_slice(&self.viterbi_errors.to_be_bytes());
+        out[6..8].copy_from_slice(&self.symbol_errors.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::LEN {
+            return None;
+        }
+        Some(Self {
+            snr_db: i16::from_be_bytes([buf[0], buf[1]]) as f32 / 10.0,
+            sync_diff: u16::from_be_bytes([buf[2], buf[3]]) as f32 / 100.0,
+            viterbi_errors: u16::from_be_bytes([buf[4], buf[5]]),
+            symbol_errors: u16::from_be_bytes([buf[6], buf[7]]),
+        })
+    }
+}
+
+/// Compare received symbols against the ideal ones regenerated from the decoded frame.
+fn measure_quality<S: DemodSample>(
+    received: &[S; 192],
+    ideal: &[i8; 192],
+    sync_diff: f32,
+    viterbi_errors: u8,
+) -> SignalQuality {
+    let symbol_errors = received
+        .iter()
+        .zip(ideal.iter())
+        .filter(|(r, i)| r.level() != **i)
+        .count() as u16;
+    let snr_db = match S::signal_to_noise(received, ideal) {
+        Some(ratio) => ratio_to_db(ratio).min(99.0),
+        None => 99.0,
+    };
+    SignalQuality {
+        snr_db,
+        sync_diff,
+        viterbi_errors: viterbi_errors as u16,
+        symbol_errors,
+    }
+}
+
+/// Approximate `10 * log10(ratio)` without relying on std.
+fn ratio_to_db(ratio: f32) -> f32 {
+    let bits = ratio.to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127;
+    // Mantissa scaled into [1, 2), where ln(m) = 2 * atanh((m - 1) / (m + 1)) converges quickly
+    let m = f32::from_bits((bits & 0x007f_ffff) | 0x3f80_0000);
+    let t = (m - 1.0) / (m + 1.0);
+    let t2 = t * t;
+    let ln_m = 2.0 * t * (1.0 + t2 * (1.0 / 3.0 + t2 * (1.0 / 5.0 + t2 / 7.0)));
+    // 10 * log10(2) and 10 * log10(e)
+    exponent as f32 * 3.010_3 + ln_m * 4.342_945
+}
+
+/// Running totals of what a `SoftDemodulator` has seen since it was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DemodulatorStats {
+    /// Input samples processed.
+    pub samples: u64,
+    /// Sync bursts found for which we attempted to decode a frame, by type.
+    pub lsf_bursts: u32,
+    pub bert_bursts: u32,
+    pub stream_bursts: u32,
+    pub packet_bursts: u32,
+    /// Frames successfully decoded.
+    pub frames_decoded: u32,
+    /// Frames that had a valid sync burst but could not be decoded, e.g., too many FEC errors.
+    pub frames_failed: u32,
+    /// Number of samples during which data carrier was detected.
+    pub dcd_samples: u64,
+    /// Whether received frames currently appear to have inverted polarity.
+    pub inverted: bool,
+    /// Number of times automatic detection has switched polarity.
+    pub polarity_changes: u32,
+}
+
+/// Filtered samples making up a decoded frame, as seen by a `SoftDemodulator`.
+///
+/// Samples are normalised by the same gain and offset that were used to decode the frame, so
+/// ideal symbols are at -1, -1/3, +1/3 and +1. Plotting them shows the distribution of the four
+/// levels and the eye diagram.
+pub struct FrameCapture {
+    /// Value of `SoftDemodulator::sample_count()` when the frame was decoded.
+    pub sample: u64,
+    /// Filtered samples per symbol, which is `SAMPLES_PER_SYMBOL` divided by the decimation.
+    pub samples_per_symbol: usize,
+    /// Normalised samples, oldest first. Only the first `192 * samples_per_symbol` are used.
+    pub samples: [f32; SAMPLES_PER_FRAME],
+}
+
+impl FrameCapture {
+    pub fn new() -> Self {
+        Self {
+            sample: 0,
+            samples_per_symbol: SAMPLES_PER_SYMBOL,
+            samples: [0f32; SAMPLES_PER_FRAME],
+        }
+    }
+
+    /// Every filtered sample covering the frame.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples[0..192 * self.samples_per_symbol]
+    }
+
+    /// The value of each of the 192 symbols at the point it was sampled for decoding.
+    ///
+    /// The symbol for sample index `i` is `i / samples_per_symbol`, and it was sampled at the
+    /// last index belonging to it.
+    pub fn symbols(&self) -> impl Iterator<Item = f32> + '_ {
+        self.samples()
+            .iter()
+            .skip(self.samples_per_symbol - 1)
+            .step_by(self.samples_per_symbol)
+            .copied()
+    }
+}
+
+impl Default for FrameCapture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which polarity a `SoftDemodulator` expects its input to have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RxPolarity {
+    /// Decode frames of either polarity, preferring whichever was most recently received.
+    #[default]
+    Auto,
+    /// Only decode frames with positive deviation for the +3 symbol.
+    Normal,
+    /// Only decode frames with inverted deviation, as produced by some radios' discriminators.
+    Inverted,
+}
+
+/// Arithmetic used by `SoftDemodulator` to filter samples and search them for sync bursts.
+///
+/// This is implemented for `f32`, and for `i32` when the `fixed-point` feature is enabled. Each
+/// decoded frame's `SignalQuality` and `FrameCapture` are always expressed in floating point.
+pub trait DemodSample: Copy + Default + PartialOrd + Neg<Output = Self> {
+    /// Correlation distance below which a sync burst is considered present.
+    const SYNC_THRESHOLD: Self;
+    /// Correlation distance when no sync burst could be present at all.
+    const NO_MATCH: Self;
+
+    /// Convert an input sample.
+    fn from_input(sample: i16) -> Self;
+
+    /// Apply the RRC filter to the most recent 81 input samples, oldest first.
+    fn filter(window: &[Self]) -> Self;
+
+    /// Tolerance for each symbol of a sync burst when filtering every `decimation`th sample.
+    fn sync_bit_threshold(decimation: usize) -> Self;
+
+    /// Normalise 8 filtered symbols, returning them along with the gain and shift removed.
+    fn sync_burst_normalise(window: &[Self; 8]) -> Option<([Self; 8], Self, Self)>;
+
+    /// How far normalised symbols are from a sync burst. Lower is better.
+    fn sync_burst_correlation(target: [i8; 8], normalised: &[Self; 8], bit_threshold: Self)
+    -> Self;
+
+    /// Normalise a filtered sample for frame decoding, so that symbols are nominally +/-1/3 or 1.
+    fn symbol(self, gain: Self, shift: Self) -> Self;
+
+    /// The nearest symbol level to a normalised sample: -3, -1, +1 or +3.
+    fn level(self) -> i8;
+
+    /// Ratio of the power of the ideal symbols to that of their difference from the normalised
+    /// samples that were received, or `None` if they match exactly.
+    fn signal_to_noise(received: &[Self; 192], ideal: &[i8; 192]) -> Option<f32>;
+
+    /// Express a normalised sample or correlation distance in the same units as the floating
+    /// point implementation.
+    fn to_f32(self) -> f32;
+}
+
+impl DemodSample for f32 {
+    const SYNC_THRESHOLD: f32 = SYNC_THRESHOLD;
+    const NO_MATCH: f32 = f32::MAX;
+
+    fn from_input(sample: i16) -> f32 {
+        sample as f32
+    }
+
+    fn filter(window: &[f32]) -> f32 {
+        RRC_48K.iter().zip(window).map(|(r, s)| r * s).sum()
+    }
+
+    fn sync_bit_threshold(decimation: usize) -> f32 {
+        // Being one sample off the peak adds about 0.3, two samples about 0.6
+        SYNC_BIT_THRESHOLD + 0.15 * (decimation - 1) as f32
+    }
+
+    fn sync_burst_normalise(window: &[f32; 8]) -> Option<([f32; 8], f32, f32)> {
+        sync_burst_normalise(window)
+    }
+
+    fn sync_burst_correlation(target: [i8; 8], normalised: &[f32; 8], bit_threshold: f32) -> f32 {
+        sync_burst_correlation(target, normalised, bit_threshold)
+    }
+
+    fn symbol(self, gain: f32, shift: f32) -> f32 {
+        (self - shift) / gain
+    }
+
+    fn level(self) -> i8 {
+        if self > 0.667 {
+            3
+        } else if self > 0.0 {
+            1
+        } else if self > -0.667 {
+            -1
+        } else {
+            -3
+        }
+    }
+
+    fn signal_to_noise(received: &[f32; 192], ideal: &[i8; 192]) -> Option<f32> {
+        let mut signal = 0.0;
+        let mut noise = 0.0;
+        for (r, i) in received.iter().zip(ideal.iter()) {
+            let i = *i as f32 / 3.0;
+            signal += i * i;
+            noise += (r - i) * (r - i);
+        }
+        (noise > 0.0).then(|| signal / noise)
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+/// Filtered samples are on the same scale as `f32`, while normalised symbols and correlation
+/// distances are in Q8.
+#[cfg(feature = "fixed-point")]
+impl DemodSample for i32 {
+    const SYNC_THRESHOLD: i32 = SYNC_THRESHOLD as i32 * 256;
+    const NO_MATCH: i32 = i32::MAX;
+
+    fn from_input(sample: i16) -> i32 {
+        sample as i32
+    }
+
+    fn filter(window: &[i32]) -> i32 {
+        let sum: i32 = RRC_48K_Q13.iter().zip(window).map(|(r, s)| r * s).sum();
+        sum >> 13
+    }
+
+    fn sync_bit_threshold(decimation: usize) -> i32 {
+        (<f32 as DemodSample>::sync_bit_threshold(decimation) * 256.0) as i32
+    }
+
+    fn sync_burst_normalise(window: &[i32; 8]) -> Option<([i32; 8], i32, i32)> {
+        sync_burst_normalise_q8(window)
+    }
+
+    fn sync_burst_correlation(target: [i8; 8], normalised: &[i32; 8], bit_threshold: i32) -> i32 {
+        sync_burst_correlation_q8(target, normalised, bit_threshold)
+    }
+
+    fn symbol(self, gain: i32, shift: i32) -> i32 {
+        (self - shift) * 256 / gain
+    }
+
+    fn level(self) -> i8 {
+        // 2/3 in Q8 is 170.67
+        if self > 170 {
+            3
+        } else if self > 0 {
+            1
+        } else if self > -171 {
+            -1
+        } else {
+            -3
+        }
+    }
+
+    fn signal_to_noise(received: &[i32; 192], ideal: &[i8; 192]) -> Option<f32> {
+        // Work in thirds of Q8 so that the ideal levels are whole numbers
+        let mut signal = 0i64;
+        let mut noise = 0i64;
+        for (r, i) in received.iter().zip(ideal.iter()) {
+            let i = *i as i64 * 256;
+            let r = *r as i64 * 3;
+            signal += i * i;
+            noise += (r - i) * (r - i);
+        }
+        (noise > 0).then(|| signal as f32 / noise as f32)
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32 / 256.0
+    }
+}
+
+/// A `SoftDemodulator` which uses only integer arithmetic while searching for frames.
+#[cfg(feature = "fixed-point")]
+pub type FixedDemodulator = SoftDemodulator<i32>;
+
 /// Converts a sequence of samples into frames.
-pub struct SoftDemodulator {
-    /// Circular buffer of incoming samples for calculating the RRC filtered value
-    filter_win: [i16; 81],
+pub struct SoftDemodulator<S: DemodSample = f32> {
+    /// Incoming samples for calculating the RRC filtered value, written twice so that the most
+    /// recent 81 are always available as a contiguous slice starting at `filter_cursor`
+    filter_win: [S; 162],
     /// Current position in filter_win
     filter_cursor: usize,
+    /// Only every nth input sample is filtered and searched for sync bursts
+    decimation: usize,
+    /// Filtered samples per symbol after decimation
+    sps: usize,
+    /// Tolerance for each symbol of a sync burst, which must be looser when we can't sample
+    /// exactly at the ideal point
+    sync_bit_threshold: S,
     /// Circular buffer of shaped samples for performing decodes based on the last 192 symbols
-    rx_win: [f32; 1920],
+    ///
+    /// Only the first `192 * sps` entries are used.
+    rx_win: [S; SAMPLES_PER_FRAME],
     /// Current position in rx_cursor
     rx_cursor: usize,
     /// A position that we are considering decoding due to decent sync
-    candidate: Option<DecodeCandidate>,
+    candidate: Option<DecodeCandidate<S>>,
+    /// Sample count, gain, shift and polarity of the most recently decoded frame
+    last_decode: Option<(u64, S, S, bool)>,
     /// How many samples have we received?
     sample: u64,
-    /// Remaining samples to read in before attempting to decode the current candidate
+    /// Remaining filtered samples to read in before attempting to decode the current candidate
     samples_until_decode: Option<u16>,
     /// Do we think there is a data carrier, i.e., channel in use? If so, at what sample does it expire?
     dcd: Option<u64>,
+    /// Which polarities we are prepared to decode
+    polarity: RxPolarity,
+    /// Counters for diagnostics, including the polarity we are currently locked to
+    stats: DemodulatorStats,
 }
 
 impl SoftDemodulator {
     pub fn new() -> Self {
+        Self::new_decimating(1)
+    }
+
+    /// Create a demodulator that filters and searches only every `decimation`th input sample.
+    ///
+    /// This reduces CPU usage roughly in proportion, at the cost of coarser symbol timing. Input
+    /// is still 48 kHz; a decimation of 2 processes it at 24 kHz.
+    ///
+    /// Coarser timing means fewer frames decode once there is noise. In the `demod` benchmark,
+    /// which has moderate noise, decimations of 1, 2 and 5 decode 130, 124 and 93 of 131 frames.
+    /// `m17rt-fer` measures the difference under other conditions.
+    ///
+    /// # Panics
+    ///
+    /// `decimation` must be 1, 2 or 5, so that there is a whole number of samples per symbol.
+    pub fn new_decimating(decimation: usize) -> Self {
+        Self::with_decimation(decimation)
+    }
+}
+
+#[cfg(feature = "fixed-point")]
+impl SoftDemodulator<i32> {
+    /// Create a demodulator which filters and searches for frames using only integer arithmetic.
+    ///
+    /// It decodes the same frames as `new()` under all but marginal conditions.
+    pub fn new_fixed_point() -> Self {
+        Self::new_fixed_point_decimating(1)
+    }
+
+    /// Fixed point equivalent of `SoftDemodulator::new_decimating()`.
+    ///
+    /// # Panics
+    ///
+    /// `decimation` must be 1, 2 or 5.
+    pub fn new_fixed_point_decimating(decimation: usize) -> Self {
+        Self::with_decimation(decimation)
+    }
+}
+
+impl<S: DemodSample> SoftDemodulator<S> {
+    fn with_decimation(decimation: usize) -> Self {
+        assert!(
+            matches!(decimation, 1 | 2 | 5),
+            "unsupported decimation {decimation}"
+        );
         SoftDemodulator {
-            filter_win: [0i16; 81],
+            filter_win: [S::default(); 162],
             filter_cursor: 0,
-            rx_win: [0f32; 1920],
+            decimation,
+            sps: SAMPLES_PER_SYMBOL / decimation,
+            sync_bit_threshold: S::sync_bit_threshold(decimation),
+            rx_win: [S::default(); SAMPLES_PER_FRAME],
             rx_cursor: 0,
             candidate: None,
+            last_decode: None,
             sample: 0,
             samples_until_decode: None,
             dcd: None,
+            polarity: RxPolarity::Auto,
+            stats: DemodulatorStats::default(),
         }
     }
 }
 
-impl SoftDemodulator {
+impl<S: DemodSample> SoftDemodulator<S> {
+    /// Number of samples processed so far. When a frame is returned from `demod()`, this gives a
+    /// timestamp that is consistent between frames of the same transmission.
+    pub fn sample_count(&self) -> u64 {
+        self.sample
+    }
+
+    /// Counters describing everything demodulated so far.
+    pub fn stats(&self) -> DemodulatorStats {
+        DemodulatorStats {
+            samples: self.sample,
+            ..self.stats
+        }
+    }
+
+    /// Change the decimation, as in `new_decimating()`.
+    ///
+    /// The sample count, statistics, polarity setting and DCD are kept, but any frame in the
+    /// middle of being received is lost.
+    ///
+    /// # Panics
+    ///
+    /// `decimation` must be 1, 2 or 5.
+    pub fn set_decimation(&mut self, decimation: usize) {
+        *self = Self {
+            sample: self.sample,
+            dcd: self.dcd,
+            polarity: self.polarity,
+            stats: self.stats,
+            ..Self::with_decimation(decimation)
+        };
+    }
+
+    /// Choose which polarities to decode. The default is `RxPolarity::Auto`.
+    pub fn set_polarity(&mut self, polarity: RxPolarity) {
+        self.polarity = polarity;
+        match polarity {
+            RxPolarity::Auto => {}
+            RxPolarity::Normal => self.stats.inverted = false,
+            RxPolarity::Inverted => self.stats.inverted = true,
+        }
+    }
+
+    /// Whether we are currently decoding frames as inverted.
+    ///
+    /// With `RxPolarity::Auto` this follows the most recently decoded frame.
+    pub fn inverted(&self) -> bool {
+        self.stats.inverted
+    }
+
     fn dcd_until(&mut self, end_sample: u64) {
         if self.dcd.is_none() {
             debug!("SoftDemodulator DCD on");
@@ -61,30 +554,90 @@ impl SoftDemodulator {
     }
 
     fn check_dcd(&mut self) {
-        if let Some(end_sample) = self.dcd {
-            if self.sample > end_sample {
-                self.dcd = None;
-                debug!("SoftDemodulator DCD off");
-            }
+        if let Some(end_sample) = self.dcd
+            && self.sample > end_sample
+        {
+            self.dcd = None;
+            debug!("SoftDemodulator DCD off");
         }
     }
-}
 
-impl Demodulator for SoftDemodulator {
-    fn demod(&mut self, sample: i16) -> Option<(Frame, u8)> {
-        self.filter_win[self.filter_cursor] = sample;
-        self.filter_cursor = (self.filter_cursor + 1) % 81;
-        let mut out: f32 = 0.0;
-        for i in 0..81 {
-            let filter_idx = (self.filter_cursor + i) % 81;
-            out += RRC_48K[i] * self.filter_win[filter_idx] as f32;
+    /// Copy out the demodulator's view of the frame that was just returned from `demod()`.
+    ///
+    /// Returns false if the most recent sample did not complete a frame.
+    pub fn capture_frame(&self, capture: &mut FrameCapture) -> bool {
+        let Some((sample, gain, shift, inverted)) = self.last_decode else {
+            return false;
+        };
+        if sample != self.sample {
+            return false;
+        }
+        let rx_len = 192 * self.sps;
+        capture.sample = sample;
+        capture.samples_per_symbol = self.sps;
+        for i in 0..rx_len {
+            let value = self.rx_win[(self.rx_cursor + i) % rx_len]
+                .symbol(gain, shift)
+                .to_f32();
+            capture.samples[i] = if inverted { -value } else { value };
+        }
+        true
+    }
+
+    /// Which type of burst `burst` really is, given the polarity we are locked to.
+    fn burst_as_received(&self, burst: SyncBurst) -> SyncBurst {
+        if self.stats.inverted {
+            burst.inverted()
+        } else {
+            burst
         }
+    }
 
-        self.rx_win[self.rx_cursor] = out;
-        self.rx_cursor = (self.rx_cursor + 1) % 1920;
+    fn count_burst(&mut self, burst: SyncBurst) {
+        match burst {
+            SyncBurst::Lsf => self.stats.lsf_bursts += 1,
+            SyncBurst::Bert => self.stats.bert_bursts += 1,
+            SyncBurst::Stream => self.stats.stream_bursts += 1,
+            SyncBurst::Packet => self.stats.packet_bursts += 1,
+            SyncBurst::Preamble | SyncBurst::EndOfTransmission => {}
+        }
+    }
+
+    /// Take in the next input sample. Returns true if it should be filtered and processed.
+    fn push_sample(&mut self, sample: i16) -> bool {
+        self.store_input(sample);
+        self.advance()
+    }
+
+    /// Add an input sample to the filter window.
+    fn store_input(&mut self, sample: i16) {
+        let sample = S::from_input(sample);
+        self.filter_win[self.filter_cursor] = sample;
+        self.filter_win[self.filter_cursor + 81] = sample;
+        self.filter_cursor = (self.filter_cursor + 1) % 81;
+    }
 
+    /// Count the next input sample. Returns true if it should be filtered and processed.
+    fn advance(&mut self) -> bool {
         self.sample += 1;
         self.check_dcd();
+        if self.dcd.is_some() {
+            self.stats.dcd_samples += 1;
+        }
+        self.sample.is_multiple_of(self.decimation as u64)
+    }
+
+    /// Filter the most recent input and look for frames.
+    fn process(&mut self) -> Option<(Frame, SignalQuality)> {
+        let out = S::filter(&self.filter_win[self.filter_cursor..self.filter_cursor + 81]);
+        self.process_filtered(out)
+    }
+
+    /// Look for frames given the filtered value of the most recent input.
+    fn process_filtered(&mut self, out: S) -> Option<(Frame, SignalQuality)> {
+        let rx_len = 192 * self.sps;
+        self.rx_win[self.rx_cursor] = out;
+        self.rx_cursor = (self.rx_cursor + 1) % rx_len;
 
         if let Some(samples_until_decode) = self.samples_until_decode {
             let sud = samples_until_decode - 1;
@@ -95,53 +648,76 @@ impl Demodulator for SoftDemodulator {
             self.samples_until_decode = None;
 
             if let Some(c) = self.candidate.take() {
-                // we have capacity for 192 symbols * 10 upsamples
+                // we have capacity for 192 symbols * sps
                 // we have calculated that the ideal sample point for 192nd symbol is right on the edge
-                // so take samples from the 10th slot all the way through.
-                let start_idx = self.rx_cursor + 1920 + 9;
-                let mut pkt_samples = [0f32; 192];
+                // so take samples from the last slot of the first symbol all the way through.
+                let start_idx = self.rx_cursor + rx_len + self.sps - 1;
+                let mut pkt_samples = [S::default(); 192];
                 for i in 0..192 {
-                    let rx_idx = (start_idx + i * 10) % 1920;
-                    pkt_samples[i] = (self.rx_win[rx_idx] - c.shift) / c.gain;
+                    let rx_idx = (start_idx + i * self.sps) % rx_len;
+                    pkt_samples[i] = self.rx_win[rx_idx].symbol(c.gain, c.shift);
                 }
-                match c.burst {
-                    SyncBurst::Lsf => {
-                        if let Some((frame, errors)) = parse_lsf(&pkt_samples) {
-                            return Some((Frame::Lsf(frame), errors));
-                        }
-                    }
-                    SyncBurst::Bert => {
-                        // TODO: BERT
-                    }
-                    SyncBurst::Stream => {
-                        if let Some((frame, errors)) = parse_stream(&pkt_samples) {
-                            return Some((Frame::Stream(frame), errors));
+                let sync_diff = c.diff.to_f32();
+                // Bursts were identified as if the polarity is normal. Try the polarity we are
+                // locked to first, then if we're allowed, see if it makes sense the other way.
+                let attempts: &[bool] = match (self.polarity, self.stats.inverted) {
+                    (RxPolarity::Auto, inverted) => &[inverted, !inverted],
+                    (_, inverted) => &[inverted][..],
+                };
+                for inverted in attempts {
+                    let (burst, symbols) = if *inverted {
+                        (c.burst.inverted(), pkt_samples.map(|s| -s))
+                    } else {
+                        (c.burst, pkt_samples)
+                    };
+                    if let Some(decoded) = decode_frame(burst, &symbols, sync_diff) {
+                        if *inverted != self.stats.inverted {
+                            debug!("SoftDemodulator polarity inverted: {inverted}");
+                            self.stats.inverted = *inverted;
+                            self.stats.polarity_changes += 1;
                         }
-                    }
-                    SyncBurst::Packet => {
-                        if let Some((frame, errors)) = parse_packet(&pkt_samples) {
-                            return Some((Frame::Packet(frame), errors));
-                        }
-                    }
-                    SyncBurst::Preamble | SyncBurst::EndOfTransmission => {
-                        // should never be chosen as a candidate
+                        self.count_burst(burst);
+                        self.stats.frames_decoded += 1;
+                        self.last_decode = Some((self.sample, c.gain, c.shift, *inverted));
+                        return Some(decoded);
                     }
                 }
+                let burst = self.burst_as_received(c.burst);
+                self.count_burst(burst);
+                if matches!(
+                    burst,
+                    SyncBurst::Lsf | SyncBurst::Stream | SyncBurst::Packet
+                ) {
+                    self.stats.frames_failed += 1;
+                }
             }
         }
 
-        let mut burst_window = [0f32; 8];
+        let mut burst_window = [S::default(); 8];
         for i in 0..8 {
-            let c = (self.rx_cursor + 1920 - 1 - ((7 - i) * 10)) % 1920;
+            let c = (self.rx_cursor + rx_len - 1 - ((7 - i) * self.sps)) % rx_len;
             burst_window[i] = self.rx_win[c];
         }
+        // Gain and shift are the same for every burst type so only work them out once
+        let normalised = S::sync_burst_normalise(&burst_window);
 
-        for burst in [SyncBurst::Preamble, SyncBurst::EndOfTransmission] {
-            let (diff, _, _) = sync_burst_correlation(burst.target(), &burst_window);
-            if diff < SYNC_THRESHOLD {
-                // arbitrary choice, 240 samples = 5ms
-                // these bursts keep repeating so it will keep pushing out the DCD end time
-                self.dcd_until(self.sample + 240);
+        if let Some((symbols, _, _)) = &normalised {
+            let preamble = SyncBurst::Preamble.target();
+            let eot = SyncBurst::EndOfTransmission.target();
+            let inverted_eot = eot.map(|t| -t);
+            let dcd_targets: &[[i8; 8]] = match self.polarity {
+                RxPolarity::Auto => &[preamble, eot, inverted_eot],
+                RxPolarity::Normal => &[preamble, eot],
+                RxPolarity::Inverted => &[preamble, inverted_eot],
+            };
+            for target in dcd_targets {
+                if S::sync_burst_correlation(*target, symbols, self.sync_bit_threshold)
+                    < S::SYNC_THRESHOLD
+                {
+                    // arbitrary choice, 240 samples = 5ms
+                    // these bursts keep repeating so it will keep pushing out the DCD end time
+                    self.dcd_until(self.sample + 240);
+                }
             }
         }
 
@@ -151,14 +727,21 @@ impl Demodulator for SoftDemodulator {
             SyncBurst::Stream,
             SyncBurst::Packet,
         ] {
-            let (diff, max, shift) = sync_burst_correlation(burst.target(), &burst_window);
-            if diff < SYNC_THRESHOLD {
+            let (diff, max, shift) = match &normalised {
+                Some((symbols, gain, shift)) => (
+                    S::sync_burst_correlation(burst.target(), symbols, self.sync_bit_threshold),
+                    *gain,
+                    *shift,
+                ),
+                None => (S::NO_MATCH, S::default(), S::default()),
+            };
+            if diff < S::SYNC_THRESHOLD {
                 let mut new_candidate = true;
-                if let Some(c) = self.candidate.as_mut() {
-                    if diff > c.diff {
-                        c.age += 1;
-                        new_candidate = false;
-                    }
+                if let Some(c) = self.candidate.as_mut()
+                    && diff > c.diff
+                {
+                    c.age += 1;
+                    new_candidate = false;
                 }
                 if new_candidate {
                     self.candidate = Some(DecodeCandidate {
@@ -170,7 +753,7 @@ impl Demodulator for SoftDemodulator {
                     });
                 }
             }
-            if diff >= SYNC_THRESHOLD
+            if diff >= S::SYNC_THRESHOLD
                 && self
                     .candidate
                     .as_ref()
@@ -179,23 +762,107 @@ impl Demodulator for SoftDemodulator {
             {
                 // wait until the rest of the frame is in the buffer
                 let c = self.candidate.as_ref().unwrap();
-                self.samples_until_decode = Some((184 * 10) - (c.age as u16));
+                self.samples_until_decode = Some((184 * self.sps as u16) - (c.age as u16));
                 debug!(
                     "Found {:?} at sample {} diff {}",
-                    c.burst,
-                    self.sample - c.age as u64,
-                    c.diff
+                    self.burst_as_received(c.burst),
+                    self.sample - (c.age as u64 * self.decimation as u64),
+                    c.diff.to_f32()
                 );
                 // After any of these frame types you would expect to see a full EOT
-                self.dcd_until(self.sample + 1920 + 1920);
+                self.dcd_until(self.sample + 2 * SAMPLES_PER_FRAME as u64);
             }
         }
 
         None
     }
+}
+
+/// Decode a frame of normalised symbols which followed a `burst`.
+fn decode_frame<S: DemodSample>(
+    burst: SyncBurst,
+    symbols: &[S; 192],
+    sync_diff: f32,
+) -> Option<(Frame, SignalQuality)> {
+    let levels = symbols.map(S::level);
+    match burst {
+        SyncBurst::Lsf => {
+            let (frame, errors) = parse_lsf(&levels)?;
+            let ideal = encode_lsf(&frame);
+            let quality = measure_quality(symbols, &ideal, sync_diff, errors);
+            Some((Frame::Lsf(frame), quality))
+        }
+        SyncBurst::Bert => {
+            // TODO: BERT
+            None
+        }
+        SyncBurst::Stream => {
+            let (frame, errors) = parse_stream(&levels)?;
+            let ideal = encode_stream(&frame);
+            let quality = measure_quality(symbols, &ideal, sync_diff, errors);
+            Some((Frame::Stream(frame), quality))
+        }
+        SyncBurst::Packet => {
+            let (frame, errors) = parse_packet(&levels)?;
+            let ideal = encode_packet(&frame);
+            let quality = measure_quality(symbols, &ideal, sync_diff, errors);
+            Some((Frame::Packet(frame), quality))
+        }
+        SyncBurst::Preamble | SyncBurst::EndOfTransmission => {
+            // should never be chosen as a candidate
+            None
+        }
+    }
+}
+
+/// Input samples filtered at a time by `SoftDemodulator::demod_block()`.
+const BLOCK_CHUNK: usize = 240;
+
+impl<S: DemodSample> Demodulator for SoftDemodulator<S> {
+    fn demod(&mut self, sample: i16) -> Option<(Frame, SignalQuality)> {
+        if self.push_sample(sample) {
+            self.process()
+        } else {
+            None
+        }
+    }
+
+    fn demod_block(
+        &mut self,
+        samples: &[i16],
+        on_frame: &mut dyn FnMut(usize, Frame, SignalQuality),
+    ) {
+        // The previous 80 inputs followed by the chunk, so that every filter window is a plain
+        // slice and the circular filter window only needs updating at the end
+        let mut input = [S::default(); 80 + BLOCK_CHUNK];
+        let mut filtered = [S::default(); BLOCK_CHUNK];
+        for (chunk_idx, chunk) in samples.chunks(BLOCK_CHUNK).enumerate() {
+            input[0..80]
+                .copy_from_slice(&self.filter_win[self.filter_cursor + 1..self.filter_cursor + 81]);
+            for (i, sample) in chunk.iter().enumerate() {
+                input[80 + i] = S::from_input(*sample);
+            }
+            // Only the inputs that advance() will accept need filtering
+            let first = self.decimation - 1 - (self.sample % self.decimation as u64) as usize;
+            for i in (first..chunk.len()).step_by(self.decimation) {
+                filtered[i] = S::filter(&input[i..i + 81]);
+            }
+            for sample in &chunk[chunk.len().saturating_sub(81)..] {
+                self.store_input(*sample);
+            }
+
+            for (i, out) in filtered[0..chunk.len()].iter().enumerate() {
+                if self.advance()
+                    && let Some((frame, quality)) = self.process_filtered(*out)
+                {
+                    on_frame(chunk_idx * BLOCK_CHUNK + i, frame, quality);
+                }
+            }
+        }
+    }
 
     fn data_carrier_detect(&self) -> bool {
-        false
+        self.dcd.is_some()
     }
 }
 
@@ -279,14 +946,54 @@ pub enum ModulatorFrame {
         /// TNC fires PTT and it's up to modulator to apply the setting, taking advantage of whatever
         /// buffering already exists in the sound card to reduce the artificial delay.
         tx_delay: u8,
+        /// TNC's configured PTT lead in milliseconds, applied in addition to `tx_delay`.
+        ///
+        /// Both are zero for the second and later frames of a long preamble.
+        ptt_lead: u16,
     },
     Lsf(LsfFrame),
     Stream(StreamFrame),
     Packet(PacketFrame),
     // TODO: BertFrame
+    /// 40 ms of a test pattern for setting up the transmitter, with no M17 framing.
+    Calibration(CalibrationPattern),
     EndOfTransmission,
 }
 
+/// Test signals for adjusting transmitter deviation and audio levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationPattern {
+    /// Alternating +3 and -3 symbols, i.e., a 2400 Hz tone at full deviation.
+    Preamble,
+    /// Four +3 symbols then four -3 symbols, a 600 Hz tone at full deviation of +/-2.4 kHz.
+    OuterTone,
+    /// Four +1 symbols then four -1 symbols, a 600 Hz tone at +/-800 Hz deviation.
+    InnerTone,
+    /// Pseudo-random symbols with all four levels equally likely.
+    Random,
+}
+
+impl CalibrationPattern {
+    pub fn from_proto(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => CalibrationPattern::Preamble,
+            2 => CalibrationPattern::OuterTone,
+            3 => CalibrationPattern::InnerTone,
+            4 => CalibrationPattern::Random,
+            _ => return None,
+        })
+    }
+
+    pub fn proto_value(&self) -> u8 {
+        match self {
+            CalibrationPattern::Preamble => 1,
+            CalibrationPattern::OuterTone => 2,
+            CalibrationPattern::InnerTone => 3,
+            CalibrationPattern::Random => 4,
+        }
+    }
+}
+
 pub struct SoftModulator {
     // TODO: 2000 was overflowing around EOT, track down why
     /// Next modulated frame to output - 1920 samples for 40ms frame plus 80 for ramp-down
@@ -312,12 +1019,12 @@ pub struct SoftModulator {
     /// This is a duration expressed in number of samples.
     report_tx_end: Option<usize>,
 
-    /// Circular buffer of most recently output samples for calculating the RRC filtered value.
-    ///
-    /// This should naturally degrade to an oldest value plus 80 zeroes after an EOT.
-    filter_win: [f32; 81],
-    /// Current position in filter_win
-    filter_cursor: usize,
+    /// Pulse shaping for the most recently output symbols.
+    filter: TxFilter,
+    /// Output amplitude as a percentage of the default.
+    output_level: u8,
+    /// Source of symbols for `CalibrationPattern::Random`.
+    calibration_prng: Prng,
 
     /// Should we ask the TNC for another frame. True after each call to update_output_buffer.
     try_get_frame: bool,
@@ -342,8 +1049,9 @@ impl SoftModulator {
             idle: true,
             calculate_tx_end: false,
             report_tx_end: None,
-            filter_win: [0f32; 81],
-            filter_cursor: 0,
+            filter: TxFilter::new(),
+            output_level: 100,
+            calibration_prng: Prng::default(),
             try_get_frame: false,
             output_latency: 0,
             samples_in_buf: 0,
@@ -351,32 +1059,33 @@ impl SoftModulator {
         }
     }
 
-    fn push_sample(&mut self, dibit: f32) {
-        // TODO: 48 kHz assumption again
-        for i in 0..10 {
-            // Right now we are encoding everything as 1.0-scaled dibit floats
-            // This is a bit silly but it will do for a minute
-            // Max possible gain from the RRC filter with upsampling is about 0.462
-            // Let's bump everything to a baseline of 16383 / 0.462 = 35461
-            // For normal signals this yields roughly 0.5 magnitude which is plenty
-            if i == 0 {
-                self.filter_win[self.filter_cursor] = dibit * 35461.0;
-            } else {
-                self.filter_win[self.filter_cursor] = 0.0;
-            }
-            self.filter_cursor = (self.filter_cursor + 1) % 81;
-            let mut out: f32 = 0.0;
-            for i in 0..81 {
-                let filter_idx = (self.filter_cursor + i) % 81;
-                out += RRC_48K[i] * self.filter_win[filter_idx];
-            }
-            self.next_transmission[self.next_len] = out as i16;
-            self.next_len += 1;
+    /// Create a modulator which shapes symbols using only integer arithmetic.
+    ///
+    /// Output is within one count of `new()` for every sample.
+    #[cfg(feature = "fixed-point")]
+    pub fn new_fixed_point() -> Self {
+        Self {
+            filter: TxFilter::new_fixed_point(),
+            ..Self::new()
         }
     }
 
+    /// Scale the output amplitude, as a percentage of the default.
+    ///
+    /// At the default of 100%, the peaks of a normal transmission are about half of full scale.
+    /// Levels above 200% would clip and are limited to that. This takes effect from the next frame.
+    pub fn set_output_level(&mut self, percent: u8) {
+        self.output_level = percent.min(200);
+    }
+
+    fn push_sample(&mut self, symbol: i8) {
+        let out = &mut self.next_transmission[self.next_len..self.next_len + SAMPLES_PER_SYMBOL];
+        self.filter.push(symbol, self.output_level, out);
+        self.next_len += SAMPLES_PER_SYMBOL;
+    }
+
     fn request_frame_if_space(&mut self) {
-        if self.buf_capacity - self.samples_in_buf >= 2000 {
+        if self.buf_capacity.saturating_sub(self.samples_in_buf) >= 2000 {
             self.try_get_frame = true;
         }
     }
@@ -413,42 +1122,47 @@ impl Modulator for SoftModulator {
         self.next_read = 0;
 
         match frame {
-            ModulatorFrame::Preamble { tx_delay } => {
-                // TODO: Stop assuming 48 kHz everywhere. 24 kHz should be fine too.
-                let tx_delay_samples = tx_delay as usize * 480;
+            ModulatorFrame::Preamble { tx_delay, ptt_lead } => {
+                let tx_delay_samples =
+                    (tx_delay as usize * 10 + ptt_lead as usize) * SAMPLES_PER_MS;
                 // Our output latency gives us a certain amount of unavoidable TxDelay
                 // So only introduce artificial delay if the requested TxDelay exceeds that
                 self.tx_delay_padding = tx_delay_samples.saturating_sub(self.output_latency);
 
                 // We should be starting from a filter_win of zeroes
                 // Transmission is effectively smeared by 80 taps and we'll capture that in EOT
-                for dibit in generate_preamble() {
-                    self.push_sample(dibit);
+                for symbol in generate_preamble() {
+                    self.push_sample(symbol);
                 }
             }
             ModulatorFrame::Lsf(lsf_frame) => {
-                for dibit in encode_lsf(&lsf_frame) {
-                    self.push_sample(dibit);
+                for symbol in encode_lsf(&lsf_frame) {
+                    self.push_sample(symbol);
                 }
             }
             ModulatorFrame::Stream(stream_frame) => {
-                for dibit in encode_stream(&stream_frame) {
-                    self.push_sample(dibit);
+                for symbol in encode_stream(&stream_frame) {
+                    self.push_sample(symbol);
                 }
             }
             ModulatorFrame::Packet(packet_frame) => {
-                for dibit in encode_packet(&packet_frame) {
-                    self.push_sample(dibit);
+                for symbol in encode_packet(&packet_frame) {
+                    self.push_sample(symbol);
+                }
+            }
+            ModulatorFrame::Calibration(pattern) => {
+                for symbol in generate_calibration(pattern, &mut self.calibration_prng) {
+                    self.push_sample(symbol);
                 }
             }
             ModulatorFrame::EndOfTransmission => {
-                for dibit in generate_end_of_transmission() {
-                    self.push_sample(dibit);
+                for symbol in generate_end_of_transmission() {
+                    self.push_sample(symbol);
                 }
                 for _ in 0..80 {
                     // This is not a real symbol value
                     // However we want to flush the filter
-                    self.push_sample(0f32);
+                    self.push_sample(0);
                 }
                 self.calculate_tx_end = true;
             }
@@ -510,11 +1224,482 @@ impl Default for SoftModulator {
     }
 }
 
+/// RRC pulse shaping of symbols for transmission, upsampled to 48 kHz.
+// Boxing is not an option without an allocator, and a modulator only ever has one filter
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum TxFilter {
+    Float {
+        /// Circular buffer of most recently output samples for calculating the RRC filtered value.
+        ///
+        /// This should naturally degrade to an oldest value plus 80 zeroes after an EOT.
+        win: [f32; 81],
+        /// Current position in win
+        cursor: usize,
+    },
+    #[cfg(feature = "fixed-point")]
+    Fixed {
+        /// Most recent symbols, newest first. Flushing uses 0.
+        symbols: [i32; 9],
+    },
+}
+
+impl TxFilter {
+    pub(crate) fn new() -> Self {
+        Self::Float {
+            win: [0f32; 81],
+            cursor: 0,
+        }
+    }
+
+    #[cfg(feature = "fixed-point")]
+    pub(crate) fn new_fixed_point() -> Self {
+        Self::Fixed { symbols: [0; 9] }
+    }
+
+    /// Shape the next symbol (-3, -1, +1 or +3) into 10 output samples, scaled to `level` percent.
+    fn push(&mut self, symbol: i8, level: u8, out: &mut [i16]) {
+        match self {
+            Self::Float { win, cursor } => {
+                for (i, o) in out.iter_mut().enumerate() {
+                    // Right now we are encoding everything as 1.0-scaled dibit floats
+                    // This is a bit silly but it will do for a minute
+                    // Max possible gain from the RRC filter with upsampling is about 0.462
+                    // Let's bump everything to a baseline of 16383 / 0.462 = 35461
+                    // For normal signals this yields roughly 0.5 magnitude which is plenty
+                    win[*cursor] = if i == 0 {
+                        symbol as f32 / 3.0 * TX_SYMBOL_SCALE
+                    } else {
+                        0.0
+                    };
+                    *cursor = (*cursor + 1) % 81;
+                    let mut sample: f32 = 0.0;
+                    for i in 0..81 {
+                        let filter_idx = (*cursor + i) % 81;
+                        sample += RRC_48K[i] * win[filter_idx];
+                    }
+                    if level != 100 {
+                        sample *= level as f32 / 100.0;
+                    }
+                    *o = sample as i16;
+                }
+            }
+            #[cfg(feature = "fixed-point")]
+            Self::Fixed { symbols } => {
+                symbols.copy_within(0..8, 1);
+                symbols[0] = symbol as i32;
+                // Only every 10th tap lines up with a symbol, so each output phase needs 9 taps
+                for (k, o) in out.iter_mut().enumerate() {
+                    let mut sample = 0i32;
+                    for (j, symbol) in symbols.iter().enumerate() {
+                        if let Some(tap) = (80 - k).checked_sub(10 * j) {
+                            sample += RRC_48K_TX_Q8[tap] * symbol;
+                        }
+                    }
+                    // Divide rather than shift so that we truncate like the float version
+                    *o = if level == 100 {
+                        (sample / 256) as i16
+                    } else {
+                        (sample * level as i32 / 25600).clamp(i16::MIN as i32, i16::MAX as i32)
+                            as i16
+                    };
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
-pub(crate) struct DecodeCandidate {
+pub(crate) struct DecodeCandidate<S> {
     burst: SyncBurst,
     age: u8,
-    diff: f32,
-    gain: f32,
-    shift: f32,
+    diff: S,
+    gain: S,
+    shift: S,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn db_approximation() {
+        for (ratio, db) in [
+            (1.0, 0.0),
+            (2.0, 3.0103),
+            (10.0, 10.0),
+            (1000.0, 30.0),
+            (0.5, -3.0103),
+        ] {
+            assert!((ratio_to_db(ratio) - db).abs() < 0.01, "{ratio} -> {db}");
+        }
+    }
+
+    #[test]
+    fn quality_of_noisy_frame() {
+        let lsf = LsfFrame::new_voice(
+            &crate::address::Address::Broadcast,
+            &crate::address::Address::Broadcast,
+        );
+        let ideal = encode_lsf(&lsf);
+        let normalised = ideal.map(|s| s as f32 / 3.0);
+        let clean = measure_quality(&normalised, &ideal, 0.0, 0);
+        assert_eq!(clean.symbol_errors, 0);
+        assert_eq!(clean.snr_db, 99.0);
+
+        let mut noisy = normalised;
+        for (i, s) in noisy.iter_mut().enumerate() {
+            // Alternate +/- 0.1 noise, and flip two symbols to the adjacent level
+            *s += if i % 2 == 0 { 0.1 } else { -0.1 };
+        }
+        noisy[50] = -noisy[50].signum() * 0.2;
+        noisy[51] = -noisy[51].signum() * 0.2;
+        let quality = measure_quality(&noisy, &ideal, 1.5, 3);
+        assert_eq!(quality.symbol_errors, 2);
+        assert_eq!(quality.viterbi_errors, 3);
+        assert!(quality.snr_db > 5.0 && quality.snr_db < 20.0);
+
+        let decoded = SignalQuality::from_bytes(&quality.to_bytes()).unwrap();
+        assert!((decoded.snr_db - quality.snr_db).abs() < 0.1);
+        assert!((decoded.sync_diff - 1.5).abs() < 0.01);
+        assert_eq!(decoded.symbol_errors, 2);
+    }
+
+    fn modulate(frames: impl IntoIterator<Item = ModulatorFrame>) -> Vec<i16> {
+        modulate_with(SoftModulator::new(), frames)
+    }
+
+    fn modulate_with(
+        mut modulator: SoftModulator,
+        frames: impl IntoIterator<Item = ModulatorFrame>,
+    ) -> Vec<i16> {
+        let mut samples = vec![];
+        let mut buf = [0i16; 1024];
+        for frame in frames {
+            modulator.provide_next_frame(Some(frame));
+            loop {
+                let n = modulator.read_output_samples(&mut buf);
+                if n == 0 {
+                    break;
+                }
+                samples.extend_from_slice(&buf[0..n]);
+            }
+        }
+        // Give the demodulator time to finish with the last frame
+        samples.extend_from_slice(&[0i16; 1920]);
+        samples
+    }
+
+    fn sample_stream() -> Vec<ModulatorFrame> {
+        let lsf = LsfFrame::new_voice(
+            &crate::address::Address::Broadcast,
+            &crate::address::Address::Broadcast,
+        );
+        let stream = StreamFrame {
+            lich_idx: 0,
+            lich_part: lsf.0[0..5].try_into().unwrap(),
+            frame_number: 0,
+            end_of_stream: true,
+            stream_data: [0u8; 16],
+        };
+        vec![
+            ModulatorFrame::Preamble {
+                tx_delay: 0,
+                ptt_lead: 0,
+            },
+            ModulatorFrame::Lsf(lsf),
+            ModulatorFrame::Stream(stream),
+            ModulatorFrame::EndOfTransmission,
+        ]
+    }
+
+    #[test]
+    fn demodulator_stats() {
+        let mut demod = SoftDemodulator::new();
+        let samples = modulate(sample_stream());
+        let decoded = samples
+            .iter()
+            .filter(|s| demod.demod(**s).is_some())
+            .count();
+        assert_eq!(decoded, 2);
+        let stats = demod.stats();
+        assert_eq!(stats.samples, samples.len() as u64);
+        assert_eq!(stats.lsf_bursts, 1);
+        assert_eq!(stats.stream_bursts, 1);
+        assert_eq!(stats.packet_bursts, 0);
+        assert_eq!(stats.frames_decoded, 2);
+        assert_eq!(stats.frames_failed, 0);
+        assert!(stats.dcd_samples > 1920 && stats.dcd_samples < stats.samples);
+
+        // Wipe out the payload of the stream frame but leave its sync burst intact
+        let mut demod = SoftDemodulator::new();
+        let mut samples = modulate(sample_stream());
+        let stream_start = 1920 * 2;
+        for s in &mut samples[stream_start + 120..stream_start + 1920] {
+            *s = -*s;
+        }
+        let decoded = samples
+            .iter()
+            .filter(|s| demod.demod(**s).is_some())
+            .count();
+        assert_eq!(decoded, 1);
+        let stats = demod.stats();
+        assert_eq!(stats.stream_bursts, 1);
+        assert_eq!(stats.frames_decoded, 1);
+        assert_eq!(stats.frames_failed, 1);
+    }
+
+    #[test]
+    fn automatic_polarity() {
+        let packet = PacketFrame {
+            payload: [0x55; 25],
+            counter: crate::protocol::PacketFrameCounter::FinalFrame { payload_len: 20 },
+        };
+        let mut frames = sample_stream();
+        frames.insert(3, ModulatorFrame::Packet(packet));
+        let normal = modulate(frames);
+        let inverted: Vec<i16> = normal.iter().map(|s| s.saturating_neg()).collect();
+        let decode = |polarity, samples: &[i16]| {
+            let mut demod = SoftDemodulator::new();
+            demod.set_polarity(polarity);
+            let frames: Vec<Frame> = samples
+                .iter()
+                .filter_map(|s| demod.demod(*s).map(|(f, _)| f))
+                .collect();
+            (frames, demod.stats())
+        };
+
+        let (expected, stats) = decode(RxPolarity::Auto, &normal);
+        assert_eq!(expected.len(), 3);
+        assert!(!stats.inverted);
+        assert_eq!(stats.polarity_changes, 0);
+
+        let (frames, stats) = decode(RxPolarity::Auto, &inverted);
+        assert_eq!(frames, expected);
+        assert!(stats.inverted);
+        assert_eq!(stats.polarity_changes, 1);
+        assert_eq!(stats.lsf_bursts, 1);
+        assert_eq!(stats.stream_bursts, 1);
+        assert_eq!(stats.packet_bursts, 1);
+        assert_eq!(stats.frames_failed, 0);
+
+        let (frames, stats) = decode(RxPolarity::Inverted, &inverted);
+        assert_eq!(frames, expected);
+        assert_eq!(stats.polarity_changes, 0);
+
+        assert!(decode(RxPolarity::Normal, &inverted).0.is_empty());
+        assert!(decode(RxPolarity::Inverted, &normal).0.is_empty());
+    }
+
+    #[test]
+    fn frame_capture() {
+        let lsf = LsfFrame::new_voice(
+            &crate::address::Address::Broadcast,
+            &crate::address::Address::Broadcast,
+        );
+        let ideal = encode_lsf(&lsf);
+        for (decimation, inverted) in [(1, false), (2, false), (5, true)] {
+            let mut samples = modulate(sample_stream());
+            if inverted {
+                samples.iter_mut().for_each(|s| *s = s.saturating_neg());
+            }
+            let mut demod = SoftDemodulator::new_decimating(decimation);
+            let mut capture = FrameCapture::new();
+            let mut captured = 0;
+            for s in samples {
+                let decoded = demod.demod(s);
+                assert_eq!(demod.capture_frame(&mut capture), decoded.is_some());
+                if let Some((Frame::Lsf(_), _)) = decoded {
+                    assert_eq!(capture.sample, demod.sample_count());
+                    assert_eq!(capture.samples().len(), 1920 / decimation);
+                    // Decimation can leave us sampling a little way off the peak
+                    let tolerance = if decimation == 1 { 0.05 } else { 0.4 };
+                    for (symbol, ideal) in capture.symbols().zip(ideal) {
+                        let ideal = ideal as f32 / 3.0;
+                        assert!((symbol - ideal).abs() < tolerance, "{symbol} vs {ideal}");
+                    }
+                    captured += 1;
+                }
+            }
+            assert_eq!(captured, 1);
+        }
+    }
+
+    #[test]
+    fn decimating_block_demod() {
+        let samples = modulate(sample_stream());
+        let mut reference = SoftDemodulator::new();
+        let mut expected = vec![];
+        for (idx, s) in samples.iter().enumerate() {
+            if let Some((frame, _)) = reference.demod(*s) {
+                expected.push((idx, frame));
+            }
+        }
+        assert_eq!(expected.len(), 2);
+
+        for decimation in [1, 2, 5] {
+            let mut demod = SoftDemodulator::new_decimating(decimation);
+            let mut decoded = vec![];
+            for (block_idx, block) in samples.chunks(1000).enumerate() {
+                demod.demod_block(block, &mut |idx, frame, _| {
+                    decoded.push((block_idx * 1000 + idx, frame))
+                });
+            }
+            assert_eq!(decoded.len(), 2, "decimation {decimation}");
+            for ((idx, frame), (expected_idx, expected_frame)) in decoded.iter().zip(&expected) {
+                assert_eq!(frame, expected_frame);
+                // Coarser timing means the frame may be completed a few samples either side
+                assert!(idx.abs_diff(*expected_idx) < 10, "decimation {decimation}");
+            }
+
+            // Filtering a block at once must give exactly what one sample at a time does
+            let mut per_sample = SoftDemodulator::new_decimating(decimation);
+            let mut one_by_one = vec![];
+            for (idx, s) in samples.iter().enumerate() {
+                if let Some((frame, _)) = per_sample.demod(*s) {
+                    one_by_one.push((idx, frame));
+                }
+            }
+            assert_eq!(decoded, one_by_one, "decimation {decimation}");
+            assert_eq!(demod.stats(), per_sample.stats(), "decimation {decimation}");
+        }
+    }
+
+    #[test]
+    fn change_decimation() {
+        let mut demod = SoftDemodulator::new();
+        demod.set_polarity(RxPolarity::Normal);
+        demod.demod_block(&[0i16; 1000], &mut |_, _, _| panic!("decoded silence"));
+        demod.set_decimation(5);
+        assert_eq!(demod.sample_count(), 1000);
+
+        let mut decoded = 0;
+        demod.demod_block(&modulate(sample_stream()), &mut |_, _, _| decoded += 1);
+        assert_eq!(decoded, 2);
+        assert_eq!(demod.stats().frames_decoded, 2);
+    }
+
+    #[test]
+    fn calibration_patterns() {
+        // The preamble pattern is the same as a real preamble
+        let preamble = modulate([ModulatorFrame::Preamble {
+            tx_delay: 0,
+            ptt_lead: 0,
+        }]);
+        let calibration = modulate([ModulatorFrame::Calibration(CalibrationPattern::Preamble)]);
+        assert_eq!(preamble, calibration);
+
+        // Tones repeat every 8 symbols and the inner tone has a third of the amplitude
+        let outer =
+            modulate((0..3).map(|_| ModulatorFrame::Calibration(CalibrationPattern::OuterTone)));
+        let inner =
+            modulate((0..3).map(|_| ModulatorFrame::Calibration(CalibrationPattern::InnerTone)));
+        let steady = 1920..3840;
+        for i in steady.clone() {
+            assert_eq!(outer[i], outer[i + 80]);
+        }
+        let peak = |s: &[i16]| s[steady.clone()].iter().map(|x| x.unsigned_abs()).max();
+        let (outer_peak, inner_peak) = (peak(&outer).unwrap(), peak(&inner).unwrap());
+        assert!(outer_peak > 12000, "{outer_peak}");
+        assert!(inner_peak.abs_diff(outer_peak / 3) < 10, "{inner_peak}");
+
+        // Random symbols use all four levels and don't repeat from one frame to the next
+        let mut prng = Prng::default();
+        let first = generate_calibration(CalibrationPattern::Random, &mut prng);
+        let second = generate_calibration(CalibrationPattern::Random, &mut prng);
+        assert_ne!(first, second);
+        for level in [3, 1, -1, -3] {
+            assert!(first.contains(&level));
+        }
+    }
+
+    #[test]
+    fn preamble_delay_and_lead() {
+        let plain = modulate([ModulatorFrame::Preamble {
+            tx_delay: 0,
+            ptt_lead: 0,
+        }]);
+        let delayed = modulate([ModulatorFrame::Preamble {
+            tx_delay: 2,
+            ptt_lead: 15,
+        }]);
+        // 20 ms of TxDelay plus 15 ms of PTT lead
+        let padding = 960 + 720;
+        assert_eq!(delayed.len(), plain.len() + padding);
+        assert!(delayed[..padding].iter().all(|s| *s == 0));
+        assert_eq!(&delayed[padding..], &plain[..]);
+    }
+
+    #[test]
+    fn output_level() {
+        let full = modulate(sample_stream());
+        let mut modulator = SoftModulator::new();
+        modulator.set_output_level(50);
+        let half = modulate_with(modulator, sample_stream());
+        for (f, h) in full.iter().zip(&half) {
+            assert!((f / 2).abs_diff(*h) <= 1, "{f} vs {h}");
+        }
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn fixed_point_modulator_matches_float() {
+        for level in [100, 30, 200] {
+            let mut float = SoftModulator::new();
+            float.set_output_level(level);
+            let float = modulate_with(float, sample_stream());
+            let mut fixed = SoftModulator::new_fixed_point();
+            fixed.set_output_level(level);
+            let fixed = modulate_with(fixed, sample_stream());
+            assert_eq!(float.len(), fixed.len());
+            for (f, x) in float.iter().zip(&fixed) {
+                assert!(f.abs_diff(*x) <= 1, "{f} vs {x} at {level}%");
+            }
+        }
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn fixed_point_demodulator_matches_float() {
+        use crate::simulation::{Channel, Impairments};
+
+        let clean = modulate(sample_stream());
+        for impairments in [
+            Impairments::new(),
+            Impairments {
+                noise: 1500.0,
+                frequency_offset_hz: 200.0,
+                timing_offset: 0.5,
+                ..Impairments::new()
+            },
+            Impairments {
+                deviation_error: -0.5,
+                clock_skew_ppm: 100.0,
+                ..Impairments::new()
+            },
+        ] {
+            let mut samples = vec![];
+            Channel::new(impairments.clone(), 1).process(&clean, |s| samples.push(s));
+            for decimation in [1, 2, 5] {
+                let mut float = SoftDemodulator::new_decimating(decimation);
+                let mut fixed = FixedDemodulator::new_fixed_point_decimating(decimation);
+                for s in &samples {
+                    let expected = float.demod(*s);
+                    let decoded = fixed.demod(*s);
+                    assert_eq!(
+                        decoded.as_ref().map(|(f, _)| f),
+                        expected.as_ref().map(|(f, _)| f),
+                        "{impairments:?} decimation {decimation}"
+                    );
+                    if let (Some((_, q)), Some((_, expected_q))) = (decoded, expected) {
+                        assert_eq!(q.symbol_errors, expected_q.symbol_errors);
+                        // Quantising symbols to Q8 limits the measurable SNR to about 50 dB
+                        let (snr, expected_snr) = (q.snr_db.min(40.0), expected_q.snr_db.min(40.0));
+                        assert!((snr - expected_snr).abs() < 1.0, "{snr} vs {expected_snr}");
+                        assert!((q.sync_diff - expected_q.sync_diff).abs() < 0.1);
+                    }
+                }
+                assert_eq!(fixed.stats(), float.stats());
+            }
+        }
+    }
 }
@@ -0,0 +1,74 @@
+//! Small pseudo-random number generator for channel access decisions
+
+/// Arbitrary non-zero starting state used if no seed is provided.
+const DEFAULT_SEED: u32 = 0x4D31_3752;
+
+/// xorshift32 generator.
+///
+/// This is cheap, `no_std` and has no dependencies. It is only intended for things like CSMA
+/// backoff where we want to avoid stations falling into lockstep - it is not cryptographically
+/// secure.
+pub(crate) struct Prng {
+    state: u32,
+}
+
+impl Prng {
+    pub(crate) fn new(seed: u32) -> Self {
+        // Scramble the seed so that similar seeds (e.g. 1, 2, 3) don't produce similar
+        // initial outputs. This is the finaliser from MurmurHash3.
+        let mut state = seed;
+        state ^= state >> 16;
+        state = state.wrapping_mul(0x85eb_ca6b);
+        state ^= state >> 13;
+        state = state.wrapping_mul(0xc2b2_ae35);
+        state ^= state >> 16;
+        // xorshift gets stuck at zero forever
+        if state == 0 {
+            state = DEFAULT_SEED;
+        }
+        Self { state }
+    }
+
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Return a value uniformly distributed over 0..=255.
+    pub(crate) fn next_u8(&mut self) -> u8 {
+        // high bits have the better statistical properties
+        (self.next_u32() >> 24) as u8
+    }
+}
+
+impl Default for Prng {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_seed_is_usable() {
+        let mut prng = Prng::new(0);
+        assert_ne!(prng.next_u32(), 0);
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Prng::new(1234);
+        let mut b = Prng::new(1234);
+        for _ in 0..100 {
+            assert_eq!(a.next_u8(), b.next_u8());
+        }
+        let mut c = Prng::new(4321);
+        assert!((0..100).any(|_| a.next_u32() != c.next_u32()));
+    }
+}
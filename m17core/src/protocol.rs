@@ -140,7 +140,7 @@ impl LsfFrame {
     }
 
     pub fn data_type(&self) -> DataType {
-        match (self.0[12] >> 1) & 0x03 {
+        match (self.lsf_type() >> 1) & 0x0003 {
             0b00 => DataType::Reserved,
             0b01 => DataType::Data,
             0b10 => DataType::Voice,
@@ -318,4 +318,18 @@ mod tests {
         frame.set_channel_access_number(11);
         assert_eq!(frame.channel_access_number(), 11);
     }
+
+    #[test]
+    fn set_data_type() {
+        let mut frame = LsfFrame([0u8; 30]);
+        for data_type in [
+            DataType::Data,
+            DataType::Voice,
+            DataType::VoiceAndData,
+            DataType::Reserved,
+        ] {
+            frame.set_data_type(data_type);
+            assert_eq!(frame.data_type(), data_type);
+        }
+    }
 }
@@ -145,7 +145,7 @@ macro_rules! impl_payload {
 macro_rules! impl_modules {
     ($t:ty, $from:tt, $to:tt) => {
         impl $t {
-            pub fn modules(&self) -> ModulesIterator {
+            pub fn modules(&self) -> ModulesIterator<'_> {
                 ModulesIterator::new(&self.0[$from..$to])
             }
 
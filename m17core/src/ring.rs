@@ -0,0 +1,118 @@
+//! Fixed-capacity FIFO queue which does not need the heap
+
+/// Circular buffer holding up to `N` items.
+pub(crate) struct RingBuffer<T, const N: usize> {
+    items: [Option<T>; N],
+    /// Index of the oldest item, if any
+    head: usize,
+    /// Number of items currently stored
+    len: usize,
+}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub(crate) fn new() -> Self {
+        const { assert!(N > 0, "ring buffer must have non-zero capacity") };
+        Self {
+            items: [const { None }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        N
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Add an item to the back of the queue, or give it back if there is no space.
+    pub(crate) fn push_back(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Add an item to the back of the queue, discarding the oldest item if necessary to make room.
+    ///
+    /// Returns the discarded item, if any.
+    pub(crate) fn push_back_overwrite(&mut self, item: T) -> Option<T> {
+        let discarded = if self.is_full() {
+            self.pop_front()
+        } else {
+            None
+        };
+        let _ = self.push_back(item);
+        discarded
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    pub(crate) fn front_mut(&mut self) -> Option<&mut T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.items[self.head].as_mut()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_order_with_wraparound() {
+        let mut ring: RingBuffer<u32, 3> = RingBuffer::new();
+        assert!(ring.is_empty());
+        for round in 0..5 {
+            assert_eq!(ring.push_back(round * 10), Ok(()));
+            assert_eq!(ring.push_back(round * 10 + 1), Ok(()));
+            assert_eq!(ring.pop_front(), Some(round * 10));
+            assert_eq!(ring.pop_front(), Some(round * 10 + 1));
+        }
+        assert_eq!(ring.pop_front(), None);
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut ring: RingBuffer<u32, 2> = RingBuffer::new();
+        assert_eq!(ring.push_back(1), Ok(()));
+        assert_eq!(ring.push_back(2), Ok(()));
+        assert!(ring.is_full());
+        assert_eq!(ring.push_back(3), Err(3));
+        assert_eq!(ring.push_back_overwrite(3), Some(1));
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.pop_front(), Some(2));
+        assert_eq!(ring.pop_front(), Some(3));
+    }
+}
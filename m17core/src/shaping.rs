@@ -1,4 +1,7 @@
-pub static RRC_48K: [f32; 81] = [
+/// Root raised cosine filter for 4800 baud at 48 kHz.
+pub static RRC_48K: [f32; 81] = RRC_48K_TAPS;
+
+const RRC_48K_TAPS: [f32; 81] = [
     -0.0031955054,
     -0.002930098,
     -0.001940547,
@@ -82,6 +85,36 @@ pub static RRC_48K: [f32; 81] = [
     -0.0031955054,
 ];
 
+/// `RRC_48K` in Q13 fixed point for filtering received samples.
+///
+/// Q13 ensures that 81 taps applied to full-scale i16 input cannot overflow an i32.
+#[cfg(feature = "fixed-point")]
+pub static RRC_48K_Q13: [i32; 81] = quantise(&RRC_48K_TAPS, 8192.0);
+
+/// Scale of `SoftModulator` output for a symbol of 1.0 applied to `RRC_48K`.
+pub const TX_SYMBOL_SCALE: f32 = 35461.0;
+
+/// `RRC_48K` in Q8 fixed point, scaled so that symbols -3, -1, 1 and 3 produce the same output
+/// as `SoftModulator`.
+#[cfg(feature = "fixed-point")]
+pub static RRC_48K_TX_Q8: [i32; 81] = quantise(&RRC_48K_TAPS, TX_SYMBOL_SCALE / 3.0 * 256.0);
+
+#[cfg(feature = "fixed-point")]
+const fn quantise(taps: &[f32; 81], scale: f32) -> [i32; 81] {
+    let mut out = [0i32; 81];
+    let mut i = 0;
+    while i < 81 {
+        let scaled = taps[i] * scale;
+        out[i] = if scaled >= 0.0 {
+            (scaled + 0.5) as i32
+        } else {
+            (scaled - 0.5) as i32
+        };
+        i += 1;
+    }
+    out
+}
+
 #[cfg(test)]
 mod test {
     use std::f32::consts::PI;
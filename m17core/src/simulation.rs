@@ -0,0 +1,452 @@
+//! Simulated radio channel for testing and characterising the modem
+//!
+//! Samples are treated as the output of an FM discriminator, i.e., what a sound card would pass
+//! from `SoftModulator` on one station to `SoftDemodulator` on another.
+
+use crate::modem::{
+    DemodSample, Demodulator, Modulator, ModulatorFrame, SAMPLES_PER_FRAME, SAMPLES_PER_SYMBOL,
+    SoftDemodulator, SoftModulator,
+};
+use crate::prng::Prng;
+use crate::protocol::{Frame, LsfFrame, PacketFrame, PacketFrameCounter, StreamFrame};
+
+/// Sample value of a sustained outer symbol from `SoftModulator`, corresponding to 2.4 kHz
+/// deviation.
+pub const OUTER_SYMBOL_LEVEL: f32 = 11159.0;
+
+/// Ways in which a channel degrades the signal passing through it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Impairments {
+    /// Standard deviation of Gaussian noise added to the signal, in sample units.
+    pub noise: f32,
+    /// Receiver tuning error in Hz, which appears as a DC offset after demodulation.
+    pub frequency_offset_hz: f32,
+    /// How fast the transmitter's sample clock runs relative to the receiver's, in parts per
+    /// million.
+    pub clock_skew_ppm: f32,
+    /// Proportion of the signal lost at the bottom of each fade, from 0.0 (none) to 1.0.
+    pub fade_depth: f32,
+    /// Length of one fade cycle in samples. Zero disables fading.
+    pub fade_period: u32,
+    /// Proportion by which the transmitter's deviation is too wide (positive) or too narrow.
+    pub deviation_error: f32,
+    /// Delay of the signal by a fraction of a sample, from 0.0 to 1.0, which moves the ideal
+    /// symbol sampling point away from the receiver's sample clock.
+    pub timing_offset: f32,
+}
+
+impl Impairments {
+    /// A perfect channel.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Applies `Impairments` to a continuous stream of samples.
+pub struct Channel {
+    impairments: Impairments,
+    prng: Prng,
+    /// Position in the current fade cycle
+    fade_pos: u32,
+    /// Position of the next output sample after `prev`, in input samples
+    resample_pos: f32,
+    /// Most recent input sample after fading
+    prev: f32,
+}
+
+impl Channel {
+    /// Create a channel. Different seeds give different noise.
+    pub fn new(impairments: Impairments, seed: u32) -> Self {
+        Self {
+            prng: Prng::new(seed),
+            fade_pos: 0,
+            resample_pos: 1.0 - impairments.timing_offset,
+            prev: 0.0,
+            impairments,
+        }
+    }
+
+    pub fn impairments(&self) -> &Impairments {
+        &self.impairments
+    }
+
+    pub fn set_impairments(&mut self, impairments: Impairments) {
+        self.impairments = impairments;
+    }
+
+    /// Pass `input` through the channel, calling `out` with each resulting sample.
+    ///
+    /// With clock skew, slightly more or fewer samples will come out than went in. The most recent
+    /// input sample is held back until the next one arrives so that it can be interpolated.
+    pub fn process(&mut self, input: &[i16], mut out: impl FnMut(i16)) {
+        let step = 1.0 + self.impairments.clock_skew_ppm * 1e-6;
+        let dc = self.impairments.frequency_offset_hz / 2400.0 * OUTER_SYMBOL_LEVEL;
+        let deviation = 1.0 + self.impairments.deviation_error;
+        for s in input {
+            let current = *s as f32 * deviation * self.fade_gain();
+            while self.resample_pos < 1.0 {
+                let signal = self.prev + (current - self.prev) * self.resample_pos;
+                let noise = gaussian(&mut self.prng) * self.impairments.noise;
+                // float to int casts saturate, which is what we want
+                out((signal + dc + noise) as i16);
+                self.resample_pos += step;
+            }
+            self.resample_pos -= 1.0;
+            self.prev = current;
+        }
+    }
+
+    /// Gain due to fading for the next sample, following a triangle wave.
+    fn fade_gain(&mut self) -> f32 {
+        let period = self.impairments.fade_period;
+        if period == 0 {
+            return 1.0;
+        }
+        self.fade_pos = (self.fade_pos + 1) % period;
+        let half = period as f32 / 2.0;
+        let depth = 1.0 - (self.fade_pos as f32 - half).abs() / half;
+        1.0 - self.impairments.fade_depth * depth
+    }
+}
+
+/// Kinds of frame that can be used to measure frame error rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestFrameType {
+    Lsf,
+    Stream,
+    Packet,
+}
+
+/// Sends randomly-generated frames through a `Channel` to measure how many are decoded intact.
+///
+/// Each frame is sent as its own transmission with a preamble and EOT, separated by a frame's
+/// worth of silence. The channel and demodulator run continuously throughout.
+pub struct FrameErrorTest<S: DemodSample = f32> {
+    channel: Channel,
+    demodulator: SoftDemodulator<S>,
+    prng: Prng,
+    ebn0_db: Option<f32>,
+}
+
+impl FrameErrorTest {
+    pub fn new(impairments: Impairments, seed: u32) -> Self {
+        Self::new_with_demodulator(impairments, seed, SoftDemodulator::new())
+    }
+}
+
+impl<S: DemodSample> FrameErrorTest<S> {
+    /// Test a particular demodulator, e.g., one that uses fixed point arithmetic.
+    pub fn new_with_demodulator(
+        impairments: Impairments,
+        seed: u32,
+        demodulator: SoftDemodulator<S>,
+    ) -> Self {
+        Self {
+            channel: Channel::new(impairments, seed),
+            demodulator,
+            prng: Prng::new(seed.wrapping_add(1)),
+            ebn0_db: None,
+        }
+    }
+
+    /// Use a differently-configured demodulator, e.g., one that is decimating.
+    pub fn set_demodulator(&mut self, demodulator: SoftDemodulator<S>) {
+        self.demodulator = demodulator;
+    }
+
+    /// Set the noise level from Eb/N0 in dB, overriding `Impairments::noise`.
+    pub fn set_ebn0_db(&mut self, ebn0_db: Option<f32>) {
+        self.ebn0_db = ebn0_db;
+    }
+
+    /// Transmit `count` frames of the given type, returning how many were decoded correctly.
+    pub fn run(&mut self, frame_type: TestFrameType, count: u32) -> u32 {
+        let mut received = 0;
+        for _ in 0..count {
+            let (frame, expected) = self.random_frame(frame_type);
+            if self.transmit(frame, &expected) {
+                received += 1;
+            }
+        }
+        received
+    }
+
+    fn random_frame(&mut self, frame_type: TestFrameType) -> (ModulatorFrame, Frame) {
+        match frame_type {
+            TestFrameType::Lsf => {
+                let mut lsf = LsfFrame([0u8; 30]);
+                self.fill(&mut lsf.0);
+                lsf.recalculate_crc();
+                (ModulatorFrame::Lsf(lsf.clone()), Frame::Lsf(lsf))
+            }
+            TestFrameType::Stream => {
+                let mut stream = StreamFrame {
+                    lich_idx: self.prng.next_u8() % 6,
+                    frame_number: (self.prng.next_u32() & 0x7fff) as u16,
+                    ..Default::default()
+                };
+                self.fill(&mut stream.lich_part);
+                self.fill(&mut stream.stream_data);
+                (
+                    ModulatorFrame::Stream(stream.clone()),
+                    Frame::Stream(stream),
+                )
+            }
+            TestFrameType::Packet => {
+                let mut packet = PacketFrame {
+                    payload: [0u8; 25],
+                    counter: PacketFrameCounter::Frame {
+                        index: (self.prng.next_u8() % 32) as usize,
+                    },
+                };
+                self.fill(&mut packet.payload);
+                (
+                    ModulatorFrame::Packet(packet.clone()),
+                    Frame::Packet(packet),
+                )
+            }
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for b in buf {
+            *b = self.prng.next_u8();
+        }
+    }
+
+    /// Send a single transmission and report whether the expected frame came out the other end.
+    fn transmit(&mut self, frame: ModulatorFrame, expected: &Frame) -> bool {
+        // Preamble, frame, EOT plus filter flush fit comfortably
+        let mut samples = [0i16; 8000];
+        let mut len = 0;
+        let mut modulator = SoftModulator::new();
+        for f in [
+            ModulatorFrame::Preamble {
+                tx_delay: 0,
+                ptt_lead: 0,
+            },
+            frame,
+            ModulatorFrame::EndOfTransmission,
+        ] {
+            modulator.provide_next_frame(Some(f));
+            loop {
+                let n = modulator.read_output_samples(&mut samples[len..]);
+                if n == 0 {
+                    break;
+                }
+                len += n;
+            }
+        }
+
+        if let Some(ebn0_db) = self.ebn0_db {
+            let power = samples[0..len]
+                .iter()
+                .map(|s| *s as f32 * *s as f32)
+                .sum::<f32>()
+                / len as f32;
+            let mut impairments = self.channel.impairments().clone();
+            impairments.noise = noise_for_ebn0(power, ebn0_db);
+            self.channel.set_impairments(impairments);
+        }
+
+        let mut found = false;
+        let demodulator = &mut self.demodulator;
+        let mut demod = |s: i16| {
+            if let Some((decoded, _)) = demodulator.demod(s)
+                && decoded == *expected
+            {
+                found = true;
+            }
+        };
+        self.channel.process(&samples[0..len], &mut demod);
+        // Silence before the next transmission, which also lets the last frame be decoded
+        self.channel.process(&[0i16; SAMPLES_PER_FRAME], &mut demod);
+        found
+    }
+}
+
+/// Standard deviation of noise that gives the requested Eb/N0 for a signal of mean power
+/// `signal_power`, in the units used by `Impairments::noise`.
+///
+/// The noise bandwidth is the full sample rate, with `SAMPLES_PER_SYMBOL` samples per 2-bit symbol.
+pub fn noise_for_ebn0(signal_power: f32, ebn0_db: f32) -> f32 {
+    const BITS_PER_SYMBOL: f32 = 2.0;
+    let es_n0 = db_to_ratio(ebn0_db) * BITS_PER_SYMBOL;
+    sqrt(signal_power * SAMPLES_PER_SYMBOL as f32 / (2.0 * es_n0))
+}
+
+/// 10^(db/10), without needing `powf` in `no_std`.
+fn db_to_ratio(db: f32) -> f32 {
+    // 2^x where x = db * log2(10) / 10, split into integer and fractional parts
+    let x = db * 0.332_192_8;
+    let mut whole = x as i32;
+    if whole as f32 > x {
+        whole -= 1;
+    }
+    let f = x - whole as f32;
+    let frac = 1.0
+        + f * (core::f32::consts::LN_2 + f * (0.240_226_5 + f * (0.055_504_1 + f * 0.009_618_1)));
+    let whole = f32::from_bits(((whole.clamp(-126, 127) + 127) as u32) << 23);
+    whole * frac
+}
+
+/// Square root by Newton's method, without needing `sqrt` in `no_std`.
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let mut guess = f32::from_bits((x.to_bits() >> 1) + 0x1fc0_0000);
+    for _ in 0..4 {
+        guess = 0.5 * (guess + x / guess);
+    }
+    guess
+}
+
+/// Approximately normally distributed value with mean 0 and standard deviation 1.
+///
+/// Sum of 12 uniform values, which avoids needing `ln` and `cos` in `no_std`.
+fn gaussian(prng: &mut Prng) -> f32 {
+    let mut sum = 0.0;
+    for _ in 0..12 {
+        sum += prng.next_u32() as f32 / u32::MAX as f32;
+    }
+    sum - 6.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(impairments: Impairments, input: &[i16]) -> Vec<i16> {
+        let mut channel = Channel::new(impairments, 1);
+        let mut out = vec![];
+        channel.process(input, |s| out.push(s));
+        out
+    }
+
+    #[test]
+    fn perfect_channel() {
+        let input: Vec<i16> = (0..100).map(|i| i * 100).collect();
+        let out = run(Impairments::new(), &input);
+        assert_eq!(&out, &input[..99]);
+
+        let delayed = run(
+            Impairments {
+                timing_offset: 0.5,
+                ..Impairments::new()
+            },
+            &input,
+        );
+        assert_eq!(delayed[0], 0);
+        assert_eq!(delayed[1], 50);
+        assert_eq!(delayed[2], 150);
+    }
+
+    #[test]
+    fn clock_skew() {
+        let input = [1000i16; 100_000];
+        let fast = run(
+            Impairments {
+                clock_skew_ppm: 100.0,
+                ..Impairments::new()
+            },
+            &input,
+        );
+        let slow = run(
+            Impairments {
+                clock_skew_ppm: -100.0,
+                ..Impairments::new()
+            },
+            &input,
+        );
+        assert!(fast.len().abs_diff(99_990) <= 1);
+        assert!(slow.len().abs_diff(100_010) <= 1);
+    }
+
+    #[test]
+    fn noise_and_offset() {
+        let out = run(
+            Impairments {
+                noise: 1000.0,
+                frequency_offset_hz: 240.0,
+                ..Impairments::new()
+            },
+            &[0i16; 10_000],
+        );
+        let mean = out.iter().map(|s| *s as f32).sum::<f32>() / out.len() as f32;
+        let variance = out
+            .iter()
+            .map(|s| (*s as f32 - mean) * (*s as f32 - mean))
+            .sum::<f32>()
+            / out.len() as f32;
+        assert!((mean - OUTER_SYMBOL_LEVEL / 10.0).abs() < 50.0);
+        assert!((variance.sqrt() - 1000.0).abs() < 50.0);
+    }
+
+    #[test]
+    fn fading() {
+        let out = run(
+            Impairments {
+                fade_depth: 0.75,
+                fade_period: 1000,
+                ..Impairments::new()
+            },
+            &[10000i16; 2000],
+        );
+        assert_eq!(*out.iter().min().unwrap(), 2500);
+        assert_eq!(*out.iter().max().unwrap(), 10000);
+    }
+
+    #[test]
+    fn math_approximations() {
+        for (db, ratio) in [(0.0, 1.0), (3.0103, 2.0), (10.0, 10.0), (-20.0, 0.01)] {
+            assert!(
+                (db_to_ratio(db) / ratio - 1.0).abs() < 0.001,
+                "{db} -> {ratio}"
+            );
+        }
+        for x in [0.01, 1.0, 2.0, 12345.0] {
+            assert!((sqrt(x) - x.sqrt()).abs() / x.sqrt() < 0.0001, "{x}");
+        }
+    }
+
+    #[test]
+    fn frame_error_rate() {
+        for frame_type in [
+            TestFrameType::Lsf,
+            TestFrameType::Stream,
+            TestFrameType::Packet,
+        ] {
+            let mut clean = FrameErrorTest::new(
+                Impairments {
+                    timing_offset: 0.5,
+                    deviation_error: -0.1,
+                    frequency_offset_hz: 50.0,
+                    ..Impairments::new()
+                },
+                1,
+            );
+            assert_eq!(clean.run(frame_type, 5), 5, "{frame_type:?}");
+
+            let mut noisy = FrameErrorTest::new(Impairments::new(), 1);
+            noisy.set_ebn0_db(Some(-5.0));
+            assert!(noisy.run(frame_type, 5) < 5, "{frame_type:?}");
+        }
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn fixed_point_frame_error_rate() {
+        let mut test = FrameErrorTest::new_with_demodulator(
+            Impairments {
+                timing_offset: 0.5,
+                deviation_error: -0.1,
+                frequency_offset_hz: 50.0,
+                ..Impairments::new()
+            },
+            1,
+            SoftDemodulator::new_fixed_point_decimating(2),
+        );
+        assert_eq!(test.run(TestFrameType::Stream, 5), 5);
+    }
+}
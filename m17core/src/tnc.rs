@@ -1,25 +1,112 @@
 use crate::address::{Address, Callsign};
 use crate::kiss::{
-    KissBuffer, KissCommand, KissFrame, PORT_PACKET_BASIC, PORT_PACKET_FULL, PORT_STREAM,
+    HW_BASIC_PACKETS, HW_CALIBRATION, HW_DUTY_CYCLE, HW_PREAMBLE, HW_PTT_TIMING, HW_QUEUE_STATUS,
+    HW_SIGNAL_QUALITY, HW_TX_LEVEL, HW_TX_LOCKOUT, HW_TX_TIMEOUT, KissBuffer, KissCommand,
+    KissFrame, PORT_PACKET_BASIC, PORT_PACKET_FULL, PORT_STREAM, PacketDiscardReason, QueueStatus,
+    StreamEndReason, StreamLostReason, TxLimitReason,
 };
-use crate::modem::ModulatorFrame;
+use crate::modem::{
+    CalibrationPattern, ModulatorFrame, SAMPLE_RATE, SAMPLES_PER_FRAME, SAMPLES_PER_MS,
+    SignalQuality,
+};
+use crate::prng::Prng;
 use crate::protocol::{
-    Frame, LichCollection, LsfFrame, Mode, PacketFrame, PacketFrameCounter, StreamFrame,
+    DataType, EncryptionType, Frame, LichCollection, LsfFrame, Mode, PacketFrame,
+    PacketFrameCounter, StreamFrame,
 };
+use crate::ring::RingBuffer;
+
+/// Default number of packets that may be queued for transmission.
+pub const DEFAULT_PACKET_QUEUE_LEN: usize = 4;
+
+/// Default number of stream frames that may be queued for transmission - 1.28 seconds.
+pub const DEFAULT_STREAM_QUEUE_LEN: usize = 32;
+
+/// A stream frame up to this many numbers behind the expected one is treated as a duplicate
+/// rather than the start of a new transmission.
+const STREAM_DUPLICATE_WINDOW: u16 = 8;
+
+/// Abandon a partly received packet if no frame arrives for this many samples - 250 ms.
+const PACKET_RX_TIMEOUT: u64 = 250 * SAMPLES_PER_MS as u64;
+
+/// Consider a stream lost if no frame arrives for this many samples - 2 seconds.
+const STREAM_RX_TIMEOUT: u64 = 2 * SAMPLE_RATE as u64;
+
+/// Frames in the same transmission should be decoded within this many samples of a whole
+/// number of frame lengths apart.
+const FRAME_TIMING_TOLERANCE: u64 = 20;
+
+/// Number of stream frames to hold while acquiring LICH from an ongoing stream - 480 ms.
+const ACQUIRE_BUFFER_LEN: usize = 12;
+
+/// Number of KISS frames that may be waiting to be read by the host.
+///
+/// This must be able to hold a stream setup plus a full acquisition buffer of frames, each
+/// preceded by its quality report.
+const OUTGOING_KISS_LEN: usize = 2 * ACQUIRE_BUFFER_LEN + 4;
+
+/// Encoded KISS frames up to this many bytes wait for the host in a compact slot. Everything
+/// except received packets fits, even with worst-case escaping.
+const SHORT_KISS_LEN: usize = 72;
+
+/// Number of received packets that may be waiting to be read by the host, enough for the full
+/// and basic copies of one packet.
+const OUTGOING_PACKETS_LEN: usize = 2;
+
+/// If the host falls behind during a stream, send up to this many frames of padding (200 ms)
+/// while waiting for it to catch up before ending the transmission ourselves.
+const STREAM_UNDERRUN_GRACE_FRAMES: u8 = 5;
+
+/// Start padding when the modulator is within this many samples of running out of audio.
+const STREAM_UNDERRUN_MARGIN: u64 = SAMPLES_PER_FRAME as u64 / 2;
+
+/// One 20 ms frame of silence in Codec2 3200 mode, as used by other M17 implementations.
+const CODEC2_3200_SILENCE: [u8; 8] = [0x01, 0x00, 0x09, 0x43, 0x9c, 0xe4, 0x21, 0x08];
+
+/// Once the time-out timer has expired, allow this many samples (2 seconds) for the end of the
+/// transmission to play out before releasing PTT regardless.
+const TX_TIMEOUT_GRACE: u64 = 2 * SAMPLE_RATE as u64;
 
 /// Handles the KISS protocol and frame management for `SoftModulator` and `SoftDemodulator`.
 ///
 /// These components work alongside each other. User is responsible for chaining them together
 /// or doing something else with the data.
-pub struct SoftTnc {
+///
+/// The depth of the transmit queues is set by the const parameters. Each queued packet costs
+/// roughly 850 bytes and each stream frame roughly 30 bytes. The defaults keep the whole TNC
+/// under 16 KB for memory-constrained targets; hosts sending packets in bulk will want a deeper
+/// packet queue. Use `new()` for the defaults or `new_with_queues()` to choose.
+///
+/// When a queue is full, new packets are rejected, while stream data discards the oldest queued
+/// frame to keep latency bounded. Either way the host is notified with a `HW_QUEUE_STATUS` report.
+pub struct SoftTnc<
+    const PACKET_QUEUE_LEN: usize = DEFAULT_PACKET_QUEUE_LEN,
+    const STREAM_QUEUE_LEN: usize = DEFAULT_STREAM_QUEUE_LEN,
+> {
     /// Handle framing of KISS commands from the host, which may arrive in arbitrary binary blobs.
     kiss_buffer: KissBuffer,
 
-    /// Kiss message that needs to be sent to the host.
-    outgoing_kiss: Option<OutgoingKiss>,
+    /// Kiss messages that need to be sent to the host.
+    outgoing_kiss: RingBuffer<OutgoingKiss, OUTGOING_KISS_LEN>,
+
+    /// Full-size KISS frames for the `OutgoingKiss::Packet` entries in `outgoing_kiss`.
+    outgoing_packets: RingBuffer<KissFrame, OUTGOING_PACKETS_LEN>,
+
+    /// Current RX function of the TNC.
+    rx_state: RxState,
 
-    /// Current RX or TX function of the TNC.
-    state: State,
+    /// Current TX function of the TNC.
+    ///
+    /// This is independent of `rx_state` so that in full duplex mode we can continue to decode
+    /// incoming frames while we are transmitting.
+    tx_state: TxState,
+
+    /// Frames we have recently given to the modulator, so we can recognise our own transmissions
+    /// if they are picked up by the demodulator in full duplex mode.
+    recent_tx: [Option<Frame>; RECENT_TX_LEN],
+
+    /// Next slot to fill in `recent_tx`
+    recent_tx_next: usize,
 
     /// Latest state of data carrier detect from demodulator - controls whether we can go to TX
     dcd: bool,
@@ -27,44 +114,57 @@ pub struct SoftTnc {
     /// If CSMA declined to transmit into an idle slot, at what point do we next check it?
     next_csma_check: Option<u64>,
 
-    /// Current monotonic time, counted in samples
-    now: u64,
+    /// Persistence parameter P. When the channel is clear, we transmit in a given slot with
+    /// probability (P+1)/256. Default 63, i.e., 25%.
+    persistence: u8,
+
+    /// CSMA slot time raw value, number of 10ms units. Default 4, i.e., one 40ms M17 frame.
+    slot_time: u8,
 
-    // TODO: use a static ring buffer crate of some sort?
-    /// Circular buffer of packets enqueued for transmission
-    packet_queue: [PendingPacket; 4],
+    /// Source of randomness for CSMA decisions.
+    prng: Prng,
 
-    /// Next slot to fill
-    packet_next: usize,
+    /// Current monotonic time, counted in samples
+    now: u64,
 
-    /// Current packet index, which is either partly transmitted or not transmitted at all.
-    packet_curr: usize,
+    /// Packets enqueued for transmission. The front packet may be partly transmitted.
+    packet_queue: RingBuffer<PendingPacket, PACKET_QUEUE_LEN>,
 
-    /// If true, packet_next == packet_curr implies full queue. packet_next is invalid.
-    /// If false, it implies empty queue.
-    packet_full: bool,
+    /// Number of packets rejected because the queue was full.
+    packets_dropped: u16,
 
     /// The LSF for a stream we are going to start transmitting.
     ///
     /// This serves as a general indicator that we want to tx a stream.
     stream_pending_lsf: Option<LsfFrame>,
 
-    /// Circular buffer of stream data enqueued for transmission.
+    /// Stream data enqueued for transmission.
     ///
     /// When the queue empties out, we hope that the last one has the end-of-stream flag set.
     /// Otherwise a buffer underrun has occurred.
     ///
     /// Overruns are less troublesome - we can drop frames and receiving stations should cope.
-    stream_queue: [StreamFrame; 8],
+    stream_queue: RingBuffer<StreamFrame, STREAM_QUEUE_LEN>,
+
+    /// Number of stream frames discarded because the queue was full.
+    stream_frames_dropped: u16,
+
+    /// LSF of the stream currently being transmitted, used to fill in LICH for padding frames.
+    stream_tx_lsf: Option<LsfFrame>,
+
+    /// Frame number to give to the next stream frame we transmit, once known.
+    ///
+    /// We number frames ourselves so that padding doesn't cause duplicates at the receiver.
+    stream_tx_frame_number: Option<u16>,
 
-    /// Next slot to fill
-    stream_next: usize,
+    /// LICH index to use for the next padding frame.
+    stream_tx_lich_idx: u8,
 
-    /// Current unsent stream frame index
-    stream_curr: usize,
+    /// Estimated time at which the modulator will run out of stream audio to send.
+    stream_tx_deadline: u64,
 
-    /// True if stream_next == stream_curr because the queue is full. stream_next is invalid.
-    stream_full: bool,
+    /// Number of consecutive padding frames sent since the host last supplied data.
+    stream_tx_padding: u8,
 
     /// Should PTT be on right now? Polled by external
     ptt: bool,
@@ -72,65 +172,243 @@ pub struct SoftTnc {
     /// TxDelay raw value, number of 10ms units. We will optimistically start with default 0.
     tx_delay: u8,
 
-    /// This is a full duplex channel so we do not need to monitor DCD or use CSMA. Default false.
+    /// TxTail raw value, number of 10ms units to keep PTT on after the transmission. Default 0.
+    tx_tail: u8,
+
+    /// Number of 40ms preamble frames at the start of each transmission. Default 1.
+    preamble_frames: u8,
+
+    /// Extra 40ms preamble frames to give a radio keyed by VOX time to key up. Default 0.
+    vox_lead_in_frames: u8,
+
+    /// Preamble frames still to be sent before the data in the current transmission.
+    tx_preamble_remaining: u8,
+
+    /// Minimum time in milliseconds from engaging PTT to the start of the audio, on top of
+    /// TxDelay. Default 0.
+    ptt_lead: u16,
+
+    /// Time in milliseconds to keep PTT on after the audio has finished, on top of TxTail.
+    /// Default 0.
+    ptt_lag: u16,
+
+    /// This is a full duplex channel so we do not need to monitor DCD or use CSMA, and we can
+    /// continue receiving while we transmit. Default false.
     full_duplex: bool,
+
+    /// Transmissions lasting longer than this many samples are ended early. Zero disables.
+    tx_timeout: u64,
+
+    /// After the time-out timer triggers, refuse to transmit for this many samples.
+    tx_lockout: u64,
+
+    /// Time at which PTT was most recently engaged.
+    tx_started: u64,
+
+    /// If set, we are locked out from transmitting until this time.
+    tx_locked_until: Option<u64>,
+
+    /// Rolling record of how much we have been transmitting.
+    duty_cycle: DutyCycle,
+
+    /// True if we have told the host that transmissions are being limited and we have not yet
+    /// told them that the limit has cleared.
+    tx_limit_reported: bool,
+
+    /// Transmission was cut short, so ignore the rest of the stream data until the next LSF.
+    discard_stream_data: bool,
+
+    /// KISS port that most recently supplied data for transmission. Limit reports go here.
+    last_tx_port: u8,
+
+    /// Sequencing statistics for received streams.
+    rx_stream_stats: RxStreamStats,
+
+    /// Also deliver compatible received packets on port 0 for basic KISS applications.
+    /// Default true.
+    basic_packets: bool,
+
+    /// Send the host a signal quality report for each received frame. Default false.
+    report_quality: bool,
+
+    /// Time at which we last received a frame, for RX timeouts.
+    last_rx_frame: u64,
+
+    /// Demodulator sample count when we last received a frame, if known.
+    last_rx_sample: Option<u64>,
+
+    /// Test pattern the host has asked us to transmit, if any. Takes priority over queued data.
+    calibration: Option<CalibrationPattern>,
+
+    /// Transmit audio level as a percentage of the default. Polled by external. Default 100.
+    tx_level: u8,
+}
+
+/// Counters describing how cleanly streams have been received.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RxStreamStats {
+    /// Stream frames passed to the host.
+    pub frames_received: u32,
+    /// Gaps in frame numbering, i.e., frames we failed to decode.
+    pub frames_lost: u32,
+    /// Frames discarded because we had already received that frame number.
+    pub frames_duplicated: u32,
+    /// Times the LICH showed a different station had taken over an ongoing stream.
+    pub stations_changed: u32,
 }
 
 impl SoftTnc {
+    /// Create a TNC with the default queue depths.
     pub fn new() -> Self {
+        Self::new_with_queues()
+    }
+}
+
+impl<const PACKET_QUEUE_LEN: usize, const STREAM_QUEUE_LEN: usize>
+    SoftTnc<PACKET_QUEUE_LEN, STREAM_QUEUE_LEN>
+{
+    /// Create a TNC with queue depths given by the const parameters.
+    pub fn new_with_queues() -> Self {
         Self {
             kiss_buffer: KissBuffer::new(),
-            outgoing_kiss: None,
-            state: State::Idle,
+            outgoing_kiss: RingBuffer::new(),
+            outgoing_packets: RingBuffer::new(),
+            rx_state: RxState::Idle,
+            tx_state: TxState::Idle,
+            recent_tx: Default::default(),
+            recent_tx_next: 0,
             dcd: false,
             next_csma_check: None,
+            persistence: 63,
+            slot_time: 4,
+            prng: Prng::default(),
             now: 0,
-            packet_queue: Default::default(),
-            packet_next: 0,
-            packet_curr: 0,
-            packet_full: false,
+            packet_queue: RingBuffer::new(),
+            packets_dropped: 0,
             stream_pending_lsf: None,
-            stream_queue: Default::default(),
-            stream_next: 0,
-            stream_curr: 0,
-            stream_full: false,
+            stream_queue: RingBuffer::new(),
+            stream_frames_dropped: 0,
+            stream_tx_lsf: None,
+            stream_tx_frame_number: None,
+            stream_tx_lich_idx: 0,
+            stream_tx_deadline: 0,
+            stream_tx_padding: 0,
             ptt: false,
             tx_delay: 0,
+            tx_tail: 0,
+            preamble_frames: 1,
+            vox_lead_in_frames: 0,
+            tx_preamble_remaining: 0,
+            ptt_lead: 0,
+            ptt_lag: 0,
             full_duplex: false,
+            tx_timeout: 0,
+            tx_lockout: 0,
+            tx_started: 0,
+            tx_locked_until: None,
+            duty_cycle: DutyCycle::new(),
+            tx_limit_reported: false,
+            discard_stream_data: false,
+            last_tx_port: PORT_PACKET_FULL,
+            rx_stream_stats: RxStreamStats::default(),
+            basic_packets: true,
+            report_quality: false,
+            last_rx_frame: 0,
+            last_rx_sample: None,
+            calibration: None,
+            tx_level: 100,
         }
     }
 
     /// Process an individual `Frame` that has been decoded by the modem.
     pub fn handle_frame(&mut self, frame: Frame) {
-        if self.ptt {
+        self.handle_frame_inner(frame, None, None);
+    }
+
+    /// Process a `Frame` along with the demodulator's sample count at the time it was decoded.
+    ///
+    /// Frames within a single transmission arrive at exact multiples of the frame length apart,
+    /// so this timing lets us notice frames from a different station that would otherwise be
+    /// mixed into the packet or stream we are receiving.
+    pub fn handle_frame_at(&mut self, frame: Frame, rx_sample: u64) {
+        self.handle_frame_inner(frame, Some(rx_sample), None);
+    }
+
+    /// Process a `Frame` with its timing and the demodulator's assessment of signal quality.
+    ///
+    /// If the host has asked for them, quality reports are sent ahead of the KISS frames they
+    /// describe. A packet is reported as a whole once it is complete.
+    pub fn handle_frame_with_quality(
+        &mut self,
+        frame: Frame,
+        rx_sample: u64,
+        quality: SignalQuality,
+    ) {
+        self.handle_frame_inner(frame, Some(rx_sample), Some(quality));
+    }
+
+    fn handle_frame_inner(
+        &mut self,
+        frame: Frame,
+        rx_sample: Option<u64>,
+        quality: Option<SignalQuality>,
+    ) {
+        if !self.full_duplex && self.ptt {
             // Ignore self-decodes
             return;
         }
+        if self.full_duplex && self.recent_tx.iter().flatten().any(|f| *f == frame) {
+            // We can't mute the receiver, so recognise our own frames by their contents
+            return;
+        }
+        self.last_rx_frame = self.now;
+        if !matches!(frame, Frame::Lsf(_))
+            && let (Some(sample), Some(last)) = (rx_sample, self.last_rx_sample)
+        {
+            let frame_len = SAMPLES_PER_FRAME as u64;
+            let offset = sample.saturating_sub(last) % frame_len;
+            if offset > FRAME_TIMING_TOLERANCE && offset < frame_len - FRAME_TIMING_TOLERANCE {
+                log::debug!("frame timing is off by {offset} samples, must be a different station");
+                self.abandon_rx(PacketDiscardReason::ForeignFrame);
+            }
+        }
+        self.last_rx_sample = rx_sample;
         match frame {
             Frame::Lsf(lsf) => {
                 // A new LSF implies a clean slate.
                 // If we were partway through decoding something else then we missed it.
+                self.abandon_rx(PacketDiscardReason::Interrupted);
+                if lsf.check_crc() != 0 {
+                    log::debug!("LSF failed CRC");
+                    self.rx_state = RxState::BadLsf(lsf);
+                    return;
+                }
                 match lsf.mode() {
                     Mode::Packet => {
-                        self.state = State::RxPacket(RxPacketState {
+                        self.rx_state = RxState::Packet(RxPacketState {
                             lsf,
                             packet: [0u8; 825],
                             count: 0,
+                            quality,
                         })
                     }
                     Mode::Stream => {
+                        self.quality_to_host(PORT_STREAM, quality);
                         let kiss = KissFrame::new_stream_setup(&lsf.0).unwrap();
                         self.kiss_to_host(kiss);
-                        self.state = State::RxStream(RxStreamState {
-                            _lsf: lsf,
-                            index: 0,
-                        });
+                        self.rx_state = RxState::Stream(RxStreamState::new(lsf, 0));
                     }
                 }
             }
             Frame::Packet(packet) => {
-                match &mut self.state {
-                    State::RxPacket(rx) => {
+                match &mut self.rx_state {
+                    RxState::Packet(rx) => {
+                        if let Some(q) = quality {
+                            match &mut rx.quality {
+                                Some(existing) => existing.combine(&q),
+                                None => rx.quality = Some(q),
+                            }
+                        }
                         match packet.counter {
                             PacketFrameCounter::Frame { index } => {
                                 if index == rx.count && index < 32 {
@@ -139,7 +417,7 @@ impl SoftTnc {
                                     rx.count += 1;
                                 } else {
                                     // unexpected order - something has gone wrong
-                                    self.state = State::Idle;
+                                    self.abandon_rx(PacketDiscardReason::BadSequence);
                                 }
                             }
                             PacketFrameCounter::FinalFrame { payload_len } => {
@@ -147,192 +425,403 @@ impl SoftTnc {
                                 let end = start + payload_len;
                                 rx.packet[start..(start + payload_len)]
                                     .copy_from_slice(&packet.payload[0..payload_len]);
-                                // TODO: compatible packets should be sent on port 0 too
                                 let kiss =
                                     KissFrame::new_full_packet(&rx.lsf.0, &rx.packet[0..end])
                                         .unwrap();
+                                let basic = if self.basic_packets {
+                                    basic_packet_payload(&rx.lsf, &rx.packet[0..end])
+                                        .and_then(|p| KissFrame::new_basic_packet(p).ok())
+                                } else {
+                                    None
+                                };
+                                let packet_quality = rx.quality;
+                                self.quality_to_host(PORT_PACKET_FULL, packet_quality);
                                 self.kiss_to_host(kiss);
-                                self.state = State::Idle;
+                                if let Some(basic) = basic {
+                                    self.kiss_to_host(basic);
+                                }
+                                self.rx_state = RxState::Idle;
                             }
                         }
                     }
+                    RxState::BadLsf(lsf) => {
+                        // Without a valid LSF we can't tell the host who sent this packet
+                        let kiss = KissFrame::new_packet_discarded(
+                            PORT_PACKET_FULL,
+                            PacketDiscardReason::BadLsf,
+                            0,
+                            &lsf.0,
+                        )
+                        .unwrap();
+                        self.kiss_to_host(kiss);
+                        self.rx_state = RxState::Idle;
+                    }
                     _ => {
                         // Invalid transition
-                        self.state = State::Idle;
+                        self.rx_state = RxState::Idle;
                     }
                 }
             }
             Frame::Stream(stream) => {
-                match &mut self.state {
-                    State::RxStream(rx) => {
-                        // TODO: consider wraparound from 0x7fff
-                        if stream.frame_number < rx.index {
-                            let mut lich = LichCollection::new();
-                            lich.set_segment(stream.lich_idx, stream.lich_part);
-                            self.state = State::RxAcquiringStream(RxAcquiringStreamState { lich });
-                        } else {
-                            rx.index = stream.frame_number + 1;
-                            let kiss = KissFrame::new_stream_data(&stream).unwrap();
-                            self.kiss_to_host(kiss);
-                            // TODO: end stream if LICH updates indicate non-META part has changed
-                            // (this implies a new station)
-                            if stream.end_of_stream {
-                                self.state = State::Idle;
+                match &mut self.rx_state {
+                    RxState::Stream(rx) => {
+                        let lost = match rx.sequence(stream.frame_number) {
+                            StreamSequence::Next { lost } => lost,
+                            StreamSequence::Duplicate => {
+                                log::debug!("duplicate stream frame {}", stream.frame_number);
+                                self.rx_stream_stats.frames_duplicated += 1;
+                                return;
                             }
+                            StreamSequence::Restarted => {
+                                // Too far backwards to be a late frame - must be a new transmission
+                                self.kiss_to_host(KissFrame::new_stream_lost(
+                                    PORT_STREAM,
+                                    StreamLostReason::Restarted,
+                                ));
+                                self.rx_state = RxState::AcquiringStream(
+                                    RxAcquiringStreamState::new(stream, quality),
+                                );
+                                return;
+                            }
+                        };
+                        rx.index = (stream.frame_number + 1) & 0x7fff;
+                        let new_lsf = rx.update_lich(stream.lich_idx, stream.lich_part);
+                        if lost > 0 {
+                            log::debug!("lost {lost} stream frames");
+                        }
+                        self.rx_stream_stats.frames_lost += lost as u32;
+                        self.rx_stream_stats.frames_received += 1;
+                        if let Some(lsf) = new_lsf {
+                            // A different station has taken over the channel without a new LSF
+                            log::debug!("LICH indicates a new station mid-stream");
+                            self.rx_stream_stats.stations_changed += 1;
+                            let kiss = KissFrame::new_stream_setup(&lsf.0).unwrap();
+                            self.kiss_to_host(kiss);
+                        }
+                        self.quality_to_host(PORT_STREAM, quality);
+                        let kiss = KissFrame::new_stream_data(&stream).unwrap();
+                        self.kiss_to_host(kiss);
+                        if stream.end_of_stream {
+                            self.rx_state = RxState::Idle;
                         }
                     }
-                    State::RxAcquiringStream(rx) => {
+                    RxState::AcquiringStream(rx) => {
                         rx.lich.set_segment(stream.lich_idx, stream.lich_part);
-                        if let Some(maybe_lsf) = rx.lich.try_assemble() {
-                            let lsf = LsfFrame(maybe_lsf);
+                        let lsf = match rx.lich.try_assemble() {
                             // LICH can change mid-transmission so wait until the CRC is correct
                             // to ensure (to high probability) we haven't done a "torn read"
-                            if lsf.check_crc() == 0 {
-                                let kiss = KissFrame::new_stream_setup(&lsf.0).unwrap();
-                                self.kiss_to_host(kiss);
-                                // TODO: avoid discarding the first data payload here
-                                // need a queue depth of 2 for outgoing kiss
-                                self.state = State::RxStream(RxStreamState {
-                                    _lsf: lsf,
-                                    index: stream.frame_number + 1,
-                                });
+                            Some(maybe_lsf) if LsfFrame(maybe_lsf).check_crc() == 0 => {
+                                LsfFrame(maybe_lsf)
+                            }
+                            _ => {
+                                rx.frames.push_back_overwrite((stream, quality));
+                                return;
+                            }
+                        };
+                        let mut buffered = core::mem::take(&mut rx.frames);
+                        let kiss = KissFrame::new_stream_setup(&lsf.0).unwrap();
+                        self.kiss_to_host(kiss);
+                        // Pass on what we heard during acquisition so the start isn't lost
+                        while let Some((early, early_quality)) = buffered.pop_front() {
+                            let behind =
+                                stream.frame_number.wrapping_sub(early.frame_number) & 0x7fff;
+                            if behind == 0 || behind > ACQUIRE_BUFFER_LEN as u16 {
+                                // Not part of the run of frames leading up to this one
+                                continue;
                             }
+                            self.quality_to_host(PORT_STREAM, early_quality);
+                            let kiss = KissFrame::new_stream_data(&early).unwrap();
+                            self.kiss_to_host(kiss);
+                            self.rx_stream_stats.frames_received += 1;
                         }
+                        self.quality_to_host(PORT_STREAM, quality);
+                        let kiss = KissFrame::new_stream_data(&stream).unwrap();
+                        self.kiss_to_host(kiss);
+                        self.rx_stream_stats.frames_received += 1;
+                        self.rx_state = if stream.end_of_stream {
+                            RxState::Idle
+                        } else {
+                            RxState::Stream(RxStreamState::new(
+                                lsf,
+                                (stream.frame_number + 1) & 0x7fff,
+                            ))
+                        };
                     }
                     _ => {
                         // If coming from another state, we have missed something.
                         // Never mind, let's start tracking LICH.
-                        let mut lich = LichCollection::new();
-                        lich.set_segment(stream.lich_idx, stream.lich_part);
-                        self.state = State::RxAcquiringStream(RxAcquiringStreamState { lich })
+                        self.abandon_rx(PacketDiscardReason::Interrupted);
+                        self.rx_state =
+                            RxState::AcquiringStream(RxAcquiringStreamState::new(stream, quality));
                     }
                 }
             }
         }
     }
 
+    /// Seed the pseudo-random number generator used for CSMA.
+    ///
+    /// Stations sharing a channel should be seeded differently so that they do not make the
+    /// same transmit decisions in lockstep. Supplying a fixed seed gives deterministic behaviour,
+    /// which is useful for testing.
+    pub fn set_csma_seed(&mut self, seed: u32) {
+        self.prng = Prng::new(seed);
+    }
+
+    /// Send at least this much extra preamble at the start of each transmission, in milliseconds.
+    ///
+    /// A radio keyed by VOX only starts transmitting once it hears audio, so unlike TxDelay this
+    /// time is filled with preamble rather than silence. It is rounded up to whole 40 ms frames
+    /// and added to the preamble requested by the host.
+    pub fn set_vox_lead_in(&mut self, ms: u16) {
+        self.vox_lead_in_frames = ms.div_ceil(40).min(u8::MAX as u16) as u8;
+    }
+
     pub fn set_data_carrier_detect(&mut self, dcd: bool) {
         self.dcd = dcd;
     }
 
     pub fn set_now(&mut self, now_samples: u64) {
+        let tx_samples = if self.ptt {
+            now_samples.saturating_sub(self.now)
+        } else {
+            0
+        };
+        self.duty_cycle.advance(now_samples, tx_samples);
         self.now = now_samples;
-        // TODO: expose this to higher layer so we can schedule a precise delay
-        // rather than waiting for some soundcard I/O event
-        if let State::TxEndingAtTime(time) = self.state {
-            if now_samples >= time {
-                self.ptt = false;
-                self.state = State::Idle;
+        if let TxState::EndingAtTime(time) = self.tx_state
+            && now_samples >= time
+        {
+            self.ptt = false;
+            self.tx_state = TxState::Idle;
+        }
+        self.check_tx_limits();
+        self.check_rx_timeout();
+    }
+
+    /// Give up on whatever we were receiving if the frames have stopped arriving.
+    fn check_rx_timeout(&mut self) {
+        let timeout = match self.rx_state {
+            RxState::Idle => return,
+            RxState::Packet(_) | RxState::BadLsf(_) => PACKET_RX_TIMEOUT,
+            RxState::AcquiringStream(_) | RxState::Stream(_) => STREAM_RX_TIMEOUT,
+        };
+        if self.now.saturating_sub(self.last_rx_frame) > timeout {
+            log::debug!("rx timed out");
+            self.abandon_rx(PacketDiscardReason::Timeout);
+        }
+    }
+
+    /// Return to idle RX state. If we were partway through a packet or stream, tell the host it
+    /// was lost.
+    fn abandon_rx(&mut self, reason: PacketDiscardReason) {
+        match &self.rx_state {
+            RxState::Packet(rx) => {
+                let kiss = KissFrame::new_packet_discarded(
+                    PORT_PACKET_FULL,
+                    reason,
+                    rx.count as u8,
+                    &rx.lsf.0,
+                )
+                .unwrap();
+                self.kiss_to_host(kiss);
             }
+            RxState::Stream(_) => {
+                let reason = match reason {
+                    PacketDiscardReason::Timeout => StreamLostReason::Timeout,
+                    PacketDiscardReason::ForeignFrame => StreamLostReason::ForeignFrame,
+                    _ => StreamLostReason::Interrupted,
+                };
+                self.kiss_to_host(KissFrame::new_stream_lost(PORT_STREAM, reason));
+            }
+            _ => {}
         }
-        // TODO: should expire packet rx if we have not received a frame for a while
-        // otherwise we could pick up the LSF from one station and FinalFrame from another
+        self.rx_state = RxState::Idle;
     }
 
     pub fn ptt(&self) -> bool {
         self.ptt
     }
 
+    /// Transmit audio level requested by the host, as a percentage of the default.
+    pub fn tx_level(&self) -> u8 {
+        self.tx_level
+    }
+
+    /// Time at which PTT is due to be released, if the end of the transmission has been scheduled.
+    ///
+    /// Since PTT only changes during `set_now()`, the caller should make sure to update the time
+    /// promptly when this is reached rather than waiting for the next sound card event.
+    pub fn ptt_off_time(&self) -> Option<u64> {
+        match self.tx_state {
+            TxState::EndingAtTime(time) => Some(time),
+            TxState::StreamSentEndOfStream
+            | TxState::AbortingStream
+            | TxState::Aborting
+            | TxState::Ending
+                if self.tx_timeout > 0 =>
+            {
+                Some(self.tx_started + self.tx_timeout + TX_TIMEOUT_GRACE)
+            }
+            _ => None,
+        }
+    }
+
     pub fn set_tx_end_time(&mut self, in_samples: usize) {
         log::debug!("tnc has been told that tx will complete in {in_samples} samples");
-        if let State::TxEnding = self.state {
-            self.state = State::TxEndingAtTime(self.now + in_samples as u64);
+        if let TxState::Ending = self.tx_state {
+            let hold = (self.tx_tail as u64 * 10 + self.ptt_lag as u64) * SAMPLES_PER_MS as u64;
+            self.tx_state = TxState::EndingAtTime(self.now + in_samples as u64 + hold);
         }
     }
 
     pub fn read_tx_frame(&mut self) -> Option<ModulatorFrame> {
-        match self.state {
-            State::Idle | State::RxAcquiringStream(_) | State::RxStream(_) | State::RxPacket(_) => {
+        let frame = self.next_tx_frame();
+        match &frame {
+            Some(ModulatorFrame::Lsf(lsf)) => self.remember_tx(Frame::Lsf(lsf.clone())),
+            Some(ModulatorFrame::Stream(stream)) => self.remember_tx(Frame::Stream(stream.clone())),
+            Some(ModulatorFrame::Packet(packet)) => self.remember_tx(Frame::Packet(packet.clone())),
+            _ => (),
+        }
+        frame
+    }
+
+    /// Total preamble frames at the start of a transmission, including any VOX lead-in.
+    fn tx_preamble_frames(&self) -> u8 {
+        self.preamble_frames
+            .max(1)
+            .saturating_add(self.vox_lead_in_frames)
+    }
+
+    fn next_tx_frame(&mut self) -> Option<ModulatorFrame> {
+        if self.tx_preamble_remaining > 0
+            && matches!(
+                self.tx_state,
+                TxState::Stream | TxState::Packet | TxState::Calibration
+            )
+        {
+            self.tx_preamble_remaining -= 1;
+            return Some(ModulatorFrame::Preamble {
+                tx_delay: 0,
+                ptt_lead: 0,
+            });
+        }
+        match self.tx_state {
+            TxState::Idle => {
                 let stream_wants_to_tx = self.stream_pending_lsf.is_some();
-                let packet_wants_to_tx = self.packet_full || (self.packet_next != self.packet_curr);
-                if !stream_wants_to_tx && !packet_wants_to_tx {
+                let packet_wants_to_tx = !self.packet_queue.is_empty();
+                let calibration_wants_to_tx = self.calibration.is_some();
+                if !stream_wants_to_tx && !packet_wants_to_tx && !calibration_wants_to_tx {
                     return None;
                 }
 
                 // We have something we might send if the channel is free
 
-                // TODO: Proper full duplex support
-                // A true full duplex TNC should be able to rx and tx concurrently, implying
-                // separate states.
+                if self.tx_locked_until.is_some() || self.duty_cycle.exceeded() {
+                    if !self.tx_limit_reported {
+                        let reason = if self.tx_locked_until.is_some() {
+                            TxLimitReason::TimeOut
+                        } else {
+                            TxLimitReason::DutyCycle
+                        };
+                        self.report_tx_limit(reason);
+                    }
+                    return None;
+                }
+
                 if !self.full_duplex {
-                    match self.next_csma_check {
-                        None => {
-                            if self.dcd {
-                                self.next_csma_check = Some(self.now + 1920);
-                                return None;
-                            } else {
-                                // channel is idle at the moment we get a frame to send
-                                // go right ahead
-                            }
-                        }
-                        Some(at_time) => {
-                            if self.now < at_time {
-                                return None;
-                            }
-                            // 25% chance that we'll transmit this slot.
-                            // Using self.now as random is probably fine so long as it's not being set in
-                            // a lumpy manner. m17app's soundmodem should be fine.
-                            // TODO: bring in prng to help in cases where `now` never ends in 0b11
-                            let p1_4 = (self.now & 3) == 3;
-                            if !self.dcd || !p1_4 {
-                                self.next_csma_check = Some(self.now + 1920);
-                                return None;
-                            } else {
-                                self.next_csma_check = None;
-                            }
-                        }
+                    // The host asked for a test transmission so don't wait for the channel
+                    if !calibration_wants_to_tx && !self.csma_permits_tx() {
+                        return None;
                     }
+                    // Half duplex - whatever we were receiving, we won't hear the rest of it
+                    self.abandon_rx(PacketDiscardReason::Interrupted);
                 }
 
-                if stream_wants_to_tx {
-                    self.state = State::TxStream;
+                if calibration_wants_to_tx {
+                    self.tx_state = TxState::Calibration;
+                } else if stream_wants_to_tx {
+                    self.tx_state = TxState::Stream;
+                    self.stream_tx_deadline = self.now
+                        + (self.tx_delay as u64 * 10 + self.ptt_lead as u64)
+                            * SAMPLES_PER_MS as u64
+                        + self.tx_preamble_frames() as u64 * SAMPLES_PER_FRAME as u64;
+                    self.stream_tx_lsf = self.stream_pending_lsf.clone();
+                    self.stream_tx_frame_number = None;
+                    self.stream_tx_lich_idx = 0;
+                    self.stream_tx_padding = 0;
                 } else {
-                    self.state = State::TxPacket;
+                    self.tx_state = TxState::Packet;
                 }
                 self.ptt = true;
+                self.tx_started = self.now;
+                self.tx_preamble_remaining = self.tx_preamble_frames() - 1;
                 Some(ModulatorFrame::Preamble {
                     tx_delay: self.tx_delay,
+                    ptt_lead: self.ptt_lead,
                 })
             }
-            State::TxStream => {
-                if !self.stream_full && self.stream_next == self.stream_curr {
-                    return None;
+            TxState::Stream => {
+                if self.stream_queue.is_empty() {
+                    if self.now + STREAM_UNDERRUN_MARGIN < self.stream_tx_deadline {
+                        // Modulator still has audio to send, so the host has time to catch up
+                        return None;
+                    }
+                    self.extend_stream_deadline();
+                    if let Some(lsf) = self.stream_pending_lsf.take() {
+                        self.stream_tx_lsf = Some(lsf.clone());
+                        return Some(ModulatorFrame::Lsf(lsf));
+                    }
+                    return Some(ModulatorFrame::Stream(self.stream_padding_frame()));
                 }
+                self.extend_stream_deadline();
                 if let Some(lsf) = self.stream_pending_lsf.take() {
+                    self.stream_tx_lsf = Some(lsf.clone());
                     return Some(ModulatorFrame::Lsf(lsf));
                 }
-                let frame = self.stream_queue[self.stream_curr].clone();
-                if self.stream_full {
-                    self.stream_full = false;
-                }
-                self.stream_curr = (self.stream_curr + 1) % 8;
+                let mut frame = self.stream_queue.pop_front()?;
+                self.stream_tx_padding = 0;
+                let frame_number = *self
+                    .stream_tx_frame_number
+                    .get_or_insert(frame.frame_number);
+                frame.frame_number = frame_number;
+                self.stream_tx_frame_number = Some((frame_number + 1) & 0x7fff);
+                self.stream_tx_lich_idx = (frame.lich_idx + 1) % 6;
                 if frame.end_of_stream {
-                    self.state = State::TxStreamSentEndOfStream;
+                    self.tx_state = TxState::StreamSentEndOfStream;
                 }
                 Some(ModulatorFrame::Stream(frame))
             }
-            State::TxStreamSentEndOfStream => {
-                self.state = State::TxEnding;
+            TxState::AbortingStream => {
+                self.tx_state = TxState::StreamSentEndOfStream;
+                Some(ModulatorFrame::Stream(self.next_stream_tx_frame(true)))
+            }
+            TxState::StreamSentEndOfStream | TxState::Aborting => {
+                self.tx_state = TxState::Ending;
                 Some(ModulatorFrame::EndOfTransmission)
             }
-            State::TxPacket => {
-                if !self.packet_full && self.packet_next == self.packet_curr {
-                    return None;
+            TxState::Calibration => match self.calibration {
+                Some(pattern) => Some(ModulatorFrame::Calibration(pattern)),
+                None => {
+                    self.tx_state = TxState::Ending;
+                    Some(ModulatorFrame::EndOfTransmission)
                 }
-                while self.packet_next != self.packet_curr {
-                    match self.packet_queue[self.packet_curr].next_frame() {
+            },
+            TxState::Packet => {
+                while let Some(packet) = self.packet_queue.front_mut() {
+                    match packet.next_frame() {
                         Some(frame) => {
                             return Some(frame);
                         }
                         None => {
-                            self.packet_curr = (self.packet_curr + 1) % 4;
+                            self.packet_queue.pop_front();
                         }
                     }
                 }
-                self.state = State::TxEnding;
+                self.tx_state = TxState::Ending;
                 Some(ModulatorFrame::EndOfTransmission)
             }
-            State::TxEnding | State::TxEndingAtTime(_) => {
+            TxState::Ending | TxState::EndingAtTime(_) => {
                 // Once we have signalled EOT we withold any new frames until
                 // the channel fully clears and we are ready to TX again
                 None
@@ -340,24 +829,216 @@ impl SoftTnc {
         }
     }
 
+    /// Account for another 40 ms frame being handed to the modulator during a stream.
+    fn extend_stream_deadline(&mut self) {
+        self.stream_tx_deadline = self.stream_tx_deadline.max(self.now) + SAMPLES_PER_FRAME as u64;
+    }
+
+    /// The host has not given us stream data in time. Generate a frame with empty payload to keep
+    /// the transmission going, or end it if the grace period has run out.
+    fn stream_padding_frame(&mut self) -> StreamFrame {
+        let end_of_stream = self.stream_tx_padding >= STREAM_UNDERRUN_GRACE_FRAMES;
+        if end_of_stream {
+            log::debug!("stream underrun, ending transmission");
+            self.tx_state = TxState::StreamSentEndOfStream;
+            self.discard_stream_data = true;
+            self.report_stream_ended_early(StreamEndReason::Underrun);
+        } else {
+            log::debug!("stream underrun, sending padding frame");
+            self.stream_tx_padding += 1;
+        }
+        self.next_stream_tx_frame(end_of_stream)
+    }
+
+    /// Generate our own stream frame with a silent payload, continuing the numbering and LICH of
+    /// the stream we are transmitting.
+    ///
+    /// Voice streams get Codec2 3200 silence, since an all-zero payload decodes as noise. Anything
+    /// else, including the Codec2 1600 half of voice+data streams, is left as zeros.
+    fn next_stream_tx_frame(&mut self, end_of_stream: bool) -> StreamFrame {
+        let lich_idx = self.stream_tx_lich_idx;
+        let mut lich_part = [0u8; 5];
+        let mut stream_data = [0u8; 16];
+        if let Some(lsf) = &self.stream_tx_lsf {
+            let start = lich_idx as usize * 5;
+            lich_part.copy_from_slice(&lsf.0[start..start + 5]);
+            if lsf.data_type() == DataType::Voice {
+                stream_data[0..8].copy_from_slice(&CODEC2_3200_SILENCE);
+                stream_data[8..16].copy_from_slice(&CODEC2_3200_SILENCE);
+            }
+        }
+        let frame_number = self.stream_tx_frame_number.unwrap_or(0);
+        self.stream_tx_frame_number = Some((frame_number + 1) & 0x7fff);
+        self.stream_tx_lich_idx = (lich_idx + 1) % 6;
+        StreamFrame {
+            lich_idx,
+            lich_part,
+            frame_number,
+            end_of_stream,
+            stream_data,
+        }
+    }
+
+    /// Enforce the time-out timer and duty cycle limit, and notice when restrictions lift.
+    fn check_tx_limits(&mut self) {
+        let timed_out =
+            self.tx_timeout > 0 && self.now.saturating_sub(self.tx_started) >= self.tx_timeout;
+        match self.tx_state {
+            TxState::Stream | TxState::Packet | TxState::Calibration => {
+                if timed_out {
+                    log::debug!("transmit time-out timer triggered");
+                    self.abort_tx();
+                    self.tx_locked_until = Some(self.now + self.tx_lockout);
+                    self.report_tx_limit(TxLimitReason::TimeOut);
+                } else if self.duty_cycle.exceeded() {
+                    log::debug!("transmit duty cycle exceeded");
+                    self.abort_tx();
+                    self.report_tx_limit(TxLimitReason::DutyCycle);
+                }
+            }
+            TxState::StreamSentEndOfStream
+            | TxState::AbortingStream
+            | TxState::Aborting
+            | TxState::Ending
+            | TxState::EndingAtTime(_) => {
+                // We are already on the way out, but if the modulator never tells us when the
+                // transmission ends then PTT must still come off eventually
+                if timed_out
+                    && self.now.saturating_sub(self.tx_started)
+                        >= self.tx_timeout + TX_TIMEOUT_GRACE
+                {
+                    log::debug!("transmission did not end after time-out, releasing PTT");
+                    self.ptt = false;
+                    self.tx_state = TxState::Idle;
+                    if !self.tx_limit_reported {
+                        self.tx_locked_until = Some(self.now + self.tx_lockout);
+                        self.report_tx_limit(TxLimitReason::TimeOut);
+                    }
+                }
+            }
+            TxState::Idle => {}
+        }
+        if let Some(until) = self.tx_locked_until
+            && self.now >= until
+        {
+            self.tx_locked_until = None;
+        }
+        if self.tx_limit_reported && self.tx_locked_until.is_none() && !self.duty_cycle.exceeded() {
+            self.report_tx_limit(TxLimitReason::Cleared);
+        }
+    }
+
+    /// Cut the current transmission short, discarding whatever we were in the middle of sending.
+    fn abort_tx(&mut self) {
+        match self.tx_state {
+            TxState::Stream => {
+                // Receivers need an end-of-stream frame, unless we never got as far as the LSF
+                let lsf_sent = self.stream_pending_lsf.take().is_none();
+                self.stream_queue.clear();
+                self.discard_stream_data = true;
+                self.report_stream_ended_early(StreamEndReason::TxLimit);
+                self.tx_preamble_remaining = 0;
+                self.tx_state = if lsf_sent {
+                    TxState::AbortingStream
+                } else {
+                    TxState::Aborting
+                };
+                return;
+            }
+            TxState::Packet => {
+                // Receivers won't be able to use a partial packet
+                if self
+                    .packet_queue
+                    .front_mut()
+                    .map(|p| p.started())
+                    .unwrap_or(false)
+                {
+                    self.packet_queue.pop_front();
+                }
+            }
+            TxState::Calibration => {
+                // Don't key up again as soon as the limit clears
+                self.calibration = None;
+            }
+            _ => return,
+        }
+        self.tx_preamble_remaining = 0;
+        self.tx_state = TxState::Aborting;
+    }
+
+    fn quality_to_host(&mut self, port: u8, quality: Option<SignalQuality>) {
+        if let Some(quality) = quality
+            && self.report_quality
+        {
+            let kiss = KissFrame::new_signal_quality(port, &quality);
+            self.kiss_to_host(kiss);
+        }
+    }
+
+    fn report_stream_ended_early(&mut self, reason: StreamEndReason) {
+        let kiss = KissFrame::new_stream_ended_early(PORT_STREAM, reason);
+        self.kiss_to_host(kiss);
+    }
+
+    fn report_tx_limit(&mut self, reason: TxLimitReason) {
+        self.tx_limit_reported = reason != TxLimitReason::Cleared;
+        let seconds_remaining = match self.tx_locked_until {
+            Some(until) => (until.saturating_sub(self.now))
+                .div_ceil(SAMPLE_RATE as u64)
+                .min(u16::MAX as u64),
+            None => 0,
+        } as u16;
+        let kiss = KissFrame::new_tx_limit(self.last_tx_port, reason, seconds_remaining);
+        self.kiss_to_host(kiss);
+    }
+
+    /// p-persistent CSMA. Decide whether we may key up right now.
+    ///
+    /// While the channel is busy we keep deferring by one slot. Once it is clear we roll the dice
+    /// at the start of each slot and transmit with probability (P+1)/256.
+    fn csma_permits_tx(&mut self) -> bool {
+        if let Some(at_time) = self.next_csma_check
+            && self.now < at_time
+        {
+            return false;
+        }
+        let slot_samples = self.slot_time as u64 * 10 * SAMPLES_PER_MS as u64;
+        if self.dcd || self.prng.next_u8() > self.persistence {
+            self.next_csma_check = Some(self.now + slot_samples);
+            return false;
+        }
+        self.next_csma_check = None;
+        true
+    }
+
     /// Read KISS message to be sent to host.
     ///
     /// After each frame input, this should be consumed in a loop until length 0 is returned.
     /// This component will never block. Upstream interface can provide blocking `read()` if desired.
     pub fn read_kiss(&mut self, target_buf: &mut [u8]) -> usize {
-        match self.outgoing_kiss.as_mut() {
-            Some(outgoing) => {
-                let n = (outgoing.kiss_frame.len - outgoing.sent).min(target_buf.len());
-                target_buf[0..n]
-                    .copy_from_slice(&outgoing.kiss_frame.data[outgoing.sent..(outgoing.sent + n)]);
-                outgoing.sent += n;
-                if outgoing.sent == outgoing.kiss_frame.len {
-                    self.outgoing_kiss = None;
-                }
-                n
+        let Some(outgoing) = self.outgoing_kiss.front_mut() else {
+            return 0;
+        };
+        let (data, sent) = match outgoing {
+            OutgoingKiss::Short { data, len, sent } => (&data[0..*len as usize], sent),
+            OutgoingKiss::Packet { sent } => {
+                let Some(kiss_frame) = self.outgoing_packets.front_mut() else {
+                    // Can't happen - the two queues are pushed together
+                    self.outgoing_kiss.pop_front();
+                    return 0;
+                };
+                (kiss_frame.as_bytes(), sent)
             }
-            None => 0,
+        };
+        let n = (data.len() - *sent).min(target_buf.len());
+        target_buf[0..n].copy_from_slice(&data[*sent..(*sent + n)]);
+        *sent += n;
+        if *sent == data.len()
+            && let Some(OutgoingKiss::Packet { .. }) = self.outgoing_kiss.pop_front()
+        {
+            self.outgoing_packets.pop_front();
         }
+        n
     }
 
     /// Host sends in some KISS data.
@@ -383,6 +1064,27 @@ impl SoftTnc {
                 }
                 continue;
             }
+            if command == KissCommand::TxTail {
+                let mut new_tail = [0u8; 1];
+                if kiss_frame.decode_payload(&mut new_tail) == Ok(1) {
+                    self.tx_tail = new_tail[0];
+                }
+                continue;
+            }
+            if command == KissCommand::P {
+                let mut new_p = [0u8; 1];
+                if kiss_frame.decode_payload(&mut new_p) == Ok(1) {
+                    self.persistence = new_p[0];
+                }
+                continue;
+            }
+            if command == KissCommand::SlotTime {
+                let mut new_slot_time = [0u8; 1];
+                if kiss_frame.decode_payload(&mut new_slot_time) == Ok(1) {
+                    self.slot_time = new_slot_time[0];
+                }
+                continue;
+            }
             if command == KissCommand::FullDuplex {
                 let mut new_duplex = [0u8; 1];
                 if kiss_frame.decode_payload(&mut new_duplex) == Ok(1) {
@@ -390,13 +1092,54 @@ impl SoftTnc {
                 }
                 continue;
             }
+            if command == KissCommand::SetHardware {
+                let mut hw_payload = [0u8; 8];
+                let Ok(len) = kiss_frame.decode_payload(&mut hw_payload) else {
+                    continue;
+                };
+                match (hw_payload[0], len) {
+                    (HW_QUEUE_STATUS, 1) => self.report_queue_status(port),
+                    (HW_BASIC_PACKETS, 2) => self.basic_packets = hw_payload[1] != 0,
+                    (HW_SIGNAL_QUALITY, 2) => self.report_quality = hw_payload[1] != 0,
+                    (HW_CALIBRATION, 2) => {
+                        self.calibration = CalibrationPattern::from_proto(hw_payload[1]);
+                        self.last_tx_port = port;
+                    }
+                    (HW_TX_LEVEL, 2) => self.tx_level = hw_payload[1].min(200),
+                    (HW_PREAMBLE, 2) => self.preamble_frames = hw_payload[1],
+                    (HW_PTT_TIMING, 5) => {
+                        self.ptt_lead = u16::from_be_bytes([hw_payload[1], hw_payload[2]]);
+                        self.ptt_lag = u16::from_be_bytes([hw_payload[3], hw_payload[4]]);
+                    }
+                    (HW_TX_TIMEOUT, 3) => {
+                        let secs = u16::from_be_bytes([hw_payload[1], hw_payload[2]]);
+                        self.tx_timeout = secs as u64 * SAMPLE_RATE as u64;
+                    }
+                    (HW_TX_LOCKOUT, 3) => {
+                        let secs = u16::from_be_bytes([hw_payload[1], hw_payload[2]]);
+                        self.tx_lockout = secs as u64 * SAMPLE_RATE as u64;
+                    }
+                    (HW_DUTY_CYCLE, 4) => {
+                        let secs = u16::from_be_bytes([hw_payload[2], hw_payload[3]]);
+                        self.duty_cycle.configure(
+                            hw_payload[1],
+                            secs as u64 * SAMPLE_RATE as u64,
+                            self.now,
+                        );
+                    }
+                    _ => (),
+                }
+                continue;
+            }
             if command != KissCommand::DataFrame {
                 // Not supporting any other settings yet
-                // TODO: allow adjusting P persistence parameter for CSMA
                 continue;
             }
+            self.last_tx_port = port;
             if port == PORT_PACKET_BASIC {
-                if self.packet_full {
+                if self.packet_queue.is_full() {
+                    self.packets_dropped = self.packets_dropped.wrapping_add(1);
+                    self.report_queue_status(port);
                     continue;
                 }
                 let mut pending = PendingPacket::new();
@@ -412,13 +1155,11 @@ impl SoftTnc {
                     &Address::Callsign(Callsign(*b"M17RT-PKT")),
                     &Address::Broadcast,
                 ));
-                self.packet_queue[self.packet_next] = pending;
-                self.packet_next = (self.packet_next + 1) % 4;
-                if self.packet_next == self.packet_curr {
-                    self.packet_full = true;
-                }
+                let _ = self.packet_queue.push_back(pending);
             } else if port == PORT_PACKET_FULL {
-                if self.packet_full {
+                if self.packet_queue.is_full() {
+                    self.packets_dropped = self.packets_dropped.wrapping_add(1);
+                    self.report_queue_status(port);
                     continue;
                 }
                 let mut pending = PendingPacket::new();
@@ -438,11 +1179,7 @@ impl SoftTnc {
                 let app_data_len = len - 30;
                 pending.app_data[0..app_data_len].copy_from_slice(&payload[30..len]);
                 pending.app_data_len = app_data_len;
-                self.packet_queue[self.packet_next] = pending;
-                self.packet_next = (self.packet_next + 1) % 4;
-                if self.packet_next == self.packet_curr {
-                    self.packet_full = true;
-                }
+                let _ = self.packet_queue.push_back(pending);
             } else if port == PORT_STREAM {
                 let mut payload = [0u8; 30];
                 let Ok(len) = kiss_frame.decode_payload(&mut payload) else {
@@ -457,23 +1194,28 @@ impl SoftTnc {
                     if lsf.check_crc() != 0 {
                         continue;
                     }
+                    if !matches!(self.tx_state, TxState::Stream) {
+                        // Anything left over from a previous stream is stale
+                        self.stream_queue.clear();
+                    }
+                    self.discard_stream_data = false;
                     self.stream_pending_lsf = Some(lsf);
                 } else {
-                    if self.stream_full {
-                        log::debug!("stream full");
+                    if self.discard_stream_data {
                         continue;
                     }
                     let frame_num_part = u16::from_be_bytes([payload[6], payload[7]]);
-                    self.stream_queue[self.stream_next] = StreamFrame {
+                    let frame = StreamFrame {
                         lich_idx: payload[5] >> 5,
                         lich_part: payload[0..5].try_into().unwrap(),
                         frame_number: frame_num_part & 0x7fff,
                         end_of_stream: frame_num_part & 0x8000 > 0,
                         stream_data: payload[8..24].try_into().unwrap(),
                     };
-                    self.stream_next = (self.stream_next + 1) % 8;
-                    if self.stream_next == self.stream_curr {
-                        self.stream_full = true;
+                    if self.stream_queue.push_back_overwrite(frame).is_some() {
+                        log::debug!("stream full");
+                        self.stream_frames_dropped = self.stream_frames_dropped.wrapping_add(1);
+                        self.report_queue_status(port);
                     }
                 }
             }
@@ -481,17 +1223,65 @@ impl SoftTnc {
         n
     }
 
+    /// Record a frame we are about to transmit so we can ignore it if we hear it come back.
+    fn remember_tx(&mut self, frame: Frame) {
+        self.recent_tx[self.recent_tx_next] = Some(frame);
+        self.recent_tx_next = (self.recent_tx_next + 1) % RECENT_TX_LEN;
+    }
+
+    /// Sequencing statistics for streams received so far.
+    pub fn rx_stream_stats(&self) -> RxStreamStats {
+        self.rx_stream_stats.clone()
+    }
+
+    /// Current occupancy of the transmit queues.
+    pub fn queue_status(&self) -> QueueStatus {
+        QueueStatus {
+            packets_queued: self.packet_queue.len() as u16,
+            packet_capacity: self.packet_queue.capacity() as u16,
+            packets_dropped: self.packets_dropped,
+            stream_frames_queued: self.stream_queue.len() as u16,
+            stream_capacity: self.stream_queue.capacity() as u16,
+            stream_frames_dropped: self.stream_frames_dropped,
+        }
+    }
+
+    fn report_queue_status(&mut self, port: u8) {
+        let kiss = KissFrame::new_queue_status(port, &self.queue_status());
+        self.kiss_to_host(kiss);
+    }
+
     fn kiss_to_host(&mut self, kiss_frame: KissFrame) {
-        self.outgoing_kiss = Some(OutgoingKiss {
-            kiss_frame,
-            sent: 0,
-        });
+        // Host isn't keeping up if either queue is full. Don't touch the existing ones as we might
+        // be partway through sending the first one.
+        if self.outgoing_kiss.is_full() {
+            log::debug!("outgoing kiss queue full, discarding frame");
+            return;
+        }
+        let outgoing = if kiss_frame.len <= SHORT_KISS_LEN {
+            let mut data = [0u8; SHORT_KISS_LEN];
+            data[0..kiss_frame.len].copy_from_slice(kiss_frame.as_bytes());
+            OutgoingKiss::Short {
+                data,
+                len: kiss_frame.len as u8,
+                sent: 0,
+            }
+        } else {
+            if self.outgoing_packets.push_back(kiss_frame).is_err() {
+                log::debug!("outgoing packet queue full, discarding frame");
+                return;
+            }
+            OutgoingKiss::Packet { sent: 0 }
+        };
+        let _ = self.outgoing_kiss.push_back(outgoing);
     }
 }
 
-impl Default for SoftTnc {
+impl<const PACKET_QUEUE_LEN: usize, const STREAM_QUEUE_LEN: usize> Default
+    for SoftTnc<PACKET_QUEUE_LEN, STREAM_QUEUE_LEN>
+{
     fn default() -> Self {
-        Self::new()
+        Self::new_with_queues()
     }
 }
 
@@ -501,64 +1291,253 @@ pub enum SoftTncError {
     InvalidState,
 }
 
-struct OutgoingKiss {
-    kiss_frame: KissFrame,
-    sent: usize,
+/// A KISS frame waiting to be read by the host, and how much of it has been read so far.
+///
+/// Most messages are short, so they are kept in a compact slot rather than a whole `KissFrame`.
+enum OutgoingKiss {
+    Short {
+        data: [u8; SHORT_KISS_LEN],
+        len: u8,
+        sent: usize,
+    },
+    /// The frame is at the front of `outgoing_packets`.
+    Packet { sent: usize },
 }
 
+/// Number of transmitted frames to remember for recognising self-decodes.
+///
+/// The modulator may buffer up to a second of audio ahead of the DAC, so this needs to cover
+/// at least 25 frames, plus whatever latency there is in the receive path.
+const RECENT_TX_LEN: usize = 32;
+
 #[allow(clippy::large_enum_variant)]
-enum State {
-    /// Nothing happening. We may have TX data queued but we won't act on it until CSMA opens up.
+enum RxState {
+    /// Not receiving anything at the moment.
     Idle,
 
     /// We received some stream data but missed the leading LSF so we are trying to assemble from LICH.
-    RxAcquiringStream(RxAcquiringStreamState),
+    AcquiringStream(RxAcquiringStreamState),
 
     /// We have acquired an identified stream transmission and are sending data payloads to the host.
-    RxStream(RxStreamState),
+    Stream(RxStreamState),
 
     /// We are receiving a packet. All is well so far, and there is more data to come before we tell the host.
-    RxPacket(RxPacketState),
+    Packet(RxPacketState),
+
+    /// We received an LSF that failed its CRC. We will find out from the next frame whether it was
+    /// a stream, which we can still acquire from LICH, or a packet, which is lost.
+    BadLsf(LsfFrame),
+}
+
+enum TxState {
+    /// Not transmitting. We may have TX data queued but we won't act on it until CSMA opens up.
+    Idle,
 
     /// PTT is on and this is a stream-type transmission. New data may be added.
-    TxStream,
+    Stream,
 
     /// We have delivered the last frame in the current stream
-    TxStreamSentEndOfStream,
+    StreamSentEndOfStream,
+
+    /// A stream is being cut short by the time-out timer or duty cycle limit. We still need to send
+    /// a final end-of-stream frame.
+    AbortingStream,
+
+    /// Transmission is being cut short by the time-out timer or duty cycle limit.
+    Aborting,
 
     /// PTT is on and this is a packet-type transmission. New packets may be enqueued.
-    TxPacket,
+    Packet,
+
+    /// PTT is on and we are sending a test pattern until the host tells us to stop.
+    Calibration,
 
     /// We gave modulator an EndOfTransmission. PTT is still on, waiting for modulator to advise end time.
-    TxEnding,
+    Ending,
 
     /// Ending transmission, PTT remains on, but we know the timestamp at which we should disengage it.
-    TxEndingAtTime(u64),
+    EndingAtTime(u64),
 }
 
-struct RxAcquiringStreamState {
-    /// Partial assembly of LSF by accumulating LICH fields.
-    lich: LichCollection,
-}
+/// Number of intervals over which we keep track of transmit time for duty cycle purposes.
+const DUTY_CYCLE_BUCKETS: usize = 30;
 
-struct RxStreamState {
-    /// Track identifying information for this transmission so we can tell if it changes.
-    _lsf: LsfFrame,
+/// Tracks how much of the time we have spent transmitting over a rolling window.
+struct DutyCycle {
+    /// Maximum percentage of the window that we may spend transmitting. 100 means no limit.
+    limit_percent: u8,
 
-    /// Expected next frame number. Allowed to skip values on RX, but not go backwards.
-    index: u16,
-}
+    /// Length of each bucket in samples.
+    bucket_len: u64,
 
-struct RxPacketState {
-    /// Initial LSF
-    lsf: LsfFrame,
+    /// Number of samples spent transmitting during each bucket.
+    buckets: [u64; DUTY_CYCLE_BUCKETS],
 
-    /// Accumulation of packet data that we have received so far.
-    packet: [u8; 825],
+    /// Index of the bucket covering the current time.
+    current: usize,
 
-    /// Number of payload frames we have received. If we are stably in the RxPacket state,
+    /// Time at which the current bucket ends.
+    current_end: u64,
+}
+
+impl DutyCycle {
+    fn new() -> Self {
+        Self {
+            limit_percent: 100,
+            bucket_len: 0,
+            buckets: [0; DUTY_CYCLE_BUCKETS],
+            current: 0,
+            current_end: 0,
+        }
+    }
+
+    fn configure(&mut self, limit_percent: u8, window_samples: u64, now: u64) {
+        self.limit_percent = limit_percent.min(100);
+        self.bucket_len = window_samples / DUTY_CYCLE_BUCKETS as u64;
+        if self.bucket_len == 0 {
+            // Window too short to be meaningful
+            self.limit_percent = 100;
+        }
+        self.buckets = [0; DUTY_CYCLE_BUCKETS];
+        self.current = 0;
+        self.current_end = now + self.bucket_len;
+    }
+
+    /// Move time forward to `now`, recording that `tx_samples` of the elapsed time was spent transmitting.
+    fn advance(&mut self, now: u64, tx_samples: u64) {
+        if self.limit_percent >= 100 {
+            return;
+        }
+        if now >= self.current_end + self.bucket_len * DUTY_CYCLE_BUCKETS as u64 {
+            // Whole window has elapsed
+            self.buckets = [0; DUTY_CYCLE_BUCKETS];
+            self.current_end = now + self.bucket_len;
+        }
+        while now >= self.current_end {
+            self.current = (self.current + 1) % DUTY_CYCLE_BUCKETS;
+            self.buckets[self.current] = 0;
+            self.current_end += self.bucket_len;
+        }
+        self.buckets[self.current] += tx_samples;
+    }
+
+    fn exceeded(&self) -> bool {
+        if self.limit_percent >= 100 {
+            return false;
+        }
+        let window = self.bucket_len * DUTY_CYCLE_BUCKETS as u64;
+        let transmitting: u64 = self.buckets.iter().sum();
+        transmitting * 100 >= window * self.limit_percent as u64
+    }
+}
+
+/// If a received packet is one that a basic KISS application could have sent on port 0, return
+/// the application data to deliver there.
+///
+/// This is an unencrypted, broadcast, RAW packet - the same as we transmit for port 0.
+fn basic_packet_payload<'a>(lsf: &LsfFrame, packet: &'a [u8]) -> Option<&'a [u8]> {
+    if lsf.encryption_type() != EncryptionType::None
+        || lsf.destination() != Address::Broadcast
+        || packet.len() < 3
+        || packet[0] != 0x00
+        || crate::crc::m17_crc(packet) != 0
+    {
+        return None;
+    }
+    Some(&packet[1..packet.len() - 2])
+}
+
+struct RxAcquiringStreamState {
+    /// Partial assembly of LSF by accumulating LICH fields.
+    lich: LichCollection,
+
+    /// Stream frames received so far and how well we heard them, to be passed on once we know
+    /// the LSF.
+    frames: RingBuffer<(StreamFrame, Option<SignalQuality>), ACQUIRE_BUFFER_LEN>,
+}
+
+impl RxAcquiringStreamState {
+    fn new(first: StreamFrame, quality: Option<SignalQuality>) -> Self {
+        let mut lich = LichCollection::new();
+        lich.set_segment(first.lich_idx, first.lich_part);
+        let mut frames = RingBuffer::new();
+        let _ = frames.push_back((first, quality));
+        Self { lich, frames }
+    }
+}
+
+struct RxStreamState {
+    /// Track identifying information for this transmission so we can tell if it changes.
+    lsf: LsfFrame,
+
+    /// Expected next frame number. Allowed to skip values on RX, but not go backwards.
+    index: u16,
+
+    /// Rolling assembly of LICH segments, to notice if a different station takes over.
+    lich: LichCollection,
+}
+
+impl RxStreamState {
+    fn new(lsf: LsfFrame, index: u16) -> Self {
+        Self {
+            lsf,
+            index,
+            lich: LichCollection::new(),
+        }
+    }
+
+    /// Classify an incoming frame number relative to the one we expected, allowing for wraparound
+    /// from 0x7fff to 0.
+    fn sequence(&self, frame_number: u16) -> StreamSequence {
+        let ahead = frame_number.wrapping_sub(self.index) & 0x7fff;
+        if ahead < 0x4000 {
+            return StreamSequence::Next { lost: ahead };
+        }
+        let behind = 0x8000 - ahead;
+        if behind <= STREAM_DUPLICATE_WINDOW {
+            StreamSequence::Duplicate
+        } else {
+            StreamSequence::Restarted
+        }
+    }
+
+    /// Add a LICH segment. If the assembled LICH is valid and identifies a different transmission
+    /// from the one we were following, adopt it and return the new LSF.
+    fn update_lich(&mut self, lich_idx: u8, lich_part: [u8; 5]) -> Option<LsfFrame> {
+        self.lich.set_segment(lich_idx, lich_part);
+        let lsf = LsfFrame(self.lich.try_assemble()?);
+        // Only the META field is allowed to change during a transmission
+        if lsf.check_crc() != 0 || lsf.0[0..14] == self.lsf.0[0..14] {
+            return None;
+        }
+        self.lsf = lsf.clone();
+        Some(lsf)
+    }
+}
+
+/// How a received stream frame number relates to the frames we have already seen.
+enum StreamSequence {
+    /// Frame follows on from the last one, possibly after missing `lost` frames.
+    Next { lost: u16 },
+    /// We have already seen this frame number recently.
+    Duplicate,
+    /// Frame number has gone backwards too far to be a late duplicate.
+    Restarted,
+}
+
+struct RxPacketState {
+    /// Initial LSF
+    lsf: LsfFrame,
+
+    /// Accumulation of packet data that we have received so far.
+    packet: [u8; 825],
+
+    /// Number of payload frames we have received. If we are stably in the RxPacket state,
     /// this will be between 0 and 32 inclusive.
     count: usize,
+
+    /// Combined signal quality of the frames so far, if known.
+    quality: Option<SignalQuality>,
 }
 
 struct PendingPacket {
@@ -579,6 +1558,11 @@ impl PendingPacket {
         }
     }
 
+    /// Whether we have begun transmitting this packet.
+    fn started(&self) -> bool {
+        self.lsf.is_none()
+    }
+
     /// Returns next frame, not including preamble or EOT.
     ///
     /// False means all data frames have been sent.
@@ -658,12 +1642,13 @@ mod tests {
         tnc.handle_frame(Frame::Lsf(lsf));
         assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
 
-        // TODO: when support is added for the basic packet port they could arrive in either order
-
         tnc.handle_frame(Frame::Packet(packet));
         kiss.len = tnc.read_kiss(&mut kiss.data);
         assert_eq!(kiss.command().unwrap(), KissCommand::DataFrame);
         assert_eq!(kiss.port().unwrap(), PORT_PACKET_FULL);
+        let mut after = KissFrame::new_empty();
+        // SMS isn't RAW so there is no basic copy on port 0
+        assert_eq!(tnc.read_kiss(&mut after.data), 0);
 
         let mut payload_buf = [0u8; 2048];
         let n = kiss.decode_payload(&mut payload_buf).unwrap();
@@ -760,12 +1745,16 @@ mod tests {
         let mut tnc = SoftTnc::new();
         let mut kiss = KissFrame::new_empty();
 
-        // Nothing to report until second packet received in its entirety
-        tnc.handle_frame(Frame::Lsf(lsf));
+        // Nothing to report until second LSF interrupts the first packet
+        tnc.handle_frame(Frame::Lsf(lsf.clone()));
         assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
         tnc.handle_frame(Frame::Packet(packet1));
         assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
         tnc.handle_frame(Frame::Lsf(lsf2));
+        assert_eq!(
+            read_packet_discarded(&mut tnc),
+            (PacketDiscardReason::Interrupted, 1, lsf)
+        );
         assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
         tnc.handle_frame(Frame::Packet(packet2));
 
@@ -964,4 +1953,1135 @@ mod tests {
         let n = kiss.decode_payload(&mut payload_buf).unwrap();
         assert_eq!(n, 26);
     }
+
+    fn tnc_with_queued_packet() -> SoftTnc {
+        let mut tnc = SoftTnc::new();
+        let kiss = KissFrame::new_basic_packet(b"hello").unwrap();
+        assert_eq!(tnc.write_kiss(kiss.as_bytes()), kiss.len);
+        tnc
+    }
+
+    #[test]
+    fn csma_full_persistence_transmits_when_clear() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::new_set_p(PORT_PACKET_BASIC, 255).as_bytes());
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(tnc.ptt());
+    }
+
+    #[test]
+    fn csma_defers_while_busy() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::new_set_p(PORT_PACKET_BASIC, 255).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_slot_time(PORT_PACKET_BASIC, 10).as_bytes());
+
+        tnc.set_data_carrier_detect(true);
+        assert!(tnc.read_tx_frame().is_none());
+
+        // channel clears but we must wait for the slot to elapse
+        tnc.set_data_carrier_detect(false);
+        tnc.set_now(4799);
+        assert!(tnc.read_tx_frame().is_none());
+        tnc.set_now(4800);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+    }
+
+    #[test]
+    fn csma_seeded_deterministic() {
+        fn first_tx_slot(seed: u32) -> u64 {
+            let mut tnc = tnc_with_queued_packet();
+            tnc.set_csma_seed(seed);
+            tnc.write_kiss(KissFrame::new_set_p(PORT_PACKET_BASIC, 15).as_bytes());
+            for slot in 0..10000 {
+                tnc.set_now(slot * 1920);
+                if tnc.read_tx_frame().is_some() {
+                    return slot;
+                }
+            }
+            panic!("never transmitted");
+        }
+        assert_eq!(first_tx_slot(17), first_tx_slot(17));
+        // 1/16 probability per slot: different seeds should not all agree
+        let slots: Vec<u64> = (1..20).map(first_tx_slot).collect();
+        assert!(slots.iter().any(|s| *s != slots[0]));
+    }
+
+    #[test]
+    fn full_duplex_receives_while_transmitting() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_PACKET_BASIC, true).as_bytes());
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(tnc.ptt());
+
+        // Another station's stream arrives while we are keyed up
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        tnc.handle_frame(Frame::Lsf(lsf));
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.port().unwrap(), PORT_STREAM);
+
+        // Transmit side carries on regardless
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+    }
+
+    #[test]
+    fn ignore_self_decode() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_PACKET_BASIC, true).as_bytes());
+        let mut frames = vec![];
+        while let Some(frame) = tnc.read_tx_frame() {
+            match frame {
+                ModulatorFrame::Lsf(lsf) => frames.push(Frame::Lsf(lsf)),
+                ModulatorFrame::Packet(packet) => frames.push(Frame::Packet(packet)),
+                _ => (),
+            }
+        }
+        assert_eq!(frames.len(), 2);
+
+        // Hearing our own packet should not deliver anything to the host
+        let mut kiss = KissFrame::new_empty();
+        for frame in frames {
+            tnc.handle_frame(frame);
+            assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
+        }
+    }
+
+    #[test]
+    fn half_duplex_ignores_rx_while_transmitting() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::new_set_p(PORT_PACKET_BASIC, 255).as_bytes());
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(tnc.ptt());
+
+        // Whatever we decode while keyed up must be our own signal
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        tnc.handle_frame(Frame::Lsf(lsf));
+        let mut kiss = KissFrame::new_empty();
+        assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
+    }
+
+    fn read_queue_status(tnc: &mut SoftTnc<2, 2>) -> QueueStatus {
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.command().unwrap(), KissCommand::SetHardware);
+        let mut payload = [0u8; 64];
+        let n = kiss.decode_payload(&mut payload).unwrap();
+        assert_eq!(payload[0], HW_QUEUE_STATUS);
+        QueueStatus::from_bytes(&payload[1..n]).unwrap()
+    }
+
+    #[test]
+    fn default_tnc_fits_small_targets() {
+        assert!(core::mem::size_of::<SoftTnc>() < 16 * 1024);
+    }
+
+    #[test]
+    fn queue_status_query() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::new_query_queue_status(PORT_PACKET_BASIC).as_bytes());
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.command().unwrap(), KissCommand::SetHardware);
+        let mut payload = [0u8; 64];
+        let n = kiss.decode_payload(&mut payload).unwrap();
+        let status = QueueStatus::from_bytes(&payload[1..n]).unwrap();
+        assert_eq!(status.packets_queued, 1);
+        assert_eq!(status.packet_capacity, DEFAULT_PACKET_QUEUE_LEN as u16);
+        assert_eq!(status.stream_capacity, DEFAULT_STREAM_QUEUE_LEN as u16);
+    }
+
+    #[test]
+    fn packet_queue_overflow_rejects_newest() {
+        let mut tnc: SoftTnc<2, 2> = SoftTnc::new_with_queues();
+        for payload in [b"one", b"two"] {
+            tnc.write_kiss(KissFrame::new_basic_packet(payload).unwrap().as_bytes());
+        }
+        let mut kiss = KissFrame::new_empty();
+        assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
+
+        tnc.write_kiss(KissFrame::new_basic_packet(b"three").unwrap().as_bytes());
+        let status = read_queue_status(&mut tnc);
+        assert_eq!(status.packets_queued, 2);
+        assert_eq!(status.packets_dropped, 1);
+    }
+
+    #[test]
+    fn stream_queue_overflow_discards_oldest() {
+        let mut tnc: SoftTnc<2, 2> = SoftTnc::new_with_queues();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        tnc.write_kiss(KissFrame::new_stream_setup(&lsf.0).unwrap().as_bytes());
+        for frame_number in 0..3 {
+            let frame = StreamFrame {
+                frame_number,
+                ..Default::default()
+            };
+            tnc.write_kiss(KissFrame::new_stream_data(&frame).unwrap().as_bytes());
+        }
+        let status = read_queue_status(&mut tnc);
+        assert_eq!(status.stream_frames_queued, 2);
+        assert_eq!(status.stream_frames_dropped, 1);
+
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Stream(StreamFrame {
+                frame_number: 1,
+                ..
+            }))
+        ));
+    }
+
+    fn read_tx_limit(tnc: &mut SoftTnc) -> (TxLimitReason, u16) {
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.command().unwrap(), KissCommand::SetHardware);
+        let mut payload = [0u8; 64];
+        assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 4);
+        assert_eq!(payload[0], crate::kiss::HW_TX_LIMIT);
+        (
+            TxLimitReason::from_proto(payload[1]).unwrap(),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        )
+    }
+
+    fn read_stream_ended_early(tnc: &mut SoftTnc) -> StreamEndReason {
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.port().unwrap(), PORT_STREAM);
+        let mut payload = [0u8; 64];
+        assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 2);
+        assert_eq!(payload[0], crate::kiss::HW_STREAM_ENDED_EARLY);
+        StreamEndReason::from_proto(payload[1]).unwrap()
+    }
+
+    fn start_endless_stream(tnc: &mut SoftTnc) {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        tnc.write_kiss(KissFrame::new_stream_setup(&lsf.0).unwrap().as_bytes());
+        tnc.write_kiss(
+            KissFrame::new_stream_data(&StreamFrame::default())
+                .unwrap()
+                .as_bytes(),
+        );
+    }
+
+    #[test]
+    fn time_out_timer_ends_transmission() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_tx_timeout(PORT_STREAM, 2).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_tx_lockout(PORT_STREAM, 10).as_bytes());
+        start_endless_stream(&mut tnc);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+
+        tnc.set_now(96000);
+        assert_eq!(read_stream_ended_early(&mut tnc), StreamEndReason::TxLimit);
+        assert_eq!(read_tx_limit(&mut tnc), (TxLimitReason::TimeOut, 10));
+        // Receivers are told the stream has ended before we stop transmitting
+        match tnc.read_tx_frame() {
+            Some(ModulatorFrame::Stream(frame)) => assert!(frame.end_of_stream),
+            _ => panic!("expected end of stream frame"),
+        }
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::EndOfTransmission)
+        ));
+        tnc.set_tx_end_time(100);
+        tnc.set_now(96100);
+        assert!(!tnc.ptt());
+
+        // Locked out - new transmission is not permitted
+        start_endless_stream(&mut tnc);
+        assert!(tnc.read_tx_frame().is_none());
+
+        // Lockout expires
+        tnc.set_now(96000 + 480000);
+        assert_eq!(read_tx_limit(&mut tnc), (TxLimitReason::Cleared, 0));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+    }
+
+    #[test]
+    fn time_out_timer_survives_clock_going_backwards() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_tx_timeout(PORT_STREAM, 2).as_bytes());
+        tnc.set_now(48000);
+        start_endless_stream(&mut tnc);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+
+        // e.g. the host restarted its clock
+        tnc.set_now(1000);
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+    }
+
+    #[test]
+    fn time_out_timer_releases_ptt_if_transmission_never_ends() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_PACKET_BASIC, true).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_tx_timeout(PORT_PACKET_BASIC, 2).as_bytes());
+        while !matches!(tnc.read_tx_frame(), Some(ModulatorFrame::EndOfTransmission)) {}
+        // The modulator never reports when the transmission will end
+        tnc.set_now(96000);
+        assert!(tnc.ptt());
+        assert_eq!(tnc.ptt_off_time(), Some(96000 + TX_TIMEOUT_GRACE));
+        tnc.set_now(96000 + TX_TIMEOUT_GRACE);
+        assert!(!tnc.ptt());
+        assert_eq!(read_tx_limit(&mut tnc).0, TxLimitReason::TimeOut);
+    }
+
+    #[test]
+    fn duty_cycle_limit() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        // 10% over 60 seconds, i.e., 6 seconds
+        tnc.write_kiss(KissFrame::new_set_duty_cycle(PORT_STREAM, 10, 60).as_bytes());
+        start_endless_stream(&mut tnc);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+
+        for second in 1..=5 {
+            tnc.set_now(second * 48000);
+            assert!(tnc.ptt());
+        }
+        tnc.set_now(6 * 48000);
+        assert_eq!(read_stream_ended_early(&mut tnc), StreamEndReason::TxLimit);
+        assert_eq!(read_tx_limit(&mut tnc).0, TxLimitReason::DutyCycle);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::EndOfTransmission)
+        ));
+        tnc.set_tx_end_time(0);
+        tnc.set_now(6 * 48000 + 1);
+        assert!(!tnc.ptt());
+
+        // Can't start again until the transmission has rolled out of the window
+        start_endless_stream(&mut tnc);
+        tnc.set_now(30 * 48000);
+        assert!(tnc.read_tx_frame().is_none());
+        tnc.set_now(70 * 48000);
+        assert_eq!(read_tx_limit(&mut tnc).0, TxLimitReason::Cleared);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+    }
+
+    #[test]
+    fn calibration_transmission() {
+        let mut tnc = tnc_with_queued_packet();
+        // Busy channel doesn't hold up a calibration transmission
+        tnc.set_data_carrier_detect(true);
+        tnc.write_kiss(KissFrame::new_set_tx_level(PORT_PACKET_FULL, 80).as_bytes());
+        tnc.write_kiss(
+            KissFrame::new_set_calibration(PORT_PACKET_FULL, Some(CalibrationPattern::OuterTone))
+                .as_bytes(),
+        );
+        assert_eq!(tnc.tx_level(), 80);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(tnc.ptt());
+        for _ in 0..10 {
+            assert!(matches!(
+                tnc.read_tx_frame(),
+                Some(ModulatorFrame::Calibration(CalibrationPattern::OuterTone))
+            ));
+        }
+
+        tnc.write_kiss(KissFrame::new_set_calibration(PORT_PACKET_FULL, None).as_bytes());
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::EndOfTransmission)
+        ));
+        tnc.set_tx_end_time(0);
+        tnc.set_now(1);
+        assert!(!tnc.ptt());
+
+        // The queued packet still waits for the channel to clear
+        assert!(tnc.read_tx_frame().is_none());
+    }
+
+    #[test]
+    fn calibration_respects_time_out_timer() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::new_set_tx_timeout(PORT_PACKET_FULL, 1).as_bytes());
+        tnc.write_kiss(
+            KissFrame::new_set_calibration(PORT_PACKET_FULL, Some(CalibrationPattern::Random))
+                .as_bytes(),
+        );
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        tnc.set_now(48000);
+        assert_eq!(read_tx_limit(&mut tnc), (TxLimitReason::TimeOut, 0));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::EndOfTransmission)
+        ));
+        tnc.set_tx_end_time(0);
+        tnc.set_now(48001);
+        assert!(!tnc.ptt());
+        // Calibration was cancelled, so we don't key up again
+        assert!(tnc.read_tx_frame().is_none());
+    }
+
+    #[test]
+    fn preamble_length_and_ptt_timing() {
+        let mut tnc = tnc_with_queued_packet();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_PACKET_BASIC, true).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_tx_delay(PORT_PACKET_BASIC, 10).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_tx_tail(PORT_PACKET_BASIC, 5).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_preamble(PORT_PACKET_BASIC, 3).as_bytes());
+        tnc.write_kiss(KissFrame::new_set_ptt_timing(PORT_PACKET_BASIC, 100, 30).as_bytes());
+
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble {
+                tx_delay: 10,
+                ptt_lead: 100
+            })
+        ));
+        for _ in 0..2 {
+            assert!(matches!(
+                tnc.read_tx_frame(),
+                Some(ModulatorFrame::Preamble {
+                    tx_delay: 0,
+                    ptt_lead: 0
+                })
+            ));
+        }
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Packet(_))
+        ));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::EndOfTransmission)
+        ));
+        assert_eq!(tnc.ptt_off_time(), None);
+
+        // PTT is held for the tail and lag after the audio finishes
+        tnc.set_tx_end_time(1000);
+        assert_eq!(tnc.ptt_off_time(), Some(1000 + 2400 + 1440));
+        tnc.set_now(4839);
+        assert!(tnc.ptt());
+        tnc.set_now(4840);
+        assert!(!tnc.ptt());
+        assert_eq!(tnc.ptt_off_time(), None);
+    }
+
+    #[test]
+    fn vox_lead_in_delays_stream() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        // Rounds up to three extra preamble frames
+        tnc.set_vox_lead_in(100);
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        tnc.write_kiss(KissFrame::new_stream_setup(&lsf.0).unwrap().as_bytes());
+        for _ in 0..4 {
+            assert!(matches!(
+                tnc.read_tx_frame(),
+                Some(ModulatorFrame::Preamble { .. })
+            ));
+        }
+        // Without the lead-in this would be late enough to start padding
+        tnc.set_now(1920 * 3);
+        assert!(tnc.read_tx_frame().is_none());
+        tnc.set_now(1920 * 4);
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+    }
+
+    #[test]
+    fn data_stream_padding_is_zeros() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        let mut lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        lsf.set_data_type(DataType::Data);
+        tnc.write_kiss(KissFrame::new_stream_setup(&lsf.0).unwrap().as_bytes());
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        tnc.set_now(1920);
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+        tnc.set_now(1920 * 2);
+        let Some(ModulatorFrame::Stream(frame)) = tnc.read_tx_frame() else {
+            panic!("expected padding frame");
+        };
+        assert_eq!(frame.stream_data, [0u8; 16]);
+    }
+
+    #[test]
+    fn padding_follows_mid_stream_lsf() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        start_endless_stream(&mut tnc);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Stream(_))
+        ));
+
+        // Host switches the voice stream to data without ending it
+        let mut lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        lsf.set_data_type(DataType::Data);
+        tnc.write_kiss(KissFrame::new_stream_setup(&lsf.0).unwrap().as_bytes());
+        tnc.set_now(1920 * 3);
+        let Some(ModulatorFrame::Lsf(sent)) = tnc.read_tx_frame() else {
+            panic!("expected new LSF");
+        };
+        assert_eq!(sent, lsf);
+
+        // Underrun padding describes the new LSF, not the original one
+        tnc.set_now(1920 * 4);
+        let Some(ModulatorFrame::Stream(frame)) = tnc.read_tx_frame() else {
+            panic!("expected padding frame");
+        };
+        let start = frame.lich_idx as usize * 5;
+        assert_eq!(frame.lich_part, lsf.0[start..start + 5]);
+        assert_eq!(frame.stream_data, [0u8; 16]);
+    }
+
+    #[test]
+    fn stream_underrun_pads_then_ends() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        start_endless_stream(&mut tnc);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Preamble { .. })
+        ));
+        assert!(matches!(tnc.read_tx_frame(), Some(ModulatorFrame::Lsf(_))));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Stream(StreamFrame {
+                frame_number: 0,
+                ..
+            }))
+        ));
+
+        // Modulator still has preamble, LSF and one frame to send
+        assert!(tnc.read_tx_frame().is_none());
+        tnc.set_now(1920);
+        assert!(tnc.read_tx_frame().is_none());
+
+        // Host has fallen behind - pad for the grace period then end the stream
+        let mut now = 0;
+        for frame_number in 1..=(STREAM_UNDERRUN_GRACE_FRAMES as u16 + 1) {
+            now = 1920 * (frame_number as u64 + 2) - STREAM_UNDERRUN_MARGIN;
+            tnc.set_now(now);
+            let Some(ModulatorFrame::Stream(frame)) = tnc.read_tx_frame() else {
+                panic!("expected padding frame");
+            };
+            assert_eq!(frame.frame_number, frame_number);
+            assert_eq!(frame.stream_data[0..8], CODEC2_3200_SILENCE);
+            assert_eq!(frame.stream_data[8..16], CODEC2_3200_SILENCE);
+            let idx = frame.lich_idx as usize;
+            assert_eq!(idx, frame_number as usize % 6);
+            assert_eq!(frame.lich_part[..], lsf.0[idx * 5..idx * 5 + 5]);
+            assert_eq!(
+                frame.end_of_stream,
+                frame_number == STREAM_UNDERRUN_GRACE_FRAMES as u16 + 1
+            );
+        }
+        assert_eq!(read_stream_ended_early(&mut tnc), StreamEndReason::Underrun);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::EndOfTransmission)
+        ));
+
+        // Late data from the host does not start another transmission
+        tnc.write_kiss(
+            KissFrame::new_stream_data(&StreamFrame::default())
+                .unwrap()
+                .as_bytes(),
+        );
+        tnc.set_tx_end_time(0);
+        tnc.set_now(now + 1);
+        assert!(tnc.read_tx_frame().is_none());
+    }
+
+    #[test]
+    fn stream_recovers_after_padding() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::set_full_duplex(PORT_STREAM, true).as_bytes());
+        start_endless_stream(&mut tnc);
+        for _ in 0..3 {
+            assert!(tnc.read_tx_frame().is_some());
+        }
+        tnc.set_now(1920 * 3);
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Stream(StreamFrame {
+                frame_number: 1,
+                ..
+            }))
+        ));
+        // Host's next frame is renumbered to follow the padding
+        tnc.write_kiss(
+            KissFrame::new_stream_data(&StreamFrame {
+                frame_number: 1,
+                end_of_stream: true,
+                ..Default::default()
+            })
+            .unwrap()
+            .as_bytes(),
+        );
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::Stream(StreamFrame {
+                frame_number: 2,
+                end_of_stream: true,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            tnc.read_tx_frame(),
+            Some(ModulatorFrame::EndOfTransmission)
+        ));
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+    }
+
+    fn stream_frame_for(lsf: &LsfFrame, frame_number: u16) -> StreamFrame {
+        let lich_idx = (frame_number % 6) as u8;
+        let start = lich_idx as usize * 5;
+        StreamFrame {
+            lich_idx,
+            lich_part: lsf.0[start..start + 5].try_into().unwrap(),
+            frame_number,
+            ..Default::default()
+        }
+    }
+
+    /// Drain KISS output, returning the payload length of each stream frame.
+    fn read_stream_kiss_lens(tnc: &mut SoftTnc) -> Vec<usize> {
+        let mut lens = vec![];
+        loop {
+            let mut kiss = KissFrame::new_empty();
+            kiss.len = tnc.read_kiss(&mut kiss.data);
+            if kiss.len == 0 {
+                return lens;
+            }
+            assert_eq!(kiss.port().unwrap(), PORT_STREAM);
+            let mut payload = [0u8; 64];
+            lens.push(kiss.decode_payload(&mut payload).unwrap());
+        }
+    }
+
+    fn read_stream_lost(tnc: &mut SoftTnc) -> StreamLostReason {
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.port().unwrap(), PORT_STREAM);
+        assert_eq!(kiss.command().unwrap(), KissCommand::SetHardware);
+        let mut payload = [0u8; 64];
+        assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 2);
+        assert_eq!(payload[0], crate::kiss::HW_STREAM_LOST);
+        StreamLostReason::from_proto(payload[1]).unwrap()
+    }
+
+    #[test]
+    fn stream_rx_timeout() {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame(Frame::Lsf(lsf.clone()));
+        tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, 0)));
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [30, 26]);
+        tnc.set_now(STREAM_RX_TIMEOUT);
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+        tnc.set_now(STREAM_RX_TIMEOUT + 1);
+        assert_eq!(read_stream_lost(&mut tnc), StreamLostReason::Timeout);
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+    }
+
+    #[test]
+    fn stream_frame_number_wraparound() {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame(Frame::Lsf(lsf.clone()));
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [30]);
+
+        for frame_number in [0x3000, 0x6000, 0x7fff, 0, 1] {
+            tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, frame_number)));
+            assert_eq!(read_stream_kiss_lens(&mut tnc), [26]);
+        }
+        let stats = tnc.rx_stream_stats();
+        // Large forward jumps are counted as gaps, but nothing is lost across the wrap
+        assert_eq!(stats.frames_received, 5);
+        assert_eq!(stats.frames_lost, 0x7ffd);
+        assert_eq!(stats.frames_duplicated, 0);
+    }
+
+    #[test]
+    fn stream_gaps_and_duplicates() {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame(Frame::Lsf(lsf.clone()));
+        read_stream_kiss_lens(&mut tnc);
+
+        for frame_number in [0, 1, 4, 4, 2, 5] {
+            tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, frame_number)));
+        }
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [26, 26, 26, 26]);
+        let stats = tnc.rx_stream_stats();
+        assert_eq!(stats.frames_received, 4);
+        assert_eq!(stats.frames_lost, 2);
+        assert_eq!(stats.frames_duplicated, 2);
+
+        // A big backwards jump is a new transmission, which we must acquire from LICH
+        tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, 1000)));
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [26]);
+        tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, 0)));
+        assert_eq!(read_stream_lost(&mut tnc), StreamLostReason::Restarted);
+        for frame_number in 1..6 {
+            tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, frame_number)));
+        }
+        assert_eq!(
+            read_stream_kiss_lens(&mut tnc),
+            [30, 26, 26, 26, 26, 26, 26]
+        );
+    }
+
+    #[test]
+    fn stream_new_station_from_lich() {
+        let lsf1 = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let lsf2 = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7ABC   ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame(Frame::Lsf(lsf1.clone()));
+        read_stream_kiss_lens(&mut tnc);
+        for frame_number in 0..12 {
+            tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf1, frame_number)));
+            assert_eq!(read_stream_kiss_lens(&mut tnc), [26]);
+        }
+
+        // Second station continues the numbering, so we only notice once the LICH is complete
+        for frame_number in 12..17 {
+            tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf2, frame_number)));
+            assert_eq!(read_stream_kiss_lens(&mut tnc), [26]);
+        }
+        tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf2, 17)));
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        let mut payload = [0u8; 64];
+        assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 30);
+        assert_eq!(payload[0..30], lsf2.0);
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [26]);
+        assert_eq!(tnc.rx_stream_stats().stations_changed, 1);
+    }
+
+    #[test]
+    fn late_entry_keeps_acquisition_frames() {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        // Join partway through, missing one LICH segment the first time around
+        for frame_number in [100, 101, 103, 104, 105, 106, 107] {
+            tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, frame_number)));
+            assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+        }
+        tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, 108)));
+
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        let mut payload = [0u8; 64];
+        assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 30);
+        assert_eq!(payload[0..30], lsf.0);
+        for expected in [100, 101, 103, 104, 105, 106, 107, 108] {
+            kiss.len = tnc.read_kiss(&mut kiss.data);
+            assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 26);
+            assert_eq!(u16::from_be_bytes([payload[6], payload[7]]), expected);
+        }
+        assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
+
+        // Carries on normally afterwards
+        tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, 109)));
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [26]);
+        assert_eq!(tnc.rx_stream_stats().frames_received, 9);
+    }
+
+    fn read_packet_discarded(tnc: &mut SoftTnc) -> (PacketDiscardReason, u8, LsfFrame) {
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.command().unwrap(), KissCommand::SetHardware);
+        assert_eq!(kiss.port().unwrap(), PORT_PACKET_FULL);
+        let mut payload = [0u8; 64];
+        assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 33);
+        assert_eq!(payload[0], crate::kiss::HW_PACKET_DISCARDED);
+        (
+            PacketDiscardReason::from_proto(payload[1]).unwrap(),
+            payload[2],
+            LsfFrame(payload[3..33].try_into().unwrap()),
+        )
+    }
+
+    fn packet_frame(index: usize) -> PacketFrame {
+        PacketFrame {
+            payload: [index as u8; 25],
+            counter: PacketFrameCounter::Frame { index },
+        }
+    }
+
+    #[test]
+    fn packet_rx_timeout() {
+        let lsf = LsfFrame::new_packet(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.set_now(1000);
+        tnc.handle_frame(Frame::Lsf(lsf.clone()));
+        tnc.set_now(1000 + 1920);
+        tnc.handle_frame(Frame::Packet(packet_frame(0)));
+        tnc.set_now(1000 + 1920 + PACKET_RX_TIMEOUT);
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+        tnc.set_now(1000 + 1920 + PACKET_RX_TIMEOUT + 1);
+        assert_eq!(
+            read_packet_discarded(&mut tnc),
+            (PacketDiscardReason::Timeout, 1, lsf)
+        );
+
+        // A final frame arriving now must not be joined onto the abandoned packet
+        tnc.handle_frame(Frame::Packet(PacketFrame {
+            payload: [0u8; 25],
+            counter: PacketFrameCounter::FinalFrame { payload_len: 3 },
+        }));
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+    }
+
+    #[test]
+    fn packet_rx_foreign_frame_timing() {
+        let lsf = LsfFrame::new_packet(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame_at(Frame::Lsf(lsf.clone()), 5000);
+        // Slight jitter is fine, as is a missing frame
+        tnc.handle_frame_at(Frame::Packet(packet_frame(0)), 5000 + 1920 + 3);
+        tnc.handle_frame_at(Frame::Packet(packet_frame(1)), 5000 + 1920 * 2 - 2);
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+        // Another station's frame lands between our frame boundaries
+        tnc.handle_frame_at(Frame::Packet(packet_frame(2)), 5000 + 1920 * 3 + 700);
+        assert_eq!(
+            read_packet_discarded(&mut tnc),
+            (PacketDiscardReason::ForeignFrame, 2, lsf)
+        );
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+    }
+
+    #[test]
+    fn stream_rx_foreign_frame_timing() {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame_at(Frame::Lsf(lsf.clone()), 0);
+        tnc.handle_frame_at(Frame::Stream(stream_frame_for(&lsf, 0)), 1920);
+        tnc.handle_frame_at(Frame::Stream(stream_frame_for(&lsf, 1)), 3840);
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [30, 26, 26]);
+        // Would otherwise be accepted as the next frame
+        tnc.handle_frame_at(Frame::Stream(stream_frame_for(&lsf, 2)), 3840 + 1000);
+        assert_eq!(read_stream_lost(&mut tnc), StreamLostReason::ForeignFrame);
+        assert_eq!(read_stream_kiss_lens(&mut tnc), []);
+    }
+
+    fn receive_single_frame_packet(tnc: &mut SoftTnc, lsf: LsfFrame, app_data: &[u8]) {
+        let mut payload = [0u8; 25];
+        payload[0..app_data.len()].copy_from_slice(app_data);
+        let crc = crate::crc::m17_crc(app_data).to_be_bytes();
+        payload[app_data.len()..app_data.len() + 2].copy_from_slice(&crc);
+        tnc.handle_frame(Frame::Lsf(lsf));
+        tnc.handle_frame(Frame::Packet(PacketFrame {
+            payload,
+            counter: PacketFrameCounter::FinalFrame {
+                payload_len: app_data.len() + 2,
+            },
+        }));
+    }
+
+    /// Drain KISS output, returning the port of each frame.
+    fn read_kiss_ports(tnc: &mut SoftTnc) -> Vec<u8> {
+        let mut ports = vec![];
+        loop {
+            let mut kiss = KissFrame::new_empty();
+            kiss.len = tnc.read_kiss(&mut kiss.data);
+            if kiss.len == 0 {
+                return ports;
+            }
+            ports.push(kiss.port().unwrap());
+        }
+    }
+
+    #[test]
+    fn basic_packet_delivered_on_port_0() {
+        let source = Address::Callsign(Callsign(*b"VK7XT    "));
+        let mut tnc = SoftTnc::new();
+        receive_single_frame_packet(
+            &mut tnc,
+            LsfFrame::new_packet(&source, &Address::Broadcast),
+            &[0x00, b'h', b'i'],
+        );
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.port().unwrap(), PORT_PACKET_FULL);
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.port().unwrap(), PORT_PACKET_BASIC);
+        assert_eq!(kiss.command().unwrap(), KissCommand::DataFrame);
+        let mut payload = [0u8; 64];
+        let n = kiss.decode_payload(&mut payload).unwrap();
+        assert_eq!(&payload[0..n], b"hi");
+        assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
+
+        // Not RAW
+        receive_single_frame_packet(
+            &mut tnc,
+            LsfFrame::new_packet(&source, &Address::Broadcast),
+            &[0x05, b'h', b'i', 0x00],
+        );
+        assert_eq!(read_kiss_ports(&mut tnc), [PORT_PACKET_FULL]);
+
+        // Not broadcast
+        receive_single_frame_packet(
+            &mut tnc,
+            LsfFrame::new_packet(&source, &source),
+            &[0x00, b'h', b'i'],
+        );
+        assert_eq!(read_kiss_ports(&mut tnc), [PORT_PACKET_FULL]);
+    }
+
+    #[test]
+    fn basic_packets_opt_out() {
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::new_set_basic_packets(PORT_PACKET_BASIC, false).as_bytes());
+        receive_single_frame_packet(
+            &mut tnc,
+            LsfFrame::new_packet(
+                &Address::Callsign(Callsign(*b"VK7XT    ")),
+                &Address::Broadcast,
+            ),
+            &[0x00, b'h', b'i'],
+        );
+        assert_eq!(read_kiss_ports(&mut tnc), [PORT_PACKET_FULL]);
+    }
+
+    #[test]
+    fn corrupt_stream_lsf_falls_back_to_lich() {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut corrupt = lsf.clone();
+        corrupt.0[7] ^= 0x10;
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame(Frame::Lsf(corrupt));
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+
+        for frame_number in 0..5 {
+            tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, frame_number)));
+            assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+        }
+        tnc.handle_frame(Frame::Stream(stream_frame_for(&lsf, 5)));
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        let mut payload = [0u8; 64];
+        assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 30);
+        assert_eq!(payload[0..30], lsf.0);
+        // Nothing lost apart from the time taken to acquire
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [26; 6]);
+    }
+
+    #[test]
+    fn corrupt_packet_lsf_reported() {
+        let mut corrupt = LsfFrame::new_packet(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        corrupt.0[7] ^= 0x10;
+        let mut tnc = SoftTnc::new();
+        tnc.handle_frame(Frame::Lsf(corrupt.clone()));
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+        tnc.handle_frame(Frame::Packet(packet_frame(0)));
+        assert_eq!(
+            read_packet_discarded(&mut tnc),
+            (PacketDiscardReason::BadLsf, 0, corrupt)
+        );
+        // Only reported once
+        tnc.handle_frame(Frame::Packet(packet_frame(1)));
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+    }
+
+    fn read_signal_quality(tnc: &mut SoftTnc, port: u8) -> SignalQuality {
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.command().unwrap(), KissCommand::SetHardware);
+        assert_eq!(kiss.port().unwrap(), port);
+        let mut payload = [0u8; 64];
+        assert_eq!(
+            kiss.decode_payload(&mut payload).unwrap(),
+            1 + SignalQuality::LEN
+        );
+        assert_eq!(payload[0], crate::kiss::HW_SIGNAL_QUALITY);
+        SignalQuality::from_bytes(&payload[1..]).unwrap()
+    }
+
+    #[test]
+    fn signal_quality_reports() {
+        let quality = |snr_db, viterbi_errors| SignalQuality {
+            snr_db,
+            sync_diff: 1.0,
+            viterbi_errors,
+            symbol_errors: 0,
+        };
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        // Off by default
+        tnc.handle_frame_with_quality(Frame::Lsf(lsf.clone()), 0, quality(20.0, 1));
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [30]);
+
+        tnc.write_kiss(KissFrame::new_set_signal_quality_reports(PORT_STREAM, true).as_bytes());
+        tnc.handle_frame_with_quality(
+            Frame::Stream(stream_frame_for(&lsf, 0)),
+            1920,
+            quality(15.0, 2),
+        );
+        assert_eq!(read_signal_quality(&mut tnc, PORT_STREAM).viterbi_errors, 2);
+        assert_eq!(read_stream_kiss_lens(&mut tnc), [26]);
+
+        // Packets are summarised once complete
+        let packet_lsf = LsfFrame::new_packet(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        tnc.handle_frame_with_quality(Frame::Lsf(packet_lsf), 10000, quality(20.0, 1));
+        assert_eq!(read_stream_lost(&mut tnc), StreamLostReason::Interrupted);
+        tnc.handle_frame_with_quality(Frame::Packet(packet_frame(0)), 11920, quality(12.0, 4));
+        assert_eq!(tnc.read_kiss(&mut [0u8; 64]), 0);
+        tnc.handle_frame_with_quality(
+            Frame::Packet(PacketFrame {
+                payload: [0u8; 25],
+                counter: PacketFrameCounter::FinalFrame { payload_len: 3 },
+            }),
+            13840,
+            quality(18.0, 0),
+        );
+        let summary = read_signal_quality(&mut tnc, PORT_PACKET_FULL);
+        assert_eq!(summary.snr_db, 12.0);
+        assert_eq!(summary.viterbi_errors, 5);
+        assert_eq!(read_kiss_ports(&mut tnc), [PORT_PACKET_FULL]);
+    }
+
+    #[test]
+    fn acquired_stream_frames_keep_quality() {
+        let lsf = LsfFrame::new_voice(
+            &Address::Callsign(Callsign(*b"VK7XT    ")),
+            &Address::Broadcast,
+        );
+        let mut tnc = SoftTnc::new();
+        tnc.write_kiss(KissFrame::new_set_signal_quality_reports(PORT_STREAM, true).as_bytes());
+        // Joining partway through, the LSF is only known once all six LICH segments arrive
+        for n in 0..6u16 {
+            tnc.handle_frame_with_quality(
+                Frame::Stream(stream_frame_for(&lsf, n)),
+                n as u64 * 1920,
+                SignalQuality {
+                    snr_db: 10.0,
+                    sync_diff: 1.0,
+                    viterbi_errors: n,
+                    symbol_errors: 0,
+                },
+            );
+        }
+        let mut kiss = KissFrame::new_empty();
+        kiss.len = tnc.read_kiss(&mut kiss.data);
+        assert_eq!(kiss.decode_payload(&mut [0u8; 64]).unwrap(), 30);
+        for n in 0..6u16 {
+            assert_eq!(read_signal_quality(&mut tnc, PORT_STREAM).viterbi_errors, n);
+            kiss.len = tnc.read_kiss(&mut kiss.data);
+            let mut payload = [0u8; 64];
+            assert_eq!(kiss.decode_payload(&mut payload).unwrap(), 26);
+            assert_eq!(u16::from_be_bytes([payload[6], payload[7]]), n);
+        }
+        assert_eq!(tnc.read_kiss(&mut kiss.data), 0);
+    }
 }
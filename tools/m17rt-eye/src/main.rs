@@ -0,0 +1,235 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use m17core::modem::{Demodulator, FrameCapture, SAMPLES_PER_SYMBOL, SoftDemodulator};
+
+/// Show the demodulator's view of a recording, for setting input levels and deviation.
+///
+/// Prints the distribution of the four symbol levels and an eye diagram built from every decoded
+/// frame, and optionally writes the underlying samples as CSV.
+#[derive(Parser)]
+struct Args {
+    #[arg(short = 'i', help = "Input RRC file")]
+    input: PathBuf,
+    #[arg(
+        long,
+        help = "Write every normalised sample of each decoded frame to a CSV file"
+    )]
+    csv: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = valid_decimation,
+        help = "Demodulator decimation: 1, 2 or 5. Higher uses less CPU but decodes fewer frames"
+    )]
+    decimation: usize,
+}
+
+/// Histogram and eye diagram cover normalised values from -RANGE to +RANGE.
+const RANGE: f32 = 1.5;
+const HISTOGRAM_BINS: usize = 60;
+const EYE_ROWS: usize = 31;
+const IDEAL_LEVELS: [f32; 4] = [-1.0, -1.0 / 3.0, 1.0 / 3.0, 1.0];
+
+struct Summary {
+    frames: usize,
+    histogram: [u32; HISTOGRAM_BINS],
+    /// Count, sum and sum of squares of the symbols closest to each ideal level
+    levels: [(u32, f64, f64); 4],
+    /// Counts by row (top is +RANGE) and offset from the symbol decision point
+    eye: Vec<[u32; EYE_ROWS]>,
+    samples_per_symbol: usize,
+}
+
+impl Summary {
+    fn new(samples_per_symbol: usize) -> Self {
+        Self {
+            frames: 0,
+            histogram: [0; HISTOGRAM_BINS],
+            levels: [(0, 0.0, 0.0); 4],
+            eye: vec![[0; EYE_ROWS]; samples_per_symbol],
+            samples_per_symbol,
+        }
+    }
+
+    fn add(&mut self, capture: &FrameCapture) {
+        self.frames += 1;
+        for symbol in capture.symbols() {
+            if let Some(bin) = bin(symbol, HISTOGRAM_BINS) {
+                self.histogram[bin] += 1;
+            }
+            let level = nearest_level(symbol);
+            let (count, sum, sum_sq) = &mut self.levels[level];
+            *count += 1;
+            *sum += symbol as f64;
+            *sum_sq += (symbol as f64) * (symbol as f64);
+        }
+        for (idx, value) in capture.samples().iter().enumerate() {
+            let column = (eye_offset(idx, self.samples_per_symbol)
+                + (self.samples_per_symbol / 2) as isize) as usize;
+            if let Some(row) = bin(-*value, EYE_ROWS) {
+                self.eye[column][row] += 1;
+            }
+        }
+    }
+
+    fn print(&self) {
+        println!("frames captured: {}\n", self.frames);
+        if self.frames == 0 {
+            return;
+        }
+
+        println!("symbol levels:");
+        for (ideal, (count, sum, sum_sq)) in IDEAL_LEVELS.iter().zip(&self.levels) {
+            if *count == 0 {
+                println!("  {ideal:+.2}: no symbols");
+                continue;
+            }
+            let mean = sum / *count as f64;
+            let sd = (sum_sq / *count as f64 - mean * mean).max(0.0).sqrt();
+            println!("  {ideal:+.2}: mean {mean:+.3}, std dev {sd:.3}, {count} symbols");
+        }
+        println!("  outer levels should be close to +/-1.00 and inner levels to +/-0.33\n");
+
+        println!("symbol distribution:");
+        let max = *self.histogram.iter().max().unwrap_or(&0);
+        for (bin, count) in self.histogram.iter().enumerate().rev() {
+            let value = bin_centre(bin, HISTOGRAM_BINS);
+            let width = (*count * 60).checked_div(max).unwrap_or(0);
+            println!("  {value:+.3} | {}", "#".repeat(width as usize));
+        }
+
+        println!("\neye diagram (columns are samples either side of the decision point):");
+        let shades = [' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+        let max = self
+            .eye
+            .iter()
+            .flat_map(|c| c.iter())
+            .copied()
+            .max()
+            .unwrap_or(0);
+        for row in 0..EYE_ROWS {
+            let value = bin_centre(EYE_ROWS - 1 - row, EYE_ROWS);
+            let mut line = String::new();
+            for column in &self.eye {
+                let count = column[row];
+                let shade = if count == 0 || max == 0 {
+                    shades[0]
+                } else {
+                    // Non-zero counts always get at least the faintest shade
+                    shades[1 + (count as usize * (shades.len() - 2)) / max as usize]
+                };
+                line.push(shade);
+                line.push(shade);
+                line.push(shade);
+            }
+            println!("  {value:+.2} |{line}|");
+        }
+        let first = -((self.samples_per_symbol / 2) as isize);
+        let offsets: String = (0..self.samples_per_symbol)
+            .map(|i| format!("{:^3}", first + i as isize))
+            .collect();
+        println!("        {offsets}");
+    }
+}
+
+/// Offset of filtered sample `idx` from the nearest symbol decision point, where the decision
+/// point for each symbol is its last sample.
+fn eye_offset(idx: usize, samples_per_symbol: usize) -> isize {
+    let sps = samples_per_symbol as isize;
+    let offset = (idx as isize % sps) - (sps - 1);
+    if offset < -(sps / 2) {
+        offset + sps
+    } else {
+        offset
+    }
+}
+
+fn bin(value: f32, bins: usize) -> Option<usize> {
+    let pos = (value + RANGE) / (2.0 * RANGE) * bins as f32;
+    if pos < 0.0 || pos >= bins as f32 {
+        None
+    } else {
+        Some(pos as usize)
+    }
+}
+
+fn bin_centre(bin: usize, bins: usize) -> f32 {
+    (bin as f32 + 0.5) / bins as f32 * 2.0 * RANGE - RANGE
+}
+
+fn nearest_level(value: f32) -> usize {
+    if value > 0.667 {
+        3
+    } else if value > 0.0 {
+        2
+    } else if value > -0.667 {
+        1
+    } else {
+        0
+    }
+}
+
+fn valid_decimation(d: &str) -> Result<usize, String> {
+    match d.parse() {
+        Ok(d @ (1 | 2 | 5)) => Ok(d),
+        _ => Err("must be 1, 2 or 5".to_owned()),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let mut file = File::open(&args.input)?;
+    let mut baseband = vec![];
+    file.read_to_end(&mut baseband)?;
+
+    let mut csv = match &args.csv {
+        Some(path) => {
+            let mut w = BufWriter::new(File::create(path)?);
+            writeln!(w, "frame,frame_sample,symbol,offset,value")?;
+            Some(w)
+        }
+        None => None,
+    };
+
+    let mut demod = SoftDemodulator::new_decimating(args.decimation);
+    let mut capture = FrameCapture::new();
+    let mut summary = Summary::new(SAMPLES_PER_SYMBOL / args.decimation);
+    for sample in baseband
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+    {
+        if demod.demod(sample).is_none() || !demod.capture_frame(&mut capture) {
+            continue;
+        }
+        if let Some(w) = csv.as_mut() {
+            let sps = capture.samples_per_symbol;
+            for (idx, value) in capture.samples().iter().enumerate() {
+                // Samples just after a decision point are shown against that symbol, so the
+                // first few belong to the one before the frame
+                let offset = eye_offset(idx, sps);
+                let symbol = (idx as isize - offset).div_euclid(sps as isize);
+                writeln!(
+                    w,
+                    "{},{},{},{},{:.4}",
+                    summary.frames, capture.sample, symbol, offset, value
+                )?;
+            }
+        }
+        summary.add(&capture);
+    }
+
+    summary.print();
+    if let Some(mut w) = csv {
+        w.flush()?;
+    }
+
+    Ok(())
+}
@@ -27,14 +27,22 @@ fn main() -> Result<(), Box<dyn Error>> {
         .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
         .enumerate()
     {
-        if let Some((frame, errors)) = demod.demod(sample) {
+        if let Some((frame, quality)) = demod.demod(sample) {
             total += 1;
             let frame_desc = match frame {
                 Frame::Lsf(_) => "lsf",
                 Frame::Stream(_) => "stream",
                 Frame::Packet(_) => "packet",
             };
-            println!("sample {}: {} with {} errors", idx, frame_desc, errors);
+            println!(
+                "sample {}: {} with {} errors, {} symbol errors, SNR {:.1} dB, sync {:.2}",
+                idx,
+                frame_desc,
+                quality.viterbi_errors,
+                quality.symbol_errors,
+                quality.snr_db,
+                quality.sync_diff
+            );
         }
     }
 
@@ -0,0 +1,104 @@
+use clap::Parser;
+use m17core::modem::SoftDemodulator;
+use m17core::simulation::{FrameErrorTest, Impairments, TestFrameType};
+
+/// Measure frame error rate of the soft modem over a simulated channel.
+#[derive(Parser)]
+struct Args {
+    #[arg(
+        long,
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Frames to send per type at each Eb/N0"
+    )]
+    frames: u32,
+    #[arg(long, default_value_t = 0.0, help = "Lowest Eb/N0 in dB")]
+    ebn0_min: f32,
+    #[arg(long, default_value_t = 12.0, help = "Highest Eb/N0 in dB")]
+    ebn0_max: f32,
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        value_parser = positive,
+        help = "Eb/N0 step in dB"
+    )]
+    ebn0_step: f32,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "Fractional sample timing offset, 0.0 to 1.0"
+    )]
+    timing_offset: f32,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "Deviation error, e.g. 0.1 for 10% too wide"
+    )]
+    deviation_error: f32,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "Frequency offset in Hz, i.e. DC offset"
+    )]
+    frequency_offset: f32,
+    #[arg(long, default_value_t = 0.0, help = "Clock skew in ppm")]
+    clock_skew: f32,
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = valid_decimation,
+        help = "Demodulator decimation: 1, 2 or 5. Higher uses less CPU but decodes fewer frames"
+    )]
+    decimation: usize,
+    #[arg(long, default_value_t = 1, help = "Seed for frame contents and noise")]
+    seed: u32,
+}
+
+fn valid_decimation(d: &str) -> Result<usize, String> {
+    match d.parse() {
+        Ok(d @ (1 | 2 | 5)) => Ok(d),
+        _ => Err("must be 1, 2 or 5".to_owned()),
+    }
+}
+
+fn positive(v: &str) -> Result<f32, String> {
+    match v.parse::<f32>() {
+        Ok(v) if v > 0.0 => Ok(v),
+        Ok(_) => Err("must be greater than zero".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let args = Args::parse();
+    let impairments = Impairments {
+        timing_offset: args.timing_offset,
+        deviation_error: args.deviation_error,
+        frequency_offset_hz: args.frequency_offset,
+        clock_skew_ppm: args.clock_skew,
+        ..Impairments::new()
+    };
+
+    println!("Eb/N0 (dB)   LSF FER   Stream FER   Packet FER");
+    let mut ebn0 = args.ebn0_min;
+    while ebn0 <= args.ebn0_max + 0.001 {
+        let mut rates = vec![];
+        for frame_type in [
+            TestFrameType::Lsf,
+            TestFrameType::Stream,
+            TestFrameType::Packet,
+        ] {
+            let mut test = FrameErrorTest::new(impairments.clone(), args.seed);
+            test.set_ebn0_db(Some(ebn0));
+            test.set_demodulator(SoftDemodulator::new_decimating(args.decimation));
+            let received = test.run(frame_type, args.frames);
+            rates.push(1.0 - received as f32 / args.frames as f32);
+        }
+        println!(
+            "{:>10.1}   {:>7.4}   {:>10.4}   {:>10.4}",
+            ebn0, rates[0], rates[1], rates[2]
+        );
+        ebn0 += args.ebn0_step;
+    }
+}